@@ -1,104 +1,428 @@
 use chrono::DateTime;
 use chrono_tz::Tz;
-use std::collections::HashMap;
-use std::error::Error;
-use std::path;
-use std::process::Command;
-use std::{
-    env,
-    fs::{self, File},
-    path::Path,
-};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{self, Path, PathBuf};
+use std::{env, fs};
 use tera::Tera;
 
+use crate::builds::BuildQueue;
+use crate::domains;
+use crate::feed;
+use crate::metadata::{self, PostMetadata};
+use crate::paths::{self, Root};
+use crate::posts::PostRecord;
+use crate::render::{self, BuildError, Changes};
+use crate::themes::{self, Theme};
+use crate::zola;
 use crate::zulip;
 
 pub fn parse_metadata(md: &str) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
     let mut m: HashMap<String, String> = HashMap::new();
     for line in md.lines() {
-        let (k, v) = match line.rsplit_once(": ") {
+        // Only the first ": " ends the key, since values (like an INTRO) can
+        // have their own
+        let (k, v) = match line.split_once(": ") {
             Some(kv) => kv,
             None => return Err("invalid line found".into()),
         };
         m.insert(k.to_string(), v.to_string());
     }
 
-    if !m.contains_key("SUBDOMAIN") {
-        return Err("no subdomain found in config!".into());
-    }
-
     Ok(m)
 }
 
-pub fn create_blog(m: HashMap<String, String>) -> Result<(), Box<dyn std::error::Error>> {
-    let tera = Tera::new(
-        Path::new(&env::var("TEMPLATES_ROOT").unwrap())
-            .join("*.toml")
-            .to_str()
-            .unwrap(),
-    )?;
+// Everything about a blog that its author picked, which the blog's
+// config.toml is generated from. This is kept in the DB, so that the config
+// can be regenerated whenever one of them changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlogSettings {
+    pub subdomain: String,
+    pub blog_name: String,
+    pub author: String,
+    pub theme: String,
+    #[serde(default = "default_accent_color")]
+    pub accent_color: String,
+    #[serde(default)]
+    pub highlight_theme: Option<String>,
+    // Already sanitized, see themes::sanitize_css
+    #[serde(default)]
+    pub custom_css: String,
+    // Slugs of the standalone pages to link to from the theme's menu, in order
+    #[serde(default)]
+    pub menu: Vec<String>,
+    // Markdown shown above the list of posts on the homepage
+    #[serde(default)]
+    pub intro: String,
+    // A verified custom domain the blog is served at, instead of its
+    // subdomain
+    #[serde(default)]
+    pub domain: Option<String>,
+}
 
-    println!("METADATA: {:?}", m);
+fn default_accent_color() -> String {
+    themes::DEFAULT_ACCENT_COLOR.to_string()
+}
 
-    let user_domain = match m.get("SUBDOMAIN") {
-        Some(v) => v,
-        None => "",
-    };
+impl BlogSettings {
+    pub fn new(subdomain: &str) -> BlogSettings {
+        BlogSettings {
+            subdomain: subdomain.to_string(),
+            blog_name: String::new(),
+            author: String::new(),
+            theme: themes::DEFAULT_THEME.to_string(),
+            accent_color: default_accent_color(),
+            highlight_theme: None,
+            custom_css: String::new(),
+            menu: Vec::new(),
+            intro: String::new(),
+            domain: None,
+        }
+    }
 
-    let mut context = tera::Context::new();
-    context.insert(
-        "user_domain",
-        format!("{}.hypertxt.io", user_domain).as_str(),
-    );
-    context.insert("blog_name", m.get("BLOG_NAME").unwrap_or(&"".to_string()));
-    context.insert("author_name", m.get("AUTHOR").unwrap_or(&"".to_string()));
+    // Changes one setting from a `KEY: value` line, checking the value first.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match key {
+            "BLOG_NAME" => self.blog_name = value.to_string(),
+            "AUTHOR" => self.author = value.to_string(),
+            "INTRO" => self.intro = value.to_string(),
+            "THEME" => {
+                themes::find(&themes::themes_root()?, value)?;
+                self.theme = value.to_string();
+            }
+            "ACCENT_COLOR" => {
+                themes::check_accent_color(value)?;
+                self.accent_color = value.to_string();
+            }
+            "HIGHLIGHT_THEME" => {
+                themes::check_highlight_theme(value)?;
+                self.highlight_theme = Some(value.to_string());
+            }
+            // Whether the pages exist is up to the caller, since only the DB
+            // knows that
+            "MENU" => {
+                self.menu = Vec::new();
+                for page in value.split(',').map(slug::slugify) {
+                    if !page.is_empty() && !self.menu.contains(&page) {
+                        self.menu.push(page);
+                    }
+                }
+            }
+            _ => return Err(format!("I don't know how to change {}", key).into()),
+        }
+        Ok(())
+    }
+
+    // For blogs created before settings were stored, which only have what's
+    // in their config.toml.
+    pub fn from_config(subdomain: &str) -> Result<BlogSettings, Box<dyn std::error::Error>> {
+        let site = zola::read_site_info(&paths::blog(subdomain)?)?;
+        Ok(BlogSettings {
+            blog_name: site.extra.logo_text,
+            author: site.extra.author,
+            theme: site.theme,
+            accent_color: site.extra.accent_color.unwrap_or_else(default_accent_color),
+            ..BlogSettings::new(subdomain)
+        })
+    }
 
-    let root = env::var("BLOG_ROOT").unwrap();
-    let blog_dir = Path::new(&root).join(user_domain);
+    // In the format terminimal (and a few other themes) use, with the blog
+    // itself first.
+    fn menu_items(&self) -> Vec<toml::Table> {
+        let item = |name: &str, url: String| {
+            toml::Table::from_iter([
+                ("name".to_string(), toml::Value::from(name)),
+                ("url".to_string(), toml::Value::from(url)),
+            ])
+        };
+        let mut items = vec![item("blog", "$BASE_URL".to_string())];
+        for page in &self.menu {
+            items.push(item(page, format!("$BASE_URL/{}", page)));
+        }
+        items
+    }
+
+    // Where the blog lives, e.g. myblog.hypertxt.io
+    pub fn host(&self) -> String {
+        match &self.domain {
+            Some(domain) => domain.clone(),
+            None => domains::default_host(&self.subdomain),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("https://{}", self.host())
+    }
+
+    fn site_config(&self, theme: &Theme) -> zola::Config {
+        let mut config = zola::Config::new(&self.host(), &self.blog_name, theme);
+        config.markdown.highlight_theme = self.highlight_theme.clone();
+        config.set_extra(theme, "accent_color", self.accent_color.as_str());
+        config.set_extra(theme, "logo_text", self.blog_name.as_str());
+        config.set_extra(theme, "author", self.author.as_str());
+        if !self.menu.is_empty() {
+            config.set_extra(theme, "menu_items", self.menu_items());
+        }
+        config
+    }
+}
+
+pub fn create_blog(settings: &BlogSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let user_domain = settings.subdomain.as_str();
+    println!("Creating blog {}", user_domain);
+
+    let blog_dir = paths::blog_dir(user_domain)?;
 
     // Make the directory
     fs::create_dir(&blog_dir).ok();
+    let blog = Root::new(&blog_dir)?;
     // Create the subdirectories
-    fs::create_dir(blog_dir.join("content")).ok();
-    fs::create_dir(blog_dir.join("templates")).ok();
+    fs::create_dir(blog.join("content")?).ok();
+    fs::create_dir(blog.join("templates")?).ok();
 
     // Symlink in the theme content
     let themes_root = path::absolute(env::var("THEMES_ROOT").unwrap())?;
-    std::os::unix::fs::symlink(themes_root, blog_dir.join("themes"))?;
+    std::os::unix::fs::symlink(themes_root, blog.join("themes")?)?;
 
-    // Write the templated config file
-    let config_file = File::create(blog_dir.join("config.toml"))?;
-    tera.render_to("config.toml", &context, &config_file)?;
-    let content_index_file = File::create(blog_dir.join("content/_index.md"))?;
-    tera.render_to("_index.md", &tera::Context::new(), &content_index_file)?;
+    // Write the config file, and the (empty) feed that goes with it
+    write_config(&blog, settings)?;
+    feed::write_json_feed(&blog, &[])?;
 
-    let static_root =
-        env::var("STATIC_ROOT").map_err(|e| format!("failed to get static root: {:?}", e))?;
+    Ok(())
+}
 
-    let out_dir = Path::new(&static_root).join(user_domain);
+// Rewrites the blog's config (and anything else that depends on its
+// settings, like the JSON feed). It still needs a full build afterwards.
+pub fn update_settings(
+    settings: &BlogSettings,
+    posts: &[(u64, PostRecord)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let blog = paths::blog(&settings.subdomain)?;
+    write_config(&blog, settings)?;
+    feed::write_json_feed(&blog, posts)?;
+    Ok(())
+}
+
+// A copy of a blog written out again from its settings and posts, through
+// the current templates, so it can be checked before it replaces the blog.
+// It lives next to the blogs, so that swapping it in is just a rename.
+pub struct StagedBlog {
+    scratch: tempfile::TempDir,
+    blog: Root,
+    copy: Root,
+}
 
-    run_zola(blog_dir, out_dir)?;
+pub fn stage_blog(
+    settings: &BlogSettings,
+    posts: &[(u64, PostRecord)],
+) -> Result<StagedBlog, Box<dyn std::error::Error>> {
+    let blog = paths::blog(&settings.subdomain)?;
+    let scratch = tempfile::tempdir_in(Root::from_env("BLOG_ROOT")?.path())?;
+    let copy = Root::new(scratch.path())?.subroot("blog")?;
+    copy_project(&blog, &copy)?;
+    write_config(&copy, settings)?;
+    for (post_id, record) in posts {
+        write_post(&copy, PostToWrite::new(*post_id, record))?;
+    }
+    feed::write_json_feed(&copy, posts)?;
+    Ok(StagedBlog {
+        scratch,
+        blog,
+        copy,
+    })
+}
+
+impl StagedBlog {
+    // Builds the copy somewhere that's thrown away, to check it builds.
+    pub fn check(&self) -> impl FnOnce() -> Result<(), BuildError> + Send + 'static {
+        let copy = self.copy.clone();
+        let out_dir = self.scratch.path().join("public");
+        move || render::build(&copy, out_dir, Changes::All).map_err(BuildError::from_error)
+    }
+
+    // Swaps the copy in for the blog in one go, and throws the old one away.
+    // It still needs a full build afterwards.
+    pub fn swap_in(self) -> Result<(), Box<dyn std::error::Error>> {
+        exchange(self.copy.path(), self.blog.path())?;
+        Ok(())
+    }
+}
+
+// Swaps two paths atomically, so there's never a moment when neither is
+// there.
+fn exchange(a: &Path, b: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let cstring = |p: &Path| {
+        std::ffi::CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    };
+    let (a, b) = (cstring(a)?, cstring(b)?);
+    // Safe because both are valid C strings that outlive the call
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
 
+// Whether the blog's config.toml is what its settings make now. Blogs made
+// before the config had everything posts use, like the taxonomies for TAGS:
+// and CATEGORIES:, won't build until it's written out again.
+pub fn config_is_current(settings: &BlogSettings) -> Result<bool, Box<dyn std::error::Error>> {
+    let blog = paths::blog(&settings.subdomain)?;
+    config_matches(&blog, &themes::themes_root()?, settings)
+}
+
+fn config_matches(
+    blog: &Root,
+    themes: &Root,
+    settings: &BlogSettings,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let theme = themes::find(themes, &settings.theme)?;
+    let on_disk = fs::read_to_string(blog.join("config.toml")?).unwrap_or_default();
+    Ok(on_disk == settings.site_config(&theme).to_toml()?)
+}
+
+fn write_config(blog: &Root, settings: &BlogSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let themes = themes::themes_root()?;
+    let theme = themes::find(&themes, &settings.theme)?;
+
+    fs::write(
+        blog.join("config.toml")?,
+        settings.site_config(&theme).to_toml()?,
+    )?;
+    sync_site_templates(blog, &themes, &theme)?;
+    write_custom_css(blog, &themes, &theme, &settings.custom_css)?;
+    fs::write(
+        blog.join("content/_index.md")?,
+        zola::content_file(&zola::SectionFrontMatter::default(), &settings.intro)?,
+    )?;
     Ok(())
 }
 
-fn find_title(msg: &str) -> (Option<&str>, Option<usize>) {
-    for (idx, line) in msg.lines().enumerate() {
-        if let Some(title) = line.strip_prefix("# ") {
-            if !title.is_empty() {
-                return (Some(title), Some(idx));
+// The tag that marks a mention as the homepage intro, rather than a post
+const HOMEPAGE_TAG: &str = "#homepage";
+
+// If the message is meant to be the homepage intro (it starts with
+// #homepage), returns the intro without the tag.
+pub fn homepage_intro(raw_msg: &str) -> Option<String> {
+    let msg = raw_msg.replace("@**Blog Bot (HyperTXT)**", "");
+    let rest = msg.trim_start().strip_prefix(HOMEPAGE_TAG)?;
+    if rest.starts_with(|c: char| !c.is_whitespace()) {
+        // e.g. #homepages
+        return None;
+    }
+    Some(rest.trim().to_string())
+}
+
+// Any uploads the intro uses need to be on the blog too
+pub async fn download_intro_images(intro: &str) -> Result<(), Box<dyn std::error::Error>> {
+    download_images(extract_user_upload_urls(intro)).await
+}
+
+// Custom CSS goes in the blog's static dir, and gets linked from every page
+// by filling in the theme's extra_head block. Themes without one can't have
+// custom CSS, so we just leave it unused.
+fn write_custom_css(
+    blog: &Root,
+    themes: &Root,
+    theme: &Theme,
+    css: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let static_dir = blog.join("static")?;
+    fs::create_dir_all(&static_dir)?;
+    let css_path = Root::new(static_dir)?.join(themes::CUSTOM_CSS_FILENAME)?;
+    let index_path = blog.join("templates/index.html")?;
+
+    if css.is_empty() || !theme.has_block(themes, "index.html", "extra_head") {
+        for path in [css_path, index_path] {
+            if path.exists() {
+                fs::remove_file(path)?;
             }
         }
-        if let Some(title) = line.strip_prefix("TITLE: ") {
-            if !title.is_empty() {
-                return (Some(title), Some(idx));
+        return Ok(());
+    }
+
+    fs::write(css_path, css)?;
+    fs::write(
+        index_path,
+        format!(
+            "{{% extends \"{}/templates/index.html\" %}}\n\
+             {{% block extra_head %}}\n\
+             {{{{ super() }}}}\n\
+             <link rel=\"stylesheet\" href=\"{{{{ get_url(path='{}', cachebust=true) }}}}\">\n\
+             {{% endblock extra_head %}}\n",
+            theme.name,
+            themes::CUSTOM_CSS_FILENAME
+        ),
+    )?;
+    Ok(())
+}
+
+// Copies our own templates (TEMPLATES_ROOT/site) into the blog's templates
+// dir, where Zola prefers them over the theme's. Some of them just reuse one
+// of the theme's templates, so those are left out (or removed, if we're
+// switching themes) when the theme doesn't have it.
+fn sync_site_templates(
+    blog: &Root,
+    themes: &Root,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let src = Path::new(&env::var("TEMPLATES_ROOT").unwrap()).join("site");
+    let dst = Root::new(blog.join("templates")?)?;
+    sync_dir(
+        &src,
+        &dst,
+        Path::new(""),
+        &|template| match extended_template(template) {
+            Some(parent) => theme.has_template(themes, parent),
+            None => true,
+        },
+    )
+}
+
+fn sync_dir(
+    src: &Path,
+    dst: &Root,
+    rel: &Path,
+    keep: &dyn Fn(&str) -> bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(src.join(rel))? {
+        let entry = entry?;
+        let rel = rel.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(dst.join(&rel)?)?;
+            sync_dir(src, dst, &rel, keep)?;
+        } else if keep(&fs::read_to_string(entry.path())?) {
+            fs::copy(entry.path(), dst.join(&rel)?)?;
+        } else {
+            let dst_path = dst.join(&rel)?;
+            if dst_path.exists() {
+                fs::remove_file(dst_path)?;
             }
         }
     }
-    (None, None)
+    Ok(())
 }
 
-fn todays_date(timestamp: u64, rfc3339: bool) -> String {
+// The template named in a Tera `{% extends "..." %}`, if there is one.
+fn extended_template(template: &str) -> Option<&str> {
+    let rest = template.trim_start().strip_prefix("{%")?.trim_start();
+    let rest = rest.strip_prefix("extends")?.trim_start();
+    let rest = rest.strip_prefix('"')?;
+    Some(&rest[..rest.find('"')?])
+}
+
+pub fn todays_date(timestamp: u64, rfc3339: bool) -> String {
     let ts = DateTime::from_timestamp(timestamp as i64, 0).unwrap();
     let timezone: Tz = "America/New_York".parse().unwrap();
     let local_time: DateTime<Tz> = ts.with_timezone(&timezone);
@@ -115,7 +439,7 @@ fn todays_date(timestamp: u64, rfc3339: bool) -> String {
 // The idea is that posts can contain uploaded images, which start with
 // `/user_uploads/...`. We want to (heuristically) find all those URLs so that
 // we can download them and serve them on the blog.
-fn extract_user_upload_urls(markdown: &str) -> Vec<String> {
+pub fn extract_user_upload_urls(markdown: &str) -> Vec<String> {
     let mut urls = Vec::new();
 
     for line in markdown.lines() {
@@ -138,68 +462,83 @@ fn extract_user_upload_urls(markdown: &str) -> Vec<String> {
     urls
 }
 
-pub async fn refresh_all_posts(
-    user_subdomain: &str,
-    post_ids: Vec<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let root = env::var("BLOG_ROOT").unwrap(); // Something like path/to/blogs/
-    let blog_dir = Path::new(&root).join(user_subdomain);
-    let static_root =
-        env::var("STATIC_ROOT").map_err(|e| format!("failed to get static root: {:?}", e))?;
-
-    // TODO: Probably update this to also regenerate other files, like the config.toml + the content/_index.md
-
-    println!("Refreshing {} posts", post_ids.len());
-    for post_id in post_ids {
-        let msg = zulip::get_message(post_id).await?;
-        let parsed_message = parse_raw_message(&msg.content, msg.timestamp);
-        download_images(parsed_message.image_urls, &static_root).await?;
-        write_post(
-            &blog_dir,
-            PostToWrite {
-                title: parsed_message.title,
-                timestamp: msg.timestamp,
-                body: parsed_message.body,
-                post_id,
-            },
-        )?;
-    }
+// Where a post lives on its blog: the current slug, plus any old ones that
+// should keep working as redirects.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostSlug {
+    pub slug: String,
+    pub aliases: Vec<String>,
+}
 
-    let out_dir = Path::new(&static_root).join(user_subdomain);
+// The slug a post would like to have, before we've checked it against the
+// author's other posts. Empty if there's nothing to make one from, e.g. a
+// title that's all emoji.
+pub fn desired_slug(raw_msg: &str, timestamp: u64) -> String {
+    let msg = parse_raw_message(raw_msg, timestamp);
+    let metadata = msg.metadata;
+    slug::slugify(metadata.page.or(metadata.slug).unwrap_or(msg.title))
+}
 
-    run_zola(blog_dir, out_dir)?;
+// How many messages to ask Zulip for in each request, and how many requests
+// (for messages or uploads) can be out at once
+const FETCH_BATCH_SIZE: usize = 100;
+const FETCH_CONCURRENCY: usize = 4;
+
+// Catches the posts up with their messages as they are on Zulip now, and
+// downloads any uploads they use that we don't have yet. Returns the IDs of
+// the messages that have gone, whose posts are left as they were.
+pub async fn refetch_posts(
+    posts: &mut [(u64, PostRecord)],
+) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    use futures::{StreamExt, TryStreamExt};
+
+    println!("Refetching {} posts", posts.len());
+    let post_ids: Vec<u64> = posts.iter().map(|(post_id, _)| *post_id).collect();
+    let batches: Vec<Vec<zulip::Message>> =
+        futures::stream::iter(post_ids.chunks(FETCH_BATCH_SIZE))
+            .map(zulip::get_messages)
+            .buffer_unordered(FETCH_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+    let missing = refresh_posts(posts, batches.into_iter().flatten());
+    let assets = posts
+        .iter()
+        .flat_map(|(_, record)| record.assets.clone())
+        .collect();
+    download_images(assets).await?;
+    Ok(missing)
+}
 
-    Ok(())
+fn refresh_posts(
+    posts: &mut [(u64, PostRecord)],
+    messages: impl IntoIterator<Item = zulip::Message>,
+) -> Vec<u64> {
+    let mut messages: HashMap<u64, zulip::Message> =
+        messages.into_iter().map(|msg| (msg.id, msg)).collect();
+    let mut missing = Vec::new();
+    for (post_id, record) in posts.iter_mut() {
+        match messages.remove(post_id) {
+            Some(msg) => record.refresh(&msg),
+            None => missing.push(*post_id),
+        }
+    }
+    missing
 }
 
-struct ParsedMessage {
-    title: String,
-    body: String,
-    image_urls: Vec<String>,
+pub struct ParsedMessage {
+    pub title: String,
+    pub metadata: PostMetadata,
+    pub body: String,
+    pub image_urls: Vec<String>,
+    pub warnings: Vec<String>,
 }
 
-fn parse_raw_message(raw_msg: &str, timestamp: u64) -> ParsedMessage {
+pub fn parse_raw_message(raw_msg: &str, timestamp: u64) -> ParsedMessage {
     let msg = raw_msg.replace("@**Blog Bot (HyperTXT)**", "");
 
-    let (post_title, line_to_remove) = find_title(&msg);
-
-    let post_markdown = match line_to_remove {
-        Some(ltr) => {
-            let val = msg
-                .lines()
-                .enumerate()
-                .filter(|(idx, _)| *idx != ltr)
-                .map(|(_, v)| v)
-                .fold(String::new(), |mut a, b| {
-                    a.reserve(b.len() + 1);
-                    a.push_str(b);
-                    a.push('\n');
-                    a
-                });
-            val.trim().to_owned()
-        }
-        None => msg.clone(),
-    };
+    let parsed = metadata::parse_post(&msg);
+    let post_markdown = parsed.body;
 
     // TODO: Zulip messages only inline the images as links, we should
     // update this to automatically turn them into Markdown images too.
@@ -207,58 +546,152 @@ fn parse_raw_message(raw_msg: &str, timestamp: u64) -> ParsedMessage {
     let image_urls = extract_user_upload_urls(&post_markdown);
 
     ParsedMessage {
-        title: post_title
-            .map(|v| v.to_string())
+        title: parsed
+            .metadata
+            .title
+            .clone()
             .unwrap_or_else(|| todays_date(timestamp, false)),
+        metadata: parsed.metadata,
         body: post_markdown,
         image_urls,
+        warnings: parsed.warnings,
     }
 }
 
-pub async fn add_post(
+// Builds a scratch copy of the blog with the post added, and the feed as it
+// would be with it (`posts`), so that a post that would break the build never
+// makes it into the real one. It waits for a slot in the build queue like any
+// other build. Fetches any uploads it uses first, since they're shared.
+// Returns any warnings about the post.
+pub async fn check_post(
+    builds: &BuildQueue,
     user_subdomain: &str,
     post_id: u64,
-    raw_msg: &str,
-    timestamp: u64,
+    record: &PostRecord,
+    posts: &[(u64, PostRecord)],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let blog = paths::blog(user_subdomain)?;
+
+    download_images(record.assets.clone()).await?;
+
+    let title = record.title.clone();
+    let post = PostToWrite::new(post_id, record);
+    let posts = posts.to_vec();
+    let result = builds
+        .run_one_off(
+            &format!("{} with post {}", user_subdomain, post_id),
+            move || build_with_post(&blog, post, &posts).map_err(BuildError::from_error),
+        )
+        .await;
+    if let Err(e) = result {
+        eprintln!("post {} doesn't build: {}\n{}", post_id, e, e.output);
+        // Anything else failing would have failed without this post too
+        let ours = e.post_id() == Some(post_id);
+        let title = ours.then_some(title.as_str());
+        let content = ours.then_some(record.content.as_str());
+        return Err(format!(
+            "{}\n\nI haven't published it, so your blog is just as it was.",
+            e.explain(title, content)
+        )
+        .into());
+    }
+
+    Ok(record.parsed().warnings)
+}
+
+fn build_with_post(
+    blog: &Root,
+    post: PostToWrite,
+    posts: &[(u64, PostRecord)],
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let msg = parse_raw_message(raw_msg, timestamp);
+    let scratch = tempfile::tempdir()?;
+    let copy = Root::new(scratch.path())?.subroot("blog")?;
+    copy_project(blog, &copy)?;
+    write_post(&copy, post)?;
+    feed::write_json_feed(&copy, posts)?;
+    let out_dir = Root::new(scratch.path())?.subroot("public")?;
+    render::build(&copy, out_dir.path(), Changes::All)
+}
 
-    let static_root =
-        env::var("STATIC_ROOT").map_err(|e| format!("failed to get static root: {:?}", e))?;
+// Copies everything a build reads: the content, config, templates and static
+// files. The themes are shared, so their symlink is copied as a symlink.
+fn copy_project(blog: &Root, dst: &Root) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(blog.path())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let target = dst.child(name)?;
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, target)?;
+        } else if file_type.is_dir() {
+            // Zola's default output dir, if anyone's ever built in here
+            if name == "public" {
+                continue;
+            }
+            fs::create_dir(&target)?;
+            render::copy_dir(&entry.path(), &Root::new(&target)?)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
 
-    download_images(msg.image_urls, &static_root).await?;
+// Writes the post into the blog, once `check_post` has passed. Returns what
+// its files held before, so that they can be put back if recording the post
+// in the DB fails.
+pub fn add_post(
+    user_subdomain: &str,
+    post_id: u64,
+    record: &PostRecord,
+) -> Result<PreviousPost, Box<dyn std::error::Error>> {
+    let blog = paths::blog(user_subdomain)?;
+    let mut previous = PreviousPost { files: Vec::new() };
+    for file in post_files(&blog, post_id)? {
+        let contents = fs::read(&file).ok();
+        previous.files.push((file, contents));
+    }
 
-    let root = env::var("BLOG_ROOT").unwrap(); // Something like path/to/blogs/
-    let blog_dir = Path::new(&root).join(user_subdomain);
+    write_post(&blog, PostToWrite::new(post_id, record))?;
 
-    write_post(
-        &blog_dir,
-        PostToWrite {
-            title: msg.title,
-            timestamp,
-            body: msg.body,
-            post_id,
-        },
-    )?;
+    Ok(previous)
+}
 
-    let out_dir = Path::new(&static_root).join(user_subdomain);
+pub struct PreviousPost {
+    files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
 
-    run_zola(blog_dir, out_dir)?;
-    Ok(())
+impl PreviousPost {
+    pub fn restore(self) -> Result<(), Box<dyn std::error::Error>> {
+        for (file, contents) in self.files {
+            match contents {
+                Some(contents) => fs::write(&file, contents)?,
+                None if file.exists() => fs::remove_file(&file)?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
 }
 
-async fn download_images(
-    image_urls: Vec<String>,
-    static_root: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+async fn download_images(image_urls: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    use futures::StreamExt;
+
+    let uploads = paths::uploads_root()?;
+    let mut downloads = Vec::new();
     for url in image_urls {
         // Create the destination path: STATIC_ROOT/../user_uploads/...
         // The URL is like /user_uploads/13/SJXAkls4A6mqvoVyWpeciPlO/DSC_0583.png
-        let relative_path = url.trim_start_matches('/');
-        let dst_path = Path::new(static_root)
-            .parent()
-            .ok_or("STATIC_ROOT has no parent directory")?
-            .join(relative_path);
+        let dst_path = match paths::upload_path(&uploads, &url) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Skipping image {}: {}", url, e);
+                continue;
+            }
+        };
 
         // Create parent directories if they don't exist
         if let Some(parent) = dst_path.parent() {
@@ -270,24 +703,49 @@ async fn download_images(
             })?;
         }
 
-        // Download the image
-        println!("Downloading image {}", url);
-        if let Err(e) = zulip::download_image(&url, dst_path.to_str().unwrap()).await {
-            eprintln!("Failed to download image {}: {}", url, e);
-        }
+        downloads.push((url, dst_path));
     }
 
+    // Download the images, a few at a time
+    futures::stream::iter(downloads)
+        .for_each_concurrent(FETCH_CONCURRENCY, |(url, dst_path)| async move {
+            println!("Downloading image {}", url);
+            if let Err(e) = zulip::download_image(&url, dst_path.to_str().unwrap()).await {
+                eprintln!("Failed to download image {}: {}", url, e);
+            }
+        })
+        .await;
+
     Ok(())
 }
 
 struct PostToWrite {
     title: String,
+    metadata: PostMetadata,
+    slug: Option<PostSlug>,
     timestamp: u64,
+    // When the post was last edited, if it has been
+    edited: Option<u64>,
     body: String,
     post_id: u64,
 }
 
-fn write_post(blog_dir: &Path, post: PostToWrite) -> Result<(), Box<dyn std::error::Error>> {
+impl PostToWrite {
+    fn new(post_id: u64, record: &PostRecord) -> PostToWrite {
+        let parsed = record.parsed();
+        PostToWrite {
+            title: parsed.title,
+            metadata: parsed.metadata,
+            slug: record.slug(),
+            timestamp: record.timestamp.unwrap_or(0),
+            edited: record.edited_at.last().copied(),
+            body: parsed.body,
+            post_id,
+        }
+    }
+}
+
+fn write_post(blog: &Root, post: PostToWrite) -> Result<(), Box<dyn std::error::Error>> {
     let tera = Tera::new(
         Path::new(&env::var("TEMPLATES_ROOT").unwrap())
             .join("*.md")
@@ -295,31 +753,100 @@ fn write_post(blog_dir: &Path, post: PostToWrite) -> Result<(), Box<dyn std::err
             .unwrap(),
     )?;
 
+    // Everything before `<!-- more -->` is what Zola uses as the summary
+    let markdown = match &post.metadata.summary {
+        Some(summary) => format!("{}\n\n<!-- more -->\n\n{}", summary, post.body),
+        None => post.body,
+    };
+
     let mut context = tera::Context::new();
-    context.insert("post_title", &post.title);
-    context.insert("post_date", &todays_date(post.timestamp, true));
-    context.insert("post_markdown", &post.body);
-
-    let post_file = File::create(
-        blog_dir
-            .join("content")
-            .join(post.post_id.to_string() + ".md"),
-    )?;
-    tera.render_to("post.md", &context, &post_file).unwrap();
+    context.insert("post_markdown", &markdown);
+    let body = tera.render("post.md", &context)?;
+
+    let mut taxonomies = BTreeMap::new();
+    if !post.metadata.tags.is_empty() {
+        taxonomies.insert("tags".to_string(), post.metadata.tags);
+    }
+    if !post.metadata.categories.is_empty() {
+        taxonomies.insert("categories".to_string(), post.metadata.categories);
+    }
+
+    let is_page = post.metadata.page.is_some();
+    let slug = post.slug.as_ref().map(|s| s.slug.clone());
+    let front_matter = zola::PageFrontMatter {
+        title: post.title,
+        date: match (is_page, post.metadata.date) {
+            (false, date) => Some(date.unwrap_or_else(|| todays_date(post.timestamp, true))),
+            (true, _) => None,
+        },
+        // Themes that show when a post was updated get it from the last
+        // edit, unless the author said otherwise
+        updated: post
+            .metadata
+            .updated
+            .or_else(|| post.edited.map(|ts| todays_date(ts, true))),
+        description: post.metadata.description,
+        slug: if is_page { None } else { slug.clone() },
+        path: if is_page {
+            Some(slug.unwrap_or_else(|| post.post_id.to_string()))
+        } else {
+            None
+        },
+        aliases: post
+            .slug
+            .map(|s| s.aliases.iter().map(|a| format!("/{}/", a)).collect())
+            .unwrap_or_default(),
+        draft: post.metadata.draft,
+        taxonomies,
+    };
+
+    // Pages live in their own section, so they stay out of the list of posts
+    // on the homepage. A post can turn into a page (or back) when it's
+    // edited, so whichever file it isn't any more gets removed.
+    let [post_file, page_file] = post_files(blog, post.post_id)?;
+    let (file, old_file) = if is_page {
+        write_pages_section(blog)?;
+        (page_file, post_file)
+    } else {
+        (post_file, page_file)
+    };
+    if old_file.exists() {
+        fs::remove_file(old_file)?;
+    }
+    fs::write(file, zola::content_file(&front_matter, &body)?)?;
     Ok(())
 }
 
-fn run_zola<P: AsRef<Path>, Q: AsRef<Path>>(zola_dir: P, out_dir: Q) -> Result<(), Box<dyn Error>> {
-    let mut build_cmd = Command::new("zola");
-    build_cmd.arg("build");
-    build_cmd.arg("--force");
-    build_cmd.current_dir(zola_dir);
-    build_cmd.args(["--output-dir", out_dir.as_ref().to_str().unwrap()]);
-    let status = build_cmd.status()?;
+// Where a post's content file goes, and where it goes if it's a page.
+pub fn post_files(blog: &Root, post_id: u64) -> Result<[PathBuf; 2], String> {
+    let filename = format!("{}.md", post_id);
+    let [posts, pages] = content_dirs(blog)?;
+    Ok([posts.join(&filename), pages.join(&filename)])
+}
+
+// The directories posts' content files go in: posts, then pages.
+pub fn content_dirs(blog: &Root) -> Result<[PathBuf; 2], String> {
+    Ok([
+        blog.join("content")?,
+        blog.join(format!("content/{}", PAGES_SECTION))?,
+    ])
+}
+
+// The section standalone pages go in. It isn't rendered itself, but the pages
+// in it still are, at whatever path they ask for.
+const PAGES_SECTION: &str = "pages";
 
-    if !status.success() {
-        return Err("error running command".into());
+fn write_pages_section(blog: &Root) -> Result<(), Box<dyn std::error::Error>> {
+    let index = blog.join(format!("content/{}/_index.md", PAGES_SECTION))?;
+    if index.exists() {
+        return Ok(());
     }
+    fs::create_dir_all(blog.join(format!("content/{}", PAGES_SECTION))?)?;
+    let front_matter = zola::SectionFrontMatter {
+        render: false,
+        ..Default::default()
+    };
+    fs::write(index, zola::content_file(&front_matter, "")?)?;
     Ok(())
 }
 
@@ -327,6 +854,204 @@ fn run_zola<P: AsRef<Path>, Q: AsRef<Path>>(zola_dir: P, out_dir: Q) -> Result<(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_metadata() {
+        let md = parse_metadata("SUBDOMAIN: myblog\nINTRO: Hi: I write about Rust").unwrap();
+        assert_eq!(md["SUBDOMAIN"], "myblog");
+        assert_eq!(md["INTRO"], "Hi: I write about Rust");
+        assert!(parse_metadata("no key here").is_err());
+    }
+
+    #[test]
+    fn test_extended_template() {
+        assert_eq!(
+            extended_template("{% extends \"tags/list.html\" %}\n"),
+            Some("tags/list.html")
+        );
+        assert_eq!(
+            extended_template("  {%extends \"index.html\"%}"),
+            Some("index.html")
+        );
+        assert_eq!(extended_template("<html></html>"), None);
+        assert_eq!(extended_template("{% block content %}"), None);
+    }
+
+    #[test]
+    fn test_homepage_intro() {
+        assert_eq!(
+            homepage_intro("@**Blog Bot (HyperTXT)** #homepage\n\nHi, I'm **me**.\n"),
+            Some("Hi, I'm **me**.".to_string())
+        );
+        assert_eq!(homepage_intro("#homepage Hello"), Some("Hello".to_string()));
+        assert_eq!(homepage_intro("#homepage"), Some(String::new()));
+        assert_eq!(homepage_intro("#homepages are great"), None);
+        assert_eq!(homepage_intro("A post about my #homepage"), None);
+        assert_eq!(homepage_intro("# homepage\n\nA post"), None);
+    }
+
+    #[test]
+    fn test_write_custom_css() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let blog_tmp = tempfile::tempdir().unwrap();
+        let blog = Root::new(blog_tmp.path()).unwrap();
+        fs::create_dir(blog.join("templates").unwrap()).unwrap();
+        let terminimal = themes::find(&themes, "terminimal").unwrap();
+        let plain = themes::find(&themes, "plain").unwrap();
+        let css = blog.join("static/custom.css").unwrap();
+        let index = blog.join("templates/index.html").unwrap();
+
+        write_custom_css(&blog, &themes, &terminimal, "h1 { color: red; }").unwrap();
+        assert_eq!(fs::read_to_string(&css).unwrap(), "h1 { color: red; }");
+        let template = fs::read_to_string(&index).unwrap();
+        assert_eq!(
+            extended_template(&template),
+            Some("terminimal/templates/index.html")
+        );
+        assert!(template.contains("get_url(path='custom.css'"));
+
+        // Switching to a theme we can't add it to drops it
+        write_custom_css(&blog, &themes, &plain, "h1 { color: red; }").unwrap();
+        assert!(!css.exists());
+        assert!(!index.exists());
+
+        write_custom_css(&blog, &themes, &terminimal, "h1 { color: red; }").unwrap();
+        write_custom_css(&blog, &themes, &terminimal, "").unwrap();
+        assert!(!css.exists());
+        assert!(!index.exists());
+    }
+
+    #[test]
+    fn test_exchange() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b) = (tmp.path().join("a"), tmp.path().join("b"));
+        fs::create_dir(&a).unwrap();
+        fs::write(a.join("file"), "from a").unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(b.join("file"), "from b").unwrap();
+        exchange(&a, &b).unwrap();
+        assert_eq!(fs::read_to_string(a.join("file")).unwrap(), "from b");
+        assert_eq!(fs::read_to_string(b.join("file")).unwrap(), "from a");
+        assert!(exchange(&a, &tmp.path().join("c")).is_err());
+    }
+
+    #[test]
+    fn test_copy_project() {
+        let (_themes_tmp, themes) = themes::tests::test_themes();
+        let blog_tmp = tempfile::tempdir().unwrap();
+        let blog = Root::new(blog_tmp.path()).unwrap();
+        fs::create_dir_all(blog.join("content/pages").unwrap()).unwrap();
+        fs::write(blog.join("config.toml").unwrap(), "title = \"Blog\"").unwrap();
+        fs::write(blog.join("content/1.md").unwrap(), "One").unwrap();
+        fs::write(blog.join("content/pages/2.md").unwrap(), "Two").unwrap();
+        fs::create_dir(blog.join("public").unwrap()).unwrap();
+        std::os::unix::fs::symlink(themes.path(), blog.join("themes").unwrap()).unwrap();
+
+        let copy_tmp = tempfile::tempdir().unwrap();
+        let copy = Root::new(copy_tmp.path()).unwrap();
+        copy_project(&blog, &copy).unwrap();
+        let read = |p: &str| fs::read_to_string(copy.path().join(p)).unwrap();
+        assert_eq!(read("config.toml"), "title = \"Blog\"");
+        assert_eq!(read("content/1.md"), "One");
+        assert_eq!(read("content/pages/2.md"), "Two");
+        assert!(!copy.path().join("public").exists());
+        assert_eq!(
+            fs::read_link(copy.path().join("themes")).unwrap(),
+            themes.path()
+        );
+
+        // Writing to the copy doesn't touch the blog
+        fs::write(copy.path().join("content/1.md"), "Changed").unwrap();
+        assert_eq!(
+            fs::read_to_string(blog.join("content/1.md").unwrap()).unwrap(),
+            "One"
+        );
+    }
+
+    #[test]
+    fn test_restore_previous_post() {
+        let blog_tmp = tempfile::tempdir().unwrap();
+        let blog = Root::new(blog_tmp.path()).unwrap();
+        fs::create_dir_all(blog.join("content/pages").unwrap()).unwrap();
+        let [post_file, page_file] = post_files(&blog, 7).unwrap();
+        fs::write(&post_file, "Before").unwrap();
+
+        let previous = PreviousPost {
+            files: vec![
+                (post_file.clone(), fs::read(&post_file).ok()),
+                (page_file.clone(), fs::read(&page_file).ok()),
+            ],
+        };
+        // e.g. the post turned into a page
+        fs::remove_file(&post_file).unwrap();
+        fs::write(&page_file, "After").unwrap();
+
+        previous.restore().unwrap();
+        assert_eq!(fs::read_to_string(&post_file).unwrap(), "Before");
+        assert!(!page_file.exists());
+    }
+
+    #[test]
+    fn test_config_matches() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let theme = themes::find(&themes, "terminimal").unwrap();
+        let blog_tmp = tempfile::tempdir().unwrap();
+        let blog = Root::new(blog_tmp.path()).unwrap();
+        let settings = BlogSettings::new("myblog");
+        assert!(!config_matches(&blog, &themes, &settings).unwrap());
+
+        let mut config = settings.site_config(&theme);
+        fs::write(blog.join("config.toml").unwrap(), config.to_toml().unwrap()).unwrap();
+        assert!(config_matches(&blog, &themes, &settings).unwrap());
+
+        // e.g. from before blogs had taxonomies
+        config.taxonomies.clear();
+        fs::write(blog.join("config.toml").unwrap(), config.to_toml().unwrap()).unwrap();
+        assert!(!config_matches(&blog, &themes, &settings).unwrap());
+    }
+
+    #[test]
+    fn test_site_config_uses_settings() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let theme = themes::find(&themes, "terminimal").unwrap();
+        let settings = BlogSettings {
+            accent_color: "pink".to_string(),
+            highlight_theme: Some("dracula".to_string()),
+            ..BlogSettings::new("myblog")
+        };
+        let config = settings.site_config(&theme);
+        assert_eq!(config.extra["accent_color"].as_str(), Some("pink"));
+        assert_eq!(config.markdown.highlight_theme.as_deref(), Some("dracula"));
+        assert!(!config.extra.contains_key("menu_items"));
+        assert_eq!(config.base_url, "https://myblog.hypertxt.io");
+
+        let settings = BlogSettings {
+            domain: Some("example.com".to_string()),
+            ..BlogSettings::new("myblog")
+        };
+        assert_eq!(settings.url(), "https://example.com");
+        assert_eq!(settings.site_config(&theme).base_url, "https://example.com");
+
+        let mut settings = BlogSettings::new("myblog");
+        settings.set("MENU", "About, projects,,about").unwrap();
+        assert_eq!(settings.menu, vec!["about", "projects"]);
+        let config = settings.site_config(&theme);
+        let items = config.extra["menu_items"].as_array().unwrap();
+        let urls: Vec<_> = items.iter().map(|i| i["url"].as_str().unwrap()).collect();
+        assert_eq!(
+            urls,
+            vec!["$BASE_URL", "$BASE_URL/about", "$BASE_URL/projects"]
+        );
+
+        // Settings stored before customization existed get the defaults
+        let old: BlogSettings = serde_json::from_str(
+            r#"{"subdomain":"myblog","blog_name":"B","author":"A","theme":"terminimal"}"#,
+        )
+        .unwrap();
+        assert_eq!(old.accent_color, "blue");
+        assert_eq!(old.highlight_theme, None);
+        assert_eq!(old.custom_css, "");
+    }
+
     #[test]
     fn test_extract_markdown_image_single_url() {
         let markdown = "![alt text](/user_uploads/13/SJXAkls4A6mqvoVyWpeciPlO/DSC_0583.png)";
@@ -363,7 +1088,7 @@ mod tests {
 
 Here is an image: ![photo](/user_uploads/13/abc/photo.png)
 
-And here is another one: [photo 2](/user_uploads/14/def/p2.jpeg)
+And here is another one: [doc](/user_uploads/14/def/doc.pdf)
 
 And a third: <img src="/user_uploads/15/ghi/banner.jpg">
 "#;
@@ -374,6 +1099,13 @@ And a third: <img src="/user_uploads/15/ghi/banner.jpg">
         assert_eq!(urls[2], "/user_uploads/15/ghi/banner.jpg");
     }
 
+    #[test]
+    fn test_extract_link_to_upload() {
+        let markdown = "And here is another one: [photo 2](/user_uploads/14/def/p2.jpeg)";
+        let urls = extract_user_upload_urls(markdown);
+        assert_eq!(urls, vec!["/user_uploads/14/def/p2.jpeg"]);
+    }
+
     #[test]
     fn test_extract_empty_string() {
         let markdown = "";
@@ -432,4 +1164,46 @@ Also an external ![image](https://example.com/pic.jpg) and an internal ![image](
         assert_eq!(urls.len(), 1);
         assert_eq!(urls[0], "/user_uploads/30/abc/image.png");
     }
+
+    #[test]
+    fn test_refresh_posts() {
+        let message = |id, content: &str| zulip::Message {
+            content: content.to_string(),
+            id,
+            sender_id: 1,
+            stream_id: Some(7),
+            timestamp: 1_700_000_000,
+            subject: "blogging".to_string(),
+            sender_full_name: "Test User".to_string(),
+            last_edit_timestamp: Some(1_700_000_500),
+        };
+        let stored = |content: &str| {
+            let mut record = PostRecord {
+                user_id: 1,
+                ..Default::default()
+            };
+            record.set_content(content);
+            record
+        };
+        let mut posts = vec![
+            (100, stored("# Old title")),
+            (101, stored("# Deleted")),
+            (102, stored("# Same")),
+        ];
+
+        let missing = refresh_posts(
+            &mut posts,
+            [
+                message(102, "# Same"),
+                message(100, "# New title\n\n![](/user_uploads/1/ab/pic.png)"),
+            ],
+        );
+        assert_eq!(missing, vec![101]);
+        assert_eq!(posts[0].1.title, "New title");
+        assert_eq!(posts[0].1.assets, vec!["/user_uploads/1/ab/pic.png"]);
+        assert_eq!(posts[0].1.edited_at, vec![1_700_000_500]);
+        assert_eq!(posts[1].1.title, "Deleted");
+        assert_eq!(posts[1].1.timestamp, None);
+        assert_eq!(posts[2].1.author, "Test User");
+    }
 }
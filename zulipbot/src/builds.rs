@@ -0,0 +1,710 @@
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadableTable, TableDefinition};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, OwnedMutexGuard, Semaphore};
+
+use crate::deploys;
+use crate::render::{self, BuildError, Changes};
+
+// Builds run in the background, so that handlers can answer straight away and
+// a slow build doesn't hold up anyone else. Each blog has at most one build
+// waiting (anything asked for while it waits gets merged into it) and one
+// running, and only a few run at once across all blogs. The author hears how
+// it went by DM, unless it's a quiet build that went fine.
+
+// Builds that have been asked for but haven't finished, by subdomain, as a
+// JSON Job. Anything still here at startup gets queued again.
+pub const QUEUED_BUILDS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("queued_builds");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    // Who to tell how it went
+    pub user_id: u64,
+    pub changes: Changes,
+    // For builds the author didn't ask for, which they only hear about if
+    // they fail
+    #[serde(default)]
+    pub quiet: bool,
+}
+
+impl Job {
+    fn merge(self, other: Job) -> Job {
+        Job {
+            user_id: other.user_id,
+            changes: self.changes.merge(other.changes),
+            quiet: self.quiet && other.quiet,
+        }
+    }
+}
+
+// How a build went, for telling its author.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub subdomain: String,
+    pub user_id: u64,
+    pub finished_at: DateTime<Utc>,
+    pub result: Result<(), BuildError>,
+    pub quiet: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    pub queued: bool,
+    pub running: bool,
+    pub last: Option<Outcome>,
+}
+
+// Does the actual build, on a blocking thread.
+pub type BuildFn = Arc<dyn Fn(&str, Changes) -> Result<(), BuildError> + Send + Sync>;
+// Called with every finished build.
+pub type Report = Arc<dyn Fn(Outcome) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    // How many blogs can build at once
+    pub parallel: usize,
+    // A backstop, for builds the renderer doesn't stop itself
+    pub timeout: Duration,
+    // How long a build waits for more changes to come in before it starts
+    pub debounce: Duration,
+}
+
+impl Limits {
+    // From BUILD_PARALLELISM (2 by default), and twice BUILD_TIMEOUT_SECS,
+    // which the renderer enforces.
+    pub fn from_env() -> Limits {
+        Limits {
+            parallel: env::var("BUILD_PARALLELISM")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|n| *n > 0)
+                .unwrap_or(2),
+            timeout: render::build_timeout() * 2,
+            debounce: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct BuildQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    db: Arc<Database>,
+    build: BuildFn,
+    report: Report,
+    limits: Limits,
+    slots: Semaphore,
+    state: Mutex<State>,
+    // One for each blog whose content is being written, by subdomain
+    content_locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+#[derive(Default)]
+struct State {
+    queued: HashMap<String, Job>,
+    running: HashMap<String, Job>,
+    // Blogs that have a worker looking after them
+    active: HashSet<String>,
+    last: HashMap<String, Outcome>,
+    // Whoever's waiting to hear how the queued (or running) build goes
+    queued_waiters: HashMap<String, Vec<oneshot::Sender<Outcome>>>,
+    running_waiters: HashMap<String, Vec<oneshot::Sender<Outcome>>>,
+}
+
+impl BuildQueue {
+    pub fn new(db: Arc<Database>, build: BuildFn, report: Report, limits: Limits) -> BuildQueue {
+        BuildQueue {
+            inner: Arc::new(Inner {
+                db,
+                build,
+                report,
+                slots: Semaphore::new(limits.parallel),
+                limits,
+                state: Mutex::new(State::default()),
+                content_locks: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    // Builds and deploys blogs with the renderer from the environment.
+    pub fn from_env(db: Arc<Database>, report: Report) -> BuildQueue {
+        let build: BuildFn = Arc::new(|subdomain, changes| {
+            deploys::build(subdomain, changes).map_err(BuildError::from_error)
+        });
+        BuildQueue::new(db, build, report, Limits::from_env())
+    }
+
+    // Queues a build of the blog, merging it into one that's already waiting.
+    pub fn enqueue(&self, subdomain: &str, user_id: u64, changes: Changes) -> Result<(), String> {
+        let job = Job {
+            user_id,
+            changes,
+            quiet: false,
+        };
+        self.add(subdomain, job, None)
+    }
+
+    // Quietly queues a build of the blog, and waits for the build it ends up
+    // in to finish.
+    pub async fn rebuild(
+        &self,
+        subdomain: &str,
+        user_id: u64,
+        changes: Changes,
+    ) -> Result<Outcome, String> {
+        let (tx, rx) = oneshot::channel();
+        let job = Job {
+            user_id,
+            changes,
+            quiet: true,
+        };
+        self.add(subdomain, job, Some(tx))?;
+        rx.await
+            .map_err(|_| format!("the build of {} was dropped", subdomain))
+    }
+
+    fn add(
+        &self,
+        subdomain: &str,
+        job: Job,
+        waiter: Option<oneshot::Sender<Outcome>>,
+    ) -> Result<(), String> {
+        let mut state = self.inner.state.lock().unwrap();
+        let job = match state.queued.remove(subdomain) {
+            Some(queued) => queued.merge(job),
+            None => job,
+        };
+        state.queued.insert(subdomain.to_string(), job);
+        self.persist(&state, subdomain)?;
+        if let Some(waiter) = waiter {
+            state
+                .queued_waiters
+                .entry(subdomain.to_string())
+                .or_default()
+                .push(waiter);
+        }
+
+        if state.active.insert(subdomain.to_string()) {
+            tokio::spawn(self.clone().work(subdomain.to_string()));
+        }
+        Ok(())
+    }
+
+    // Queues everything that was left over when we last stopped. Returns how
+    // many blogs that was.
+    pub fn resume(&self) -> Result<usize, String> {
+        let mut jobs = Vec::new();
+        {
+            let txn = self
+                .inner
+                .db
+                .begin_read()
+                .map_err(|e| format!("failed to read queued builds: {:?}", e))?;
+            let tbl = txn
+                .open_table(QUEUED_BUILDS_TABLE)
+                .map_err(|e| format!("failed to read queued builds: {:?}", e))?;
+            let entries = tbl
+                .iter()
+                .map_err(|e| format!("failed to read queued builds: {:?}", e))?;
+            for entry in entries {
+                let (subdomain, job) =
+                    entry.map_err(|e| format!("failed to read queued builds: {:?}", e))?;
+                let job: Job = serde_json::from_str(job.value())
+                    .map_err(|e| format!("bad queued build for {}: {:?}", subdomain.value(), e))?;
+                jobs.push((subdomain.value().to_string(), job));
+            }
+        }
+        for (subdomain, job) in jobs.iter().cloned() {
+            self.add(&subdomain, job, None)?;
+        }
+        Ok(jobs.len())
+    }
+
+    // Held by anything that writes a blog's content, so that nothing else
+    // writes it in between, e.g. a post being published while the whole
+    // blog is being written out again.
+    pub async fn lock_content(&self, subdomain: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.inner.content_locks.lock().unwrap();
+            Arc::clone(locks.entry(subdomain.to_string()).or_default())
+        };
+        lock.lock_owned().await
+    }
+
+    // How many blogs can build at once
+    pub fn parallel(&self) -> usize {
+        self.inner.limits.parallel
+    }
+
+    pub fn status(&self, subdomain: &str) -> Status {
+        let state = self.inner.state.lock().unwrap();
+        Status {
+            queued: state.queued.contains_key(subdomain),
+            running: state.running.contains_key(subdomain),
+            last: state.last.get(subdomain).cloned(),
+        }
+    }
+
+    // Looks after one blog until it has nothing left to build.
+    async fn work(self, subdomain: String) {
+        loop {
+            tokio::time::sleep(self.inner.limits.debounce).await;
+
+            let job = {
+                let mut state = self.inner.state.lock().unwrap();
+                let Some(job) = state.queued.remove(&subdomain) else {
+                    state.active.remove(&subdomain);
+                    return;
+                };
+                state.running.insert(subdomain.clone(), job.clone());
+                let waiters = state.queued_waiters.remove(&subdomain).unwrap_or_default();
+                state.running_waiters.insert(subdomain.clone(), waiters);
+                job
+            };
+
+            if let Some(result) = self.run(&subdomain, &job).await {
+                self.finish(&subdomain, &job, result, true);
+            }
+            if self.stop_running(&subdomain) {
+                return;
+            }
+        }
+    }
+
+    // Builds the job, unless it's taking so long that it's already been
+    // reported as failed, in which case there's nothing left to report.
+    async fn run(&self, subdomain: &str, job: &Job) -> Option<Result<(), BuildError>> {
+        let build = Arc::clone(&self.inner.build);
+        let (name, changes) = (subdomain.to_string(), job.changes.clone());
+        self.run_in_slot(
+            subdomain,
+            move || build(&name, changes),
+            // The author hears now, but the blog stays marked as running
+            // until it stops, so that nothing else builds alongside it
+            |e| self.finish(subdomain, job, Err(e), false),
+        )
+        .await
+    }
+
+    // Runs a one-off build that isn't part of any blog's queue, e.g. of a
+    // scratch copy to check a post, in a slot like any other build.
+    pub async fn run_one_off<F>(&self, name: &str, build: F) -> Result<(), BuildError>
+    where
+        F: FnOnce() -> Result<(), BuildError> + Send + 'static,
+    {
+        let mut overdue = None;
+        let result = self.run_in_slot(name, build, |e| overdue = Some(e)).await;
+        result.or(overdue.map(Err)).unwrap_or(Ok(()))
+    }
+
+    // Waits for a slot, and builds in it. If the build runs past the
+    // timeout, `overdue` hears about it then, and the build keeps its slot
+    // until it stops, since there's no stopping it from here.
+    async fn run_in_slot<F, O>(
+        &self,
+        name: &str,
+        build: F,
+        overdue: O,
+    ) -> Option<Result<(), BuildError>>
+    where
+        F: FnOnce() -> Result<(), BuildError> + Send + 'static,
+        O: FnOnce(BuildError),
+    {
+        let _slot = match self.inner.slots.acquire().await {
+            Ok(slot) => slot,
+            Err(e) => return Some(Err(BuildError::new(format!("build queue closed: {:?}", e)))),
+        };
+
+        let mut task = tokio::task::spawn_blocking(build);
+        let joined = |result: Result<Result<(), BuildError>, tokio::task::JoinError>| {
+            result.unwrap_or_else(|e| Err(BuildError::new(format!("build crashed: {:?}", e))))
+        };
+        match tokio::time::timeout(self.inner.limits.timeout, &mut task).await {
+            Ok(result) => Some(joined(result)),
+            Err(_) => {
+                // The renderer should have stopped it well before now, so it
+                // may not stop at all
+                overdue(BuildError::new(format!(
+                    "it took longer than {} seconds",
+                    self.inner.limits.timeout.as_secs()
+                )));
+                let late = joined(task.await);
+                eprintln!("overdue build of {} finally finished: {:?}", name, late);
+                None
+            }
+        }
+    }
+
+    // Tells everyone how the build went. If it's done running, it's also
+    // no longer marked as running.
+    fn finish(&self, subdomain: &str, job: &Job, result: Result<(), BuildError>, stopped: bool) {
+        if let Err(e) = &result {
+            eprintln!("build of {} failed: {}\n{}", subdomain, e, e.output);
+        }
+        let outcome = Outcome {
+            subdomain: subdomain.to_string(),
+            user_id: job.user_id,
+            finished_at: Utc::now(),
+            result,
+            quiet: job.quiet,
+        };
+        {
+            let mut state = self.inner.state.lock().unwrap();
+            if stopped {
+                state.running.remove(subdomain);
+                if let Err(e) = self.persist(&state, subdomain) {
+                    eprintln!("{}", e);
+                }
+            }
+            for waiter in state.running_waiters.remove(subdomain).unwrap_or_default() {
+                // They may have stopped waiting
+                let _ = waiter.send(outcome.clone());
+            }
+            state.last.insert(subdomain.to_string(), outcome.clone());
+        }
+        (self.inner.report)(outcome);
+    }
+
+    // Marks the blog as no longer building. Returns whether its worker is
+    // done, because there's nothing else queued for it.
+    fn stop_running(&self, subdomain: &str) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.running.remove(subdomain).is_some() {
+            if let Err(e) = self.persist(&state, subdomain) {
+                eprintln!("{}", e);
+            }
+        }
+        let done = !state.queued.contains_key(subdomain);
+        if done {
+            state.active.remove(subdomain);
+        }
+        done
+    }
+
+    // Stores what the blog still needs built: whatever's running (in case we
+    // stop before it finishes) plus whatever's waiting.
+    fn persist(&self, state: &State, subdomain: &str) -> Result<(), String> {
+        let err = |e: &dyn std::fmt::Debug| format!("failed to save queued build: {:?}", e);
+        let job = match (state.running.get(subdomain), state.queued.get(subdomain)) {
+            (Some(running), Some(queued)) => Some(running.clone().merge(queued.clone())),
+            (Some(job), None) | (None, Some(job)) => Some(job.clone()),
+            (None, None) => None,
+        };
+
+        let txn = self.inner.db.begin_write().map_err(|e| err(&e))?;
+        {
+            let mut tbl = txn.open_table(QUEUED_BUILDS_TABLE).map_err(|e| err(&e))?;
+            match job {
+                Some(job) => {
+                    let json = serde_json::to_string(&job).map_err(|e| err(&e))?;
+                    tbl.insert(subdomain, json.as_str()).map_err(|e| err(&e))?;
+                }
+                None => {
+                    tbl.remove(subdomain).map_err(|e| err(&e))?;
+                }
+            }
+        }
+        txn.commit().map_err(|e| err(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::sync::mpsc as std_mpsc;
+    use tokio::sync::mpsc;
+
+    struct TestQueue {
+        _tmp: tempfile::TempDir,
+        db: Arc<Database>,
+        queue: BuildQueue,
+        // Every build that ran, in order
+        builds: Arc<Mutex<Vec<(String, Changes)>>>,
+        outcomes: mpsc::UnboundedReceiver<Outcome>,
+        // Each build says when it's started, and then waits to be let go,
+        // if the queue is gated
+        started: mpsc::UnboundedReceiver<String>,
+        release: std_mpsc::Sender<()>,
+    }
+
+    impl TestQueue {
+        // Waits for the next build to start, and returns its blog.
+        async fn started(&mut self) -> String {
+            self.started.recv().await.unwrap()
+        }
+
+        // Lets one build that's waiting finish.
+        fn release(&self) {
+            self.release.send(()).unwrap();
+        }
+    }
+
+    // A queue whose builds fail for the "broken" blog. If it's gated, each
+    // build waits for a `release()` before it finishes.
+    fn test_queue(limits: Limits, gated: bool) -> TestQueue {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Arc::new(Database::create(tmp.path().join("test.redb")).unwrap());
+        let txn = db.begin_write().unwrap();
+        txn.open_table(QUEUED_BUILDS_TABLE).unwrap();
+        txn.commit().unwrap();
+
+        let builds = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&builds);
+        let (started_tx, started) = mpsc::unbounded_channel();
+        let (release, released) = std_mpsc::channel();
+        let released = Mutex::new(released);
+        let build: BuildFn = Arc::new(move |subdomain, changes| {
+            recorded
+                .lock()
+                .unwrap()
+                .push((subdomain.to_string(), changes));
+            if gated {
+                started_tx.send(subdomain.to_string()).unwrap();
+                released.lock().unwrap().recv().unwrap();
+            }
+            if subdomain == "broken" {
+                return Err(BuildError::new("error running command"));
+            }
+            Ok(())
+        });
+        let (tx, outcomes) = mpsc::unbounded_channel();
+        let report: Report = Arc::new(move |outcome| {
+            tx.send(outcome).unwrap();
+        });
+        TestQueue {
+            _tmp: tmp,
+            db: Arc::clone(&db),
+            queue: BuildQueue::new(db, build, report, limits),
+            builds,
+            outcomes,
+            started,
+            release,
+        }
+    }
+
+    fn limits(debounce_ms: u64) -> Limits {
+        Limits {
+            parallel: 2,
+            timeout: Duration::from_secs(60),
+            debounce: Duration::from_millis(debounce_ms),
+        }
+    }
+
+    fn persisted(db: &Database) -> Vec<String> {
+        let txn = db.begin_read().unwrap();
+        let tbl = txn.open_table(QUEUED_BUILDS_TABLE).unwrap();
+        tbl.iter()
+            .unwrap()
+            .map(|e| e.unwrap().0.value().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rapid_changes_coalesce() {
+        let mut t = test_queue(limits(100), false);
+        t.queue.enqueue("myblog", 1, Changes::post(10)).unwrap();
+        t.queue.enqueue("myblog", 1, Changes::post(11)).unwrap();
+        t.queue.enqueue("myblog", 1, Changes::post(10)).unwrap();
+        assert!(t.queue.status("myblog").queued);
+        assert_eq!(persisted(&t.db), vec!["myblog"]);
+
+        let outcome = t.outcomes.recv().await.unwrap();
+        assert_eq!(outcome.subdomain, "myblog");
+        assert_eq!(outcome.user_id, 1);
+        assert!(outcome.result.is_ok());
+        assert_eq!(
+            *t.builds.lock().unwrap(),
+            vec![(
+                "myblog".to_string(),
+                Changes::Posts(BTreeSet::from([10, 11]))
+            )]
+        );
+
+        let status = t.queue.status("myblog");
+        assert!(!status.queued && !status.running);
+        assert!(status.last.unwrap().result.is_ok());
+        assert!(persisted(&t.db).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_changes_during_a_build_get_their_own_build() {
+        let mut t = test_queue(limits(10), true);
+        t.queue.enqueue("myblog", 1, Changes::post(10)).unwrap();
+        t.started().await;
+        assert!(t.queue.status("myblog").running);
+
+        t.queue.enqueue("myblog", 1, Changes::All).unwrap();
+        t.queue.enqueue("myblog", 1, Changes::post(11)).unwrap();
+        t.release();
+        t.outcomes.recv().await.unwrap();
+        t.started().await;
+        t.release();
+        t.outcomes.recv().await.unwrap();
+        assert_eq!(
+            *t.builds.lock().unwrap(),
+            vec![
+                ("myblog".to_string(), Changes::post(10)),
+                ("myblog".to_string(), Changes::All),
+            ]
+        );
+        assert!(persisted(&t.db).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_reported() {
+        let mut t = test_queue(limits(10), false);
+        t.queue.enqueue("broken", 2, Changes::All).unwrap();
+        let outcome = t.outcomes.recv().await.unwrap();
+        assert_eq!(outcome.user_id, 2);
+        assert_eq!(outcome.result.unwrap_err().reason, "error running command");
+        assert!(t.queue.status("broken").last.unwrap().result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_overdue_builds_keep_their_slot() {
+        let mut t = test_queue(
+            Limits {
+                parallel: 1,
+                timeout: Duration::from_millis(10),
+                ..limits(10)
+            },
+            true,
+        );
+        t.queue.enqueue("a", 1, Changes::All).unwrap();
+        t.queue.enqueue("b", 2, Changes::All).unwrap();
+        assert_eq!(t.started().await, "a");
+
+        // The author hears about it once it's overdue, but it's still
+        // running, and nothing else can start until it stops
+        let outcome = t.outcomes.recv().await.unwrap();
+        assert_eq!(outcome.subdomain, "a");
+        assert!(outcome.result.unwrap_err().reason.contains("longer than"));
+        assert!(t.queue.status("a").running);
+        assert_eq!(persisted(&t.db), vec!["a", "b"]);
+        assert_eq!(t.builds.lock().unwrap().len(), 1);
+
+        t.release();
+        assert_eq!(t.started().await, "b");
+        assert!(!t.queue.status("a").running);
+        t.release();
+        assert_eq!(t.outcomes.recv().await.unwrap().subdomain, "b");
+    }
+
+    #[tokio::test]
+    async fn test_parallelism_is_bounded() {
+        let mut t = test_queue(
+            Limits {
+                parallel: 1,
+                ..limits(10)
+            },
+            true,
+        );
+        t.queue.enqueue("a", 1, Changes::All).unwrap();
+        t.queue.enqueue("b", 2, Changes::All).unwrap();
+        let first = t.started().await;
+        // Both get picked up, but only one has a slot to build in
+        while !(t.queue.status("a").running && t.queue.status("b").running) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(t.builds.lock().unwrap().len(), 1);
+        t.release();
+        assert_eq!(t.outcomes.recv().await.unwrap().subdomain, first);
+        assert_ne!(t.started().await, first);
+        t.release();
+        t.outcomes.recv().await.unwrap();
+        assert_eq!(t.builds.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_waits_for_its_build() {
+        let mut t = test_queue(limits(10), true);
+        t.queue.enqueue("myblog", 1, Changes::post(10)).unwrap();
+        t.started().await;
+
+        // Waits for the build after the one that's running
+        let queue = t.queue.clone();
+        let rebuild = tokio::spawn(async move { queue.rebuild("myblog", 1, Changes::All).await });
+        t.release();
+        assert!(!t.outcomes.recv().await.unwrap().quiet);
+        t.started().await;
+        t.release();
+        let outcome = rebuild.await.unwrap().unwrap();
+        assert!(outcome.quiet);
+        assert!(outcome.result.is_ok());
+        assert_eq!(
+            *t.builds.lock().unwrap(),
+            vec![
+                ("myblog".to_string(), Changes::post(10)),
+                ("myblog".to_string(), Changes::All),
+            ]
+        );
+        assert!(t.outcomes.recv().await.unwrap().quiet);
+
+        let queue = t.queue.clone();
+        let rebuild = tokio::spawn(async move { queue.rebuild("broken", 2, Changes::All).await });
+        t.started().await;
+        t.release();
+        assert!(rebuild.await.unwrap().unwrap().result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_changes_from_the_author_arent_quiet() {
+        let mut t = test_queue(limits(100), false);
+        // Polling it once queues the build
+        let mut rebuild = Box::pin(t.queue.rebuild("myblog", 1, Changes::All));
+        assert!(futures::poll!(&mut rebuild).is_pending());
+        t.queue.enqueue("myblog", 1, Changes::post(10)).unwrap();
+
+        let outcome = rebuild.await.unwrap();
+        assert!(!outcome.quiet);
+        assert!(!t.outcomes.recv().await.unwrap().quiet);
+        assert_eq!(t.builds.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_restart() {
+        let mut t = test_queue(limits(10), false);
+        let job = Job {
+            user_id: 3,
+            changes: Changes::post(7),
+            quiet: false,
+        };
+        let txn = t.db.begin_write().unwrap();
+        txn.open_table(QUEUED_BUILDS_TABLE)
+            .unwrap()
+            .insert("myblog", serde_json::to_string(&job).unwrap().as_str())
+            .unwrap();
+        txn.commit().unwrap();
+
+        assert_eq!(t.queue.resume().unwrap(), 1);
+        let outcome = t.outcomes.recv().await.unwrap();
+        assert_eq!(outcome.user_id, 3);
+        assert_eq!(
+            *t.builds.lock().unwrap(),
+            vec![("myblog".to_string(), Changes::post(7))]
+        );
+        assert!(persisted(&t.db).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_content_locks_are_per_blog() {
+        let t = test_queue(limits(10), false);
+        let held = t.queue.lock_content("a").await;
+        // Another blog isn't held up
+        drop(t.queue.lock_content("b").await);
+
+        let queue = t.queue.clone();
+        let mut waiting = tokio::spawn(async move { drop(queue.lock_content("a").await) });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(futures::poll!(&mut waiting).is_pending());
+        drop(held);
+        waiting.await.unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use crate::paths::{self, Root};
+use crate::render::{self, Changes};
+
+// Every build goes into a fresh, numbered directory under
+// STATIC_ROOT/../builds/{subdomain}, and only replaces the live site once it
+// has finished. STATIC_ROOT/{subdomain} is a symlink to whichever version is
+// live, and is swapped with a rename, so readers see either the old site or
+// the new one and never a half-built one. The last few versions are kept so
+// that we can roll back to them.
+
+// How many versions to keep per blog, from KEEP_BUILDS (5 by default). The
+// live one is always kept on top of these.
+pub fn keep_builds() -> usize {
+    env::var("KEEP_BUILDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(5)
+}
+
+pub struct Deployer {
+    // STATIC_ROOT/{subdomain}, what gets served
+    live: PathBuf,
+    versions: Root,
+    keep: usize,
+}
+
+impl Deployer {
+    pub fn new(
+        static_root: &Root,
+        builds_root: &Root,
+        subdomain: &str,
+        keep: usize,
+    ) -> Result<Deployer, String> {
+        Ok(Deployer {
+            live: static_root.entry(subdomain)?,
+            versions: builds_root.subroot(subdomain)?,
+            keep,
+        })
+    }
+
+    pub fn from_env(subdomain: &str) -> Result<Deployer, String> {
+        let static_root = Root::from_env("STATIC_ROOT")?;
+        let parent = static_root
+            .path()
+            .parent()
+            .ok_or("STATIC_ROOT has no parent directory")?;
+        let builds_root = Root::create(parent)?.subroot("builds")?;
+        Deployer::new(&static_root, &builds_root, subdomain, keep_builds())
+    }
+
+    // Builds into a new version with `build`, and makes it live if that
+    // worked. If it didn't, the live site is left alone and the new version
+    // is thrown away.
+    pub fn deploy<F>(&self, changes: Changes, build: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnOnce(&Path, Changes) -> Result<(), Box<dyn Error>>,
+    {
+        self.adopt_live_dir()?;
+
+        let versions = self.versions()?;
+        let current = self.current();
+        let next = versions.last().map_or(1, |v| v + 1);
+        let dir = self.versions.child(&next.to_string())?;
+        fs::create_dir(&dir)?;
+
+        // Only building what changed needs everything else from the last
+        // build. That's only right if the last build is what's live: after a
+        // rollback, the live site is behind the content.
+        let changes = match (changes, current) {
+            (Changes::All, _) => Changes::All,
+            (changes, Some(current)) if Some(&current) == versions.last() => {
+                let from = self.versions.child(&current.to_string())?;
+                render::copy_dir(&from, &Root::new(&dir)?)?;
+                changes
+            }
+            _ => Changes::All,
+        };
+
+        if let Err(e) = build(&dir, changes) {
+            fs::remove_dir_all(&dir).ok();
+            return Err(e);
+        }
+        self.activate(next)?;
+        self.prune()?;
+        Ok(())
+    }
+
+    // Makes the version before the live one live again. Returns its number.
+    pub fn rollback(&self) -> Result<u64, String> {
+        let current = self
+            .current()
+            .ok_or("There's no build to roll back from yet")?;
+        let previous = self
+            .versions()?
+            .into_iter()
+            .rev()
+            .find(|v| *v < current)
+            .ok_or("There's no earlier build to roll back to")?;
+        self.activate(previous)?;
+        Ok(previous)
+    }
+
+    // The version that's live now, if any.
+    pub fn current(&self) -> Option<u64> {
+        let target = fs::read_link(&self.live).ok()?;
+        if target.parent()? != self.versions.path() {
+            return None;
+        }
+        target.file_name()?.to_str()?.parse().ok()
+    }
+
+    // Every version we have, oldest first.
+    pub fn versions(&self) -> Result<Vec<u64>, String> {
+        let entries = fs::read_dir(self.versions.path())
+            .map_err(|e| format!("failed to list {:?}: {:?}", self.versions.path(), e))?;
+        let mut versions: Vec<u64> = entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    // Points the live symlink at a version, by renaming a new symlink over
+    // it.
+    fn activate(&self, version: u64) -> Result<(), String> {
+        let target = self.versions.child(&version.to_string())?;
+        let tmp = self.versions.child("live.tmp")?;
+        fs::remove_file(&tmp).ok();
+        std::os::unix::fs::symlink(&target, &tmp)
+            .map_err(|e| format!("failed to link {:?}: {:?}", tmp, e))?;
+        fs::rename(&tmp, &self.live)
+            .map_err(|e| format!("failed to make {:?} live: {:?}", target, e))
+    }
+
+    // Blogs built before versioning have a real directory where the symlink
+    // should be. It becomes their first version.
+    fn adopt_live_dir(&self) -> Result<(), String> {
+        match fs::symlink_metadata(&self.live) {
+            Ok(meta) if meta.is_dir() => {
+                let next = self.versions()?.last().map_or(1, |v| v + 1);
+                let dir = self.versions.child(&next.to_string())?;
+                fs::rename(&self.live, &dir)
+                    .map_err(|e| format!("failed to move {:?}: {:?}", self.live, e))?;
+                self.activate(next)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn prune(&self) -> Result<(), String> {
+        let current = self.current();
+        let versions = self.versions()?;
+        let old = versions.len().saturating_sub(self.keep);
+        for version in &versions[..old] {
+            if Some(*version) == current {
+                continue;
+            }
+            let dir = self.versions.child(&version.to_string())?;
+            fs::remove_dir_all(&dir).map_err(|e| format!("failed to remove {:?}: {:?}", dir, e))?;
+        }
+        Ok(())
+    }
+}
+
+// Builds the blog and deploys it, with the renderer from the environment.
+pub fn build(subdomain: &str, changes: Changes) -> Result<(), Box<dyn Error>> {
+    let blog = paths::blog(subdomain)?;
+    Deployer::from_env(subdomain)?
+        .deploy(changes, |dir, changes| render::build(&blog, dir, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestDeploys {
+        tmp: tempfile::TempDir,
+        deployer: Deployer,
+    }
+
+    fn test_deploys(keep: usize) -> TestDeploys {
+        let tmp = tempfile::tempdir().unwrap();
+        let static_root = Root::create(tmp.path().join("static")).unwrap();
+        let builds_root = Root::create(tmp.path().join("builds")).unwrap();
+        let deployer = Deployer::new(&static_root, &builds_root, "myblog", keep).unwrap();
+        TestDeploys { tmp, deployer }
+    }
+
+    impl TestDeploys {
+        fn live(&self, file: &str) -> String {
+            fs::read_to_string(self.tmp.path().join("static/myblog").join(file)).unwrap()
+        }
+    }
+
+    // A build that writes `content` to index.html, and records what it was
+    // asked to build in changes.txt.
+    fn writes(content: &str) -> impl FnOnce(&Path, Changes) -> Result<(), Box<dyn Error>> + '_ {
+        move |dir, changes| {
+            fs::write(dir.join("index.html"), content)?;
+            fs::write(dir.join("changes.txt"), format!("{:?}", changes))?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_deploy_swaps_live_version() {
+        let t = test_deploys(5);
+        t.deployer.deploy(Changes::All, writes("one")).unwrap();
+        assert_eq!(t.live("index.html"), "one");
+        assert_eq!(t.deployer.current(), Some(1));
+
+        t.deployer.deploy(Changes::All, writes("two")).unwrap();
+        assert_eq!(t.live("index.html"), "two");
+        assert_eq!(t.deployer.versions().unwrap(), vec![1, 2]);
+        assert!(fs::symlink_metadata(t.tmp.path().join("static/myblog"))
+            .unwrap()
+            .is_symlink());
+    }
+
+    #[test]
+    fn test_failed_build_leaves_live_site_alone() {
+        let t = test_deploys(5);
+        t.deployer.deploy(Changes::All, writes("good")).unwrap();
+        let err = t
+            .deployer
+            .deploy(Changes::All, |dir, _| {
+                fs::write(dir.join("index.html"), "half")?;
+                Err("error running command".into())
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "error running command");
+        assert_eq!(t.live("index.html"), "good");
+        assert_eq!(t.deployer.versions().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_incremental_build_starts_from_live_version() {
+        let t = test_deploys(5);
+        t.deployer
+            .deploy(Changes::All, |dir, _| {
+                fs::write(dir.join("other.html"), "untouched")?;
+                Ok(())
+            })
+            .unwrap();
+        t.deployer.deploy(Changes::post(1), writes("new")).unwrap();
+        assert_eq!(t.live("other.html"), "untouched");
+        assert_eq!(t.live("changes.txt"), format!("{:?}", Changes::post(1)));
+    }
+
+    #[test]
+    fn test_rollback() {
+        let t = test_deploys(5);
+        assert!(t.deployer.rollback().is_err());
+        t.deployer.deploy(Changes::All, writes("one")).unwrap();
+        assert!(t.deployer.rollback().is_err());
+        t.deployer.deploy(Changes::All, writes("two")).unwrap();
+        t.deployer.deploy(Changes::All, writes("three")).unwrap();
+
+        assert_eq!(t.deployer.rollback().unwrap(), 2);
+        assert_eq!(t.live("index.html"), "two");
+        assert_eq!(t.deployer.rollback().unwrap(), 1);
+        assert_eq!(t.live("index.html"), "one");
+
+        // The live site is behind the content now, so even a single post
+        // needs everything rebuilt
+        t.deployer.deploy(Changes::post(1), writes("four")).unwrap();
+        assert_eq!(t.live("index.html"), "four");
+        assert_eq!(t.live("changes.txt"), format!("{:?}", Changes::All));
+        assert_eq!(t.deployer.current(), Some(4));
+    }
+
+    #[test]
+    fn test_prune_keeps_recent_versions() {
+        let t = test_deploys(2);
+        for content in ["one", "two", "three"] {
+            t.deployer.deploy(Changes::All, writes(content)).unwrap();
+        }
+        assert_eq!(t.deployer.versions().unwrap(), vec![2, 3]);
+
+        t.deployer.rollback().unwrap();
+        t.deployer.rollback().unwrap_err();
+        t.deployer.deploy(Changes::All, writes("four")).unwrap();
+        assert_eq!(t.deployer.versions().unwrap(), vec![3, 4]);
+    }
+
+    #[test]
+    fn test_adopts_unversioned_live_dir() {
+        let t = test_deploys(5);
+        let live = t.tmp.path().join("static/myblog");
+        fs::create_dir(&live).unwrap();
+        fs::write(live.join("index.html"), "old").unwrap();
+
+        t.deployer.deploy(Changes::All, writes("new")).unwrap();
+        assert_eq!(t.deployer.versions().unwrap(), vec![1, 2]);
+        assert_eq!(t.deployer.rollback().unwrap(), 1);
+        assert_eq!(t.live("index.html"), "old");
+    }
+}
@@ -0,0 +1,379 @@
+use rand::{distributions::Alphanumeric, Rng};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::paths::{self, Root};
+
+// Every blog gets {subdomain}.hypertxt.io, and can add a custom domain on top.
+pub const BASE_DOMAIN: &str = "hypertxt.io";
+
+// Where we serve ownership tokens for domains that are being added, each only
+// on the domain it's for. If a request for one on the new domain gets back
+// what we wrote, then the domain points at us, which only its owner could
+// have arranged.
+pub const CHALLENGE_PATH: &str = "/.well-known/hypertxt-challenge/";
+
+pub fn default_host(subdomain: &str) -> String {
+    format!("{}.{}", subdomain, BASE_DOMAIN)
+}
+
+// Normalizes a domain someone wants to use (e.g. "Example.com."), and checks
+// that it's a plain hostname that isn't one of ours.
+pub fn check_hostname(domain: &str) -> Result<String, String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let invalid = || format!("{:?} doesn't look like a domain name", domain);
+
+    if domain.len() > 253 {
+        return Err(invalid());
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    if labels.len() < 2 {
+        return Err(invalid());
+    }
+    for label in &labels {
+        let valid = !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if !valid {
+            return Err(invalid());
+        }
+    }
+    // Top-level domains are never all numbers, so this rules out IP addresses
+    if labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    if domain == BASE_DOMAIN || domain.ends_with(&format!(".{}", BASE_DOMAIN)) {
+        return Err(format!(
+            "{} is already yours, you don't need to add it",
+            domain
+        ));
+    }
+    Ok(domain)
+}
+
+pub fn new_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+// Tokens aren't part of any blog, so they live next to the uploads, in a dir
+// for each domain: STATIC_ROOT/../domain_challenges/{domain}/{token}.
+pub fn challenges_root() -> Result<Root, String> {
+    let static_root = Root::from_env("STATIC_ROOT")?;
+    let parent = static_root
+        .path()
+        .parent()
+        .ok_or("STATIC_ROOT has no parent directory")?;
+    Root::create(parent)?.subroot("domain_challenges")
+}
+
+// Replaces any token the domain already had, so only the latest one works.
+pub fn write_challenge(challenges: &Root, domain: &str, token: &str) -> Result<(), String> {
+    remove_challenge(challenges, domain)?;
+    let path = challenges.subroot(domain)?.child(token)?;
+    fs::write(&path, token).map_err(|e| format!("failed to write {:?}: {:?}", path, e))
+}
+
+pub fn remove_challenge(challenges: &Root, domain: &str) -> Result<(), String> {
+    let path = challenges.child(domain)?;
+    if path.exists() {
+        fs::remove_dir_all(&path).map_err(|e| format!("failed to remove {:?}: {:?}", path, e))?;
+    }
+    Ok(())
+}
+
+// Fetches the token back from the domain, which only works once it's pointed
+// at us. It's someone else's domain, so we only ever talk to a public address
+// it resolves to, and don't follow redirects anywhere else.
+pub async fn verify(domain: &str, token: &str) -> Result<(), String> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, 80))
+        .await
+        .map_err(|e| format!("couldn't look up {}: {}", domain, e))?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(format!(
+            "{} points at {}, which isn't a public address",
+            domain,
+            addr.ip()
+        ));
+    }
+    let addr = addrs
+        .first()
+        .ok_or_else(|| format!("{} doesn't have an address", domain))?;
+    fetch_token(domain, *addr, token).await
+}
+
+// Asks `addr` for the token as `domain`, without looking the domain up again
+// in case it's changed since we checked the address.
+async fn fetch_token(domain: &str, addr: SocketAddr, token: &str) -> Result<(), String> {
+    let url = format!("http://{}{}{}", domain, CHALLENGE_PATH, token);
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(domain, addr)
+        .build()
+        .map_err(|e| format!("failed to build HTTP client: {:?}", e))?;
+    let resp = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("couldn't reach {}: {}", url, e))?;
+    if !resp.status().is_success() {
+        return Err(format!("{} returned {}", url, resp.status()));
+    }
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| format!("couldn't read {}: {}", url, e))?;
+    if body.trim() != token {
+        return Err(format!("{} didn't return the token we expected", url));
+    }
+    Ok(())
+}
+
+// Whether an address is on the internet at large, rather than e.g. loopback
+// or a private network of ours.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network", carrier-grade NAT and reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local, link-local and documentation
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0xdb8))
+}
+
+// A Caddy site block serving a blog at its custom domain. Uploads are shared
+// by every blog, so they come from their own root.
+pub fn caddy_config(domain: &str, out_dir: &Path, uploads: &Root) -> String {
+    let uploads_parent = uploads.path().parent().unwrap_or(uploads.path());
+    format!(
+        "{domain} {{\n\
+         \thandle /user_uploads/* {{\n\
+         \t\troot * {uploads}\n\
+         \t\tfile_server\n\
+         \t}}\n\
+         \thandle {{\n\
+         \t\troot * {out_dir}\n\
+         \t\tfile_server\n\
+         \t}}\n\
+         \thandle_errors {{\n\
+         \t\trewrite * /404.html\n\
+         \t\troot * {out_dir}\n\
+         \t\tfile_server\n\
+         \t}}\n\
+         }}\n",
+        domain = domain,
+        uploads = uploads_parent.display(),
+        out_dir = out_dir.display(),
+    )
+}
+
+// Each custom domain gets its own file under PROXY_CONF_ROOT, which the main
+// Caddyfile pulls in with `import`.
+pub fn write_proxy_config(domain: &str, subdomain: &str) -> Result<(), String> {
+    let conf = Root::from_env("PROXY_CONF_ROOT")?;
+    let path = conf.child(&format!("{}.caddy", domain))?;
+    let snippet = caddy_config(domain, &paths::out_dir(subdomain)?, &paths::uploads_root()?);
+    fs::write(&path, snippet).map_err(|e| format!("failed to write {:?}: {:?}", path, e))
+}
+
+pub fn remove_proxy_config(domain: &str) -> Result<(), String> {
+    let conf = Root::from_env("PROXY_CONF_ROOT")?;
+    let path = conf.child(&format!("{}.caddy", domain))?;
+    if path.exists() {
+        fs::remove_file(&path).map_err(|e| format!("failed to remove {:?}: {:?}", path, e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Stands in for a web server the domain points at, answering every
+    // request with the given status, extra headers and body. Returns its
+    // address.
+    async fn stand_in_server(status: &'static str, headers: String, body: String) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((mut socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await;
+                let resp = format!(
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(resp.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_check_hostname() {
+        assert_eq!(check_hostname("Example.COM.").unwrap(), "example.com");
+        assert_eq!(
+            check_hostname("blog.my-site.co.uk").unwrap(),
+            "blog.my-site.co.uk"
+        );
+        for bad in [
+            "localhost",
+            "example..com",
+            "-example.com",
+            "exa_mple.com",
+            "example.com/path",
+            "example.com:8080",
+            "../etc/passwd",
+            "127.0.0.1",
+            "ünïcödé.com",
+            "hypertxt.io",
+            "someone-else.hypertxt.io",
+        ] {
+            assert!(check_hostname(bad).is_err(), "{:?} should be rejected", bad);
+        }
+        assert!(check_hostname(&format!("{}.com", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn test_tokens_are_unique_names() {
+        let (a, b) = (new_token(), new_token());
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 32);
+        assert!(a.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_from_stand_in_server() {
+        let token = new_token();
+        let good = stand_in_server("200 OK", String::new(), format!("{}\n", token)).await;
+        fetch_token("example.com", good, &token).await.unwrap();
+
+        let addr = stand_in_server("200 OK", String::new(), "something else".to_string()).await;
+        assert!(fetch_token("example.com", addr, &token).await.is_err());
+
+        let addr = stand_in_server("404 Not Found", String::new(), token.clone()).await;
+        assert!(fetch_token("example.com", addr, &token).await.is_err());
+
+        // Even though it redirects somewhere that would answer, we don't go
+        let location = format!("Location: http://{}{}{}\r\n", good, CHALLENGE_PATH, token);
+        let addr = stand_in_server("302 Found", location, String::new()).await;
+        assert!(fetch_token("example.com", addr, &token).await.is_err());
+
+        // Nothing listening at all
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        assert!(fetch_token("example.com", addr, &token).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_refuses_private_addresses() {
+        let err = verify("localhost", &new_token()).await.unwrap_err();
+        assert!(err.contains("isn't a public address"), "{}", err);
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_challenge_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let challenges = Root::new(tmp.path()).unwrap();
+        let token = new_token();
+        write_challenge(&challenges, "example.com", &token).unwrap();
+        let path = tmp.path().join("example.com").join(&token);
+        assert_eq!(fs::read_to_string(&path).unwrap(), token);
+
+        // A new token replaces the old one
+        let new = new_token();
+        write_challenge(&challenges, "example.com", &new).unwrap();
+        assert!(!path.exists());
+        assert!(tmp.path().join("example.com").join(&new).exists());
+
+        remove_challenge(&challenges, "example.com").unwrap();
+        assert!(!tmp.path().join("example.com").exists());
+        assert!(write_challenge(&challenges, "example.com", "../escape").is_err());
+        assert!(write_challenge(&challenges, "..", &token).is_err());
+    }
+
+    #[test]
+    fn test_caddy_config() {
+        let tmp = tempfile::tempdir().unwrap();
+        let uploads = Root::create(tmp.path().join("user_uploads")).unwrap();
+        let config = caddy_config("example.com", Path::new("/srv/static/myblog"), &uploads);
+        assert!(config.starts_with("example.com {\n"));
+        assert!(config.contains("\t\troot * /srv/static/myblog\n"));
+        assert!(config.contains(&format!(
+            "\t\troot * {}\n",
+            uploads.path().parent().unwrap().display()
+        )));
+        assert_eq!(config.matches('{').count(), config.matches('}').count());
+    }
+}
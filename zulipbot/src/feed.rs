@@ -0,0 +1,354 @@
+use chrono::{DateTime, NaiveDate};
+use pulldown_cmark::{html, CowStr, Event, Parser, Tag};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::bloggen;
+use crate::paths::Root;
+use crate::posts::PostRecord;
+use crate::zola;
+
+// Zola generates the Atom and RSS feeds itself, but it doesn't know how to do
+// JSON Feed (https://www.jsonfeed.org/version/1.1/), so we build that one from
+// the posts' records in the DB and drop it into the blog's static dir, which
+// Zola copies into the built site as-is.
+
+pub const JSON_FEED_FILENAME: &str = "feed.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonFeed {
+    pub version: String,
+    pub title: String,
+    pub home_page_url: String,
+    pub feed_url: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<JsonFeedAuthor>,
+    pub items: Vec<JsonFeedItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonFeedAuthor {
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JsonFeedItem {
+    pub id: String,
+    pub url: String,
+    pub title: String,
+    pub content_html: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_published: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+pub fn write_json_feed(
+    blog: &Root,
+    posts: &[(u64, PostRecord)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let site = zola::read_site_info(blog)?;
+
+    let feed = json_feed(
+        &site.base_url,
+        &site.extra.logo_text,
+        &site.extra.author,
+        posts,
+    );
+
+    let static_dir = blog.join("static")?;
+    fs::create_dir_all(&static_dir)?;
+    fs::write(
+        Root::new(static_dir)?.join(JSON_FEED_FILENAME)?,
+        serde_json::to_string_pretty(&feed)?,
+    )?;
+    Ok(())
+}
+
+pub fn json_feed(
+    base_url: &str,
+    blog_name: &str,
+    author: &str,
+    posts: &[(u64, PostRecord)],
+) -> JsonFeed {
+    let base_url = base_url.trim_end_matches('/');
+
+    let mut items: Vec<(Option<String>, JsonFeedItem)> = posts
+        .iter()
+        .filter_map(|(post_id, record)| {
+            // Standalone pages aren't posts, so they don't go in the feed
+            if record.draft || record.page.is_some() {
+                return None;
+            }
+            let msg = record.parsed();
+            let date_published = msg
+                .metadata
+                .date
+                .as_deref()
+                .and_then(rfc3339)
+                .or_else(|| record.timestamp.map(|ts| bloggen::todays_date(ts, true)));
+            let slug = record
+                .slug()
+                .map_or_else(|| post_id.to_string(), |s| s.slug);
+            let item = JsonFeedItem {
+                id: post_id.to_string(),
+                url: format!("{}/{}/", base_url, slug),
+                // The same title the post itself gets
+                title: record.title.clone(),
+                content_html: markdown_to_html(&msg.body, base_url),
+                summary: msg.metadata.summary.or(msg.metadata.description),
+                image: record
+                    .assets
+                    .first()
+                    .map(|url| format!("{}{}", base_url, url)),
+                date_published: date_published.clone(),
+                date_modified: msg.metadata.updated.as_deref().and_then(rfc3339),
+                tags: record.tags.clone(),
+            };
+            Some((date_published, item))
+        })
+        .collect();
+
+    // Newest first. RFC 3339 timestamps in UTC sort lexically.
+    items.sort_by(|a, b| b.0.cmp(&a.0));
+
+    JsonFeed {
+        version: "https://jsonfeed.org/version/1.1".to_string(),
+        title: blog_name.to_string(),
+        home_page_url: format!("{}/", base_url),
+        feed_url: format!("{}/{}", base_url, JSON_FEED_FILENAME),
+        authors: if author.is_empty() {
+            vec![]
+        } else {
+            vec![JsonFeedAuthor {
+                name: author.to_string(),
+            }]
+        },
+        items: items.into_iter().map(|(_, item)| item).collect(),
+    }
+}
+
+// Dates from metadata can be plain dates, or full RFC 3339 in any timezone,
+// but JSON Feed wants full RFC 3339, and the feed is sorted on them as UTC.
+// None if it's neither.
+pub fn rfc3339(date: &str) -> Option<String> {
+    if let Ok(d) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Some(format!("{}T00:00:00Z", d.format("%Y-%m-%d")));
+    }
+    let d = DateTime::parse_from_rfc3339(date).ok()?;
+    Some(
+        d.to_utc()
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+    )
+}
+
+// Feed readers don't know what site a post came from, so links and images
+// relative to the blog (like uploads) are made absolute.
+fn markdown_to_html(markdown: &str, base_url: &str) -> String {
+    let absolute = |url: CowStr<'_>| -> CowStr<'static> {
+        if url.starts_with('/') && !url.starts_with("//") {
+            format!("{}{}", base_url, url).into()
+        } else {
+            url.into_string().into()
+        }
+    };
+    let events = Parser::new(markdown).map(|event| match event {
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: absolute(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: absolute(dest_url),
+            title,
+            id,
+        }),
+        e => e,
+    });
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    // Checks a feed against the requirements in
+    // https://www.jsonfeed.org/version/1.1/, working on the raw JSON so that
+    // it catches things our own types would paper over.
+    fn validate_json_feed(feed: &Value) {
+        let obj = feed.as_object().expect("feed must be an object");
+        assert_eq!(obj["version"], "https://jsonfeed.org/version/1.1");
+        assert!(obj["title"].is_string(), "title is required");
+        for key in ["home_page_url", "feed_url"] {
+            if let Some(url) = obj.get(key) {
+                assert!(url.as_str().unwrap().starts_with("https://"));
+            }
+        }
+        if let Some(authors) = obj.get("authors") {
+            for author in authors.as_array().unwrap() {
+                assert!(author.get("name").is_some() || author.get("url").is_some());
+            }
+        }
+        let items = obj["items"].as_array().expect("items is required");
+        let mut ids = std::collections::HashSet::new();
+        for item in items {
+            let item = item.as_object().unwrap();
+            let id = item["id"].as_str().expect("id is required and a string");
+            assert!(ids.insert(id.to_string()), "ids must be unique");
+            assert!(
+                item.contains_key("content_html") || item.contains_key("content_text"),
+                "content_html or content_text is required"
+            );
+            for key in ["date_published", "date_modified"] {
+                if let Some(date) = item.get(key) {
+                    DateTime::parse_from_rfc3339(date.as_str().unwrap())
+                        .expect("dates must be RFC 3339");
+                }
+            }
+            for key in ["url", "image"] {
+                if let Some(url) = item.get(key) {
+                    assert!(url.as_str().unwrap().starts_with("https://"));
+                }
+            }
+            if let Some(tags) = item.get("tags") {
+                assert!(tags.as_array().unwrap().iter().all(Value::is_string));
+            }
+        }
+    }
+
+    fn post(
+        post_id: u64,
+        content: &str,
+        timestamp: Option<u64>,
+        slug: Option<&str>,
+    ) -> (u64, PostRecord) {
+        let mut record = PostRecord {
+            timestamp,
+            slugs: slug.into_iter().map(String::from).collect(),
+            ..Default::default()
+        };
+        record.set_content(content);
+        (post_id, record)
+    }
+
+    #[test]
+    fn test_json_feed_is_valid() {
+        let posts = vec![
+            post(
+                1,
+                "TITLE: First\nTAGS: rust, zulip\nSUMMARY: Short\n\nHello ![pic](/user_uploads/1/a/pic.png)",
+                Some(1732114800),
+                Some("first"),
+            ),
+            post(2, "Untitled post with a [link](https://example.com)", Some(1732201200), None),
+            post(3, "TITLE: Dated\nDATE: 2024-01-02\nUPDATED: 2024-01-03\n\nBody", None, None),
+            post(4, "TITLE: Secret\nDRAFT: true\n\nBody", Some(1732201200), Some("secret")),
+            post(5, "PAGE: about\n\nAbout me", Some(1732201200), Some("about")),
+        ];
+        let feed = json_feed("https://myblog.hypertxt.io", "My \"Blog\"", "Me", &posts);
+        let value = serde_json::to_value(&feed).unwrap();
+        validate_json_feed(&value);
+
+        assert_eq!(feed.feed_url, "https://myblog.hypertxt.io/feed.json");
+        let ids: Vec<_> = feed.items.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(ids, vec!["2", "1", "3"]);
+
+        let first = &feed.items[1];
+        assert_eq!(first.url, "https://myblog.hypertxt.io/first/");
+        assert_eq!(first.tags, vec!["rust", "zulip"]);
+        assert_eq!(first.summary.as_deref(), Some("Short"));
+        assert_eq!(
+            first.image.as_deref(),
+            Some("https://myblog.hypertxt.io/user_uploads/1/a/pic.png")
+        );
+        assert!(first
+            .content_html
+            .contains(r#"<img src="https://myblog.hypertxt.io/user_uploads/1/a/pic.png""#));
+
+        let untitled = &feed.items[0];
+        assert_eq!(untitled.url, "https://myblog.hypertxt.io/2/");
+        assert_eq!(untitled.title, "2024-11-21");
+        assert!(untitled
+            .content_html
+            .contains(r#"<a href="https://example.com">"#));
+
+        let dated = &feed.items[2];
+        assert_eq!(
+            dated.date_published.as_deref(),
+            Some("2024-01-02T00:00:00Z")
+        );
+        assert_eq!(dated.date_modified.as_deref(), Some("2024-01-03T00:00:00Z"));
+    }
+
+    #[test]
+    fn test_json_feed_dates_are_utc() {
+        let posts = vec![
+            post(
+                1,
+                "TITLE: Offset\nDATE: 2024-01-02T01:00:00+05:00\n\nBody",
+                None,
+                None,
+            ),
+            post(2, "TITLE: Plain\nDATE: 2024-01-01\n\nBody", None, None),
+            post(
+                3,
+                "TITLE: Garbage\nDATE: last tuesday\nUPDATED: soon\n\nBody",
+                Some(1732114800),
+                None,
+            ),
+        ];
+        let feed = json_feed("https://myblog.hypertxt.io", "Blog", "", &posts);
+        validate_json_feed(&serde_json::to_value(&feed).unwrap());
+
+        // The offset one was really on the 1st, after midnight UTC
+        let dates: Vec<_> = feed
+            .items
+            .iter()
+            .map(|i| (i.id.as_str(), i.date_published.as_deref()))
+            .collect();
+        assert_eq!(
+            dates,
+            vec![
+                ("3", Some("2024-11-20T15:00:00Z")),
+                ("1", Some("2024-01-01T20:00:00Z")),
+                ("2", Some("2024-01-01T00:00:00Z")),
+            ]
+        );
+        assert_eq!(feed.items[0].date_modified, None);
+
+        assert_eq!(
+            rfc3339("2024-01-02T01:00:00.5+05:00").as_deref(),
+            Some("2024-01-01T20:00:00Z")
+        );
+        assert_eq!(rfc3339("last tuesday"), None);
+    }
+
+    #[test]
+    fn test_empty_json_feed_is_valid() {
+        let feed = json_feed("https://myblog.hypertxt.io/", "", "", &[]);
+        validate_json_feed(&serde_json::to_value(&feed).unwrap());
+        assert_eq!(feed.home_page_url, "https://myblog.hypertxt.io/");
+        assert!(feed.authors.is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::bloggen;
+use crate::paths::{self, Root};
+
+// The database, each blog's Zola project (BLOG_ROOT/{subdomain}), its live
+// site (STATIC_ROOT/{subdomain}) and the uploads all blogs share can drift
+// apart, e.g. when we stop between committing a post and writing it out, or
+// someone tidies up by hand. This compares what's on disk with what the
+// database says should be there, and puts right whatever it can without the
+// database. Fixing the rest (and working out what the database says) is up
+// to the caller.
+
+// Files changed more recently than this might be half way through being
+// published, so they're left alone.
+const RECENT: Duration = Duration::from_secs(5 * 60);
+
+pub struct Roots {
+    pub blogs: Root,
+    pub static_root: Root,
+    pub themes: Root,
+    pub uploads: Root,
+}
+
+impl Roots {
+    pub fn from_env() -> Result<Roots, String> {
+        Ok(Roots {
+            blogs: Root::from_env("BLOG_ROOT")?,
+            static_root: Root::from_env("STATIC_ROOT")?,
+            themes: Root::from_env("THEMES_ROOT")?,
+            uploads: paths::uploads_root()?,
+        })
+    }
+}
+
+// What the database says should be on disk.
+#[derive(Debug, Default)]
+pub struct Expected {
+    // Each blog's posts, by subdomain, and whether each one is a page
+    pub blogs: BTreeMap<String, BTreeMap<u64, bool>>,
+    // Every upload that a post (or an old revision of one) or an intro uses
+    pub uploads: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    // The user's subdomain isn't mapped back to them, so it could be given
+    // to someone else
+    UnmappedSubdomain { user_id: u64, subdomain: String },
+    // A blog directory, or live site, that isn't anyone's blog
+    UnknownBlog { subdomain: String },
+    MissingBlog { subdomain: String },
+    MissingSite { subdomain: String },
+    MissingPost { subdomain: String, post_id: u64 },
+    // A content file for a post the blog doesn't have (or that's in the
+    // wrong section), relative to the blog
+    OrphanedContent { subdomain: String, path: PathBuf },
+    // The blog's link to the themes doesn't go anywhere
+    DanglingThemes { subdomain: String },
+    // Relative to the uploads root
+    UnreferencedUpload { path: PathBuf },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnmappedSubdomain { user_id, subdomain } => write!(
+                f,
+                "**{}**: isn't mapped back to user {}",
+                subdomain, user_id
+            ),
+            Problem::UnknownBlog { subdomain } => {
+                write!(f, "**{}**: is on disk, but isn't anyone's blog", subdomain)
+            }
+            Problem::MissingBlog { subdomain } => {
+                write!(f, "**{}**: has no blog directory", subdomain)
+            }
+            Problem::MissingSite { subdomain } => write!(f, "**{}**: has no live site", subdomain),
+            Problem::MissingPost { subdomain, post_id } => {
+                write!(f, "**{}**: post {} isn't on disk", subdomain, post_id)
+            }
+            Problem::OrphanedContent { subdomain, path } => write!(
+                f,
+                "**{}**: {} isn't one of its posts",
+                subdomain,
+                path.display()
+            ),
+            Problem::DanglingThemes { subdomain } => {
+                write!(f, "**{}**: its themes link is broken", subdomain)
+            }
+            Problem::UnreferencedUpload { path } => {
+                write!(f, "user_uploads/{} isn't used by anything", path.display())
+            }
+        }
+    }
+}
+
+// Everything on disk that doesn't match what's expected.
+pub fn check(roots: &Roots, expected: &Expected, now: SystemTime) -> Result<Vec<Problem>, String> {
+    let mut problems = Vec::new();
+
+    for name in dir_names(roots.blogs.path())?
+        .into_iter()
+        .chain(dir_names(roots.static_root.path())?)
+        .collect::<BTreeSet<_>>()
+    {
+        if !expected.blogs.contains_key(&name) {
+            problems.push(Problem::UnknownBlog { subdomain: name });
+        }
+    }
+
+    for (subdomain, posts) in &expected.blogs {
+        let site = roots.static_root.entry(subdomain)?;
+        if fs::metadata(&site).is_err() {
+            problems.push(Problem::MissingSite {
+                subdomain: subdomain.clone(),
+            });
+        }
+
+        let Ok(blog) = Root::new(roots.blogs.child(subdomain)?) else {
+            problems.push(Problem::MissingBlog {
+                subdomain: subdomain.clone(),
+            });
+            continue;
+        };
+        check_blog(roots, &blog, subdomain, posts, now, &mut problems)?;
+    }
+
+    let mut used = HashSet::new();
+    for url in &expected.uploads {
+        if let Ok(path) = paths::upload_path(&roots.uploads, url) {
+            used.insert(path);
+        }
+    }
+    for path in files(roots.uploads.path())? {
+        if !used.contains(&path) && !is_recent(&path, now) {
+            let path = path
+                .strip_prefix(roots.uploads.path())
+                .map_err(|e| format!("{:?}", e))?
+                .to_path_buf();
+            problems.push(Problem::UnreferencedUpload { path });
+        }
+    }
+    Ok(problems)
+}
+
+fn check_blog(
+    roots: &Roots,
+    blog: &Root,
+    subdomain: &str,
+    posts: &BTreeMap<u64, bool>,
+    now: SystemTime,
+    problems: &mut Vec<Problem>,
+) -> Result<(), String> {
+    let themes = blog.entry("themes")?;
+    let is_link = fs::symlink_metadata(&themes).is_ok_and(|m| m.file_type().is_symlink());
+    let links_to_themes = fs::canonicalize(&themes).is_ok_and(|t| t == roots.themes.path());
+    if !links_to_themes && (is_link || !themes.exists()) {
+        problems.push(Problem::DanglingThemes {
+            subdomain: subdomain.to_string(),
+        });
+    }
+
+    let mut wanted = HashSet::new();
+    for (post_id, is_page) in posts {
+        let [post_file, page_file] = bloggen::post_files(blog, *post_id)?;
+        let file = if *is_page { page_file } else { post_file };
+        if !file.exists() {
+            problems.push(Problem::MissingPost {
+                subdomain: subdomain.to_string(),
+                post_id: *post_id,
+            });
+        }
+        wanted.insert(file);
+    }
+
+    // Content files are named after their post's ID; anything else (like
+    // _index.md) isn't a post
+    for dir in bloggen::content_dirs(blog)? {
+        for file in files_in(&dir)? {
+            let is_post = file.extension().is_some_and(|e| e == "md")
+                && file
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|s| s.parse::<u64>().is_ok());
+            if is_post && !wanted.contains(&file) && !is_recent(&file, now) {
+                let path = file
+                    .strip_prefix(blog.path())
+                    .map_err(|e| format!("{:?}", e))?
+                    .to_path_buf();
+                problems.push(Problem::OrphanedContent {
+                    subdomain: subdomain.to_string(),
+                    path,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Puts right the problems that can be fixed from what's on disk alone.
+// Returns whether it was one of those.
+pub fn repair(roots: &Roots, problem: &Problem) -> Result<bool, String> {
+    match problem {
+        Problem::OrphanedContent { subdomain, path } => {
+            let file = Root::new(roots.blogs.child(subdomain)?)?.join(path)?;
+            fs::remove_file(&file).map_err(|e| format!("failed to remove {:?}: {:?}", file, e))?;
+        }
+        Problem::DanglingThemes { subdomain } => {
+            let link = Root::new(roots.blogs.child(subdomain)?)?.entry("themes")?;
+            if fs::symlink_metadata(&link).is_ok() {
+                fs::remove_file(&link)
+                    .map_err(|e| format!("failed to remove {:?}: {:?}", link, e))?;
+            }
+            std::os::unix::fs::symlink(roots.themes.path(), &link)
+                .map_err(|e| format!("failed to link {:?}: {:?}", link, e))?;
+        }
+        Problem::UnreferencedUpload { path } => {
+            let file = roots.uploads.join(path)?;
+            fs::remove_file(&file).map_err(|e| format!("failed to remove {:?}: {:?}", file, e))?;
+            // Along with any directories that leaves empty
+            for dir in file.ancestors().skip(1) {
+                if dir == roots.uploads.path() || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn is_recent(path: &Path, now: SystemTime) -> bool {
+    fs::symlink_metadata(path)
+        .and_then(|m| m.modified())
+        .is_ok_and(|modified| now.duration_since(modified).unwrap_or_default() < RECENT)
+}
+
+// The names of the entries in a directory, other than plain files, e.g.
+// each blog under BLOG_ROOT. Hidden ones are scratch space, like a blog
+// being rewritten (see bloggen::stage_blog), rather than blogs.
+fn dir_names(dir: &Path) -> Result<Vec<String>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("failed to list {:?}: {:?}", dir, e))?;
+    let mut names = Vec::new();
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if !name.starts_with('.') {
+                names.push(name.to_string());
+            }
+        }
+    }
+    Ok(names)
+}
+
+// The plain files directly in a directory, if it exists.
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    Ok(entries
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .map(|e| e.path())
+        .collect())
+}
+
+// Every plain file under a directory.
+fn files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("failed to list {:?}: {:?}", dir, e))?;
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        match entry.file_type() {
+            Ok(t) if t.is_dir() => files.extend(self::files(&entry.path())?),
+            Ok(t) if t.is_file() => files.push(entry.path()),
+            _ => {}
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_roots() -> (tempfile::TempDir, Roots) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = |name: &str| Root::create(tmp.path().join(name)).unwrap();
+        let roots = Roots {
+            blogs: root("blogs"),
+            static_root: root("static"),
+            themes: root("themes"),
+            uploads: root("user_uploads"),
+        };
+        (tmp, roots)
+    }
+
+    fn write(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    // A blog with post 1 and page 2 written out, and a live site.
+    fn add_blog(roots: &Roots, subdomain: &str) {
+        let blog = roots.blogs.path().join(subdomain);
+        write(&blog.join("content/_index.md"));
+        write(&blog.join("content/1.md"));
+        write(&blog.join("content/pages/2.md"));
+        std::os::unix::fs::symlink(roots.themes.path(), blog.join("themes")).unwrap();
+        fs::create_dir(roots.static_root.path().join(subdomain)).unwrap();
+    }
+
+    fn expected() -> Expected {
+        Expected {
+            blogs: BTreeMap::from([("alice".to_string(), BTreeMap::from([(1, false), (2, true)]))]),
+            uploads: vec!["/user_uploads/1/ab/pic.png".to_string()],
+        }
+    }
+
+    // Far enough ahead that nothing counts as recent
+    fn later() -> SystemTime {
+        SystemTime::now() + RECENT * 2
+    }
+
+    #[test]
+    fn test_consistent() {
+        let (_tmp, roots) = test_roots();
+        add_blog(&roots, "alice");
+        write(&roots.uploads.path().join("1/ab/pic.png"));
+        // A blog half way through being rewritten
+        fs::create_dir(roots.blogs.path().join(".tmpAbC123")).unwrap();
+        assert_eq!(check(&roots, &expected(), later()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_finds_and_repairs_problems() {
+        let (_tmp, roots) = test_roots();
+        add_blog(&roots, "alice");
+        add_blog(&roots, "mallory");
+        let alice = roots.blogs.path().join("alice");
+        // Post 1 has turned into a page, and post 3 has been deleted
+        fs::rename(alice.join("content/1.md"), alice.join("content/pages/1.md")).unwrap();
+        write(&alice.join("content/3.md"));
+        fs::remove_file(alice.join("themes")).unwrap();
+        std::os::unix::fs::symlink("/nowhere", alice.join("themes")).unwrap();
+        fs::remove_dir(roots.static_root.path().join("alice")).unwrap();
+        write(&roots.uploads.path().join("1/ab/pic.png"));
+        write(&roots.uploads.path().join("2/cd/old.png"));
+        let mut expected = expected();
+        expected.blogs.insert("bob".to_string(), BTreeMap::new());
+
+        let problems = check(&roots, &expected, later()).unwrap();
+        let subdomain = |s: &str| s.to_string();
+        assert_eq!(
+            problems,
+            vec![
+                Problem::UnknownBlog {
+                    subdomain: subdomain("mallory")
+                },
+                Problem::MissingSite {
+                    subdomain: subdomain("alice")
+                },
+                Problem::DanglingThemes {
+                    subdomain: subdomain("alice")
+                },
+                Problem::MissingPost {
+                    subdomain: subdomain("alice"),
+                    post_id: 1
+                },
+                Problem::OrphanedContent {
+                    subdomain: subdomain("alice"),
+                    path: PathBuf::from("content/3.md")
+                },
+                Problem::OrphanedContent {
+                    subdomain: subdomain("alice"),
+                    path: PathBuf::from("content/pages/1.md")
+                },
+                Problem::MissingSite {
+                    subdomain: subdomain("bob")
+                },
+                Problem::MissingBlog {
+                    subdomain: subdomain("bob")
+                },
+                Problem::UnreferencedUpload {
+                    path: PathBuf::from("2/cd/old.png")
+                },
+            ]
+        );
+        assert_eq!(
+            problems[4].to_string(),
+            "**alice**: content/3.md isn't one of its posts"
+        );
+
+        // Nothing that's just been written counts
+        let now = check(&roots, &expected, SystemTime::now()).unwrap();
+        assert!(!now
+            .iter()
+            .any(|p| matches!(p, Problem::OrphanedContent { .. })));
+
+        let repaired: Vec<bool> = problems
+            .iter()
+            .map(|p| repair(&roots, p).unwrap())
+            .collect();
+        assert_eq!(
+            repaired,
+            vec![false, false, true, false, true, true, false, false, true]
+        );
+        assert!(!roots.uploads.path().join("2").exists());
+        assert!(roots.uploads.path().join("1/ab/pic.png").exists());
+        assert_eq!(
+            fs::canonicalize(alice.join("themes")).unwrap(),
+            roots.themes.path()
+        );
+        let left = check(&roots, &expected, later()).unwrap();
+        assert_eq!(left.len(), 5);
+        assert!(left.iter().all(|p| !repair(&roots, p).unwrap()));
+    }
+}
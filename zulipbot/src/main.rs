@@ -1,9 +1,27 @@
-use redb::{Database, ReadableTable, TableDefinition};
+use builds::BuildQueue;
+use redb::{Database, ReadableTable, TableDefinition, WriteTransaction};
+use render::Changes;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use zulip::{EventType, ListenType, Message, SendMessage};
 
 mod bloggen;
+mod builds;
+mod deploys;
+mod domains;
+mod feed;
+mod fsck;
+mod metadata;
+mod paths;
+mod posts;
+mod render;
+mod sandbox;
+mod schema;
+mod server;
+mod themes;
+mod zola;
 mod zulip;
 
 #[tokio::main]
@@ -18,47 +36,62 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mention_db = Arc::clone(&db);
     let update_db = Arc::clone(&db);
 
-    // Make sure all the tables exist first
-    {
-        let txn = db.begin_write()?;
-        txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
-        txn.open_table(SUBDOMAIN_TO_USER_ID_TABLE)?;
-        txn.open_table(USER_ID_TO_POST_IDS_TABLE)?;
-        txn.open_table(POST_ID_TO_POST_TABLE)?;
+    // Bring the database up to date, and make sure all the tables exist
+    let from = schema::migrate(&db)?;
+    if from < schema::CURRENT_VERSION {
+        println!(
+            "Migrated the database from schema version {} to {}",
+            from,
+            schema::CURRENT_VERSION
+        );
+    }
+
+    // Tell authors how their builds went, by DM
+    let report_db = Arc::clone(&db);
+    let report: builds::Report = Arc::new(move |outcome| {
+        if let Err(e) = record_build(&report_db, &outcome) {
+            eprintln!("failed to record build of {}: {}", outcome.subdomain, e);
+        }
+        // Authors only hear about builds they didn't ask for if they fail
+        if outcome.quiet && outcome.result.is_ok() {
+            return;
+        }
+        let msg = build_report(&report_db, &outcome);
+        tokio::spawn(async move {
+            if let Err(e) = zulip::send_direct_message(&msg, outcome.user_id).await {
+                eprintln!("failed to report build of {}: {}", outcome.subdomain, e);
+            }
+        });
+    });
+    let builds = BuildQueue::from_env(Arc::clone(&db), report);
+    let resumed = builds.resume()?;
+    if resumed > 0 {
+        println!("Resumed {} queued builds", resumed);
     }
+    // Bring blogs made with an older config up to date in the background
+    let stale = stale_configs(&db)?;
+    if !stale.is_empty() {
+        println!("Rewriting {} blogs with out of date configs", stale.len());
+        tokio::spawn(rewrite_stale(Arc::clone(&db), builds.clone(), stale));
+    }
+    let dm_builds = builds.clone();
+    let mention_builds = builds.clone();
+    let update_builds = builds.clone();
 
     let dm_handle = tokio::spawn(async move {
         zulip::call_on_each_message(ListenType::DM, EventType::Message, |msg| {
             let db = dm_db.clone();
+            let builds = dm_builds.clone();
             async move {
-                match msg.content.as_str() {
-                    "regenerate" => {
-                        let response_msg = match refresh_all_posts(&db, &msg).await {
-                            Ok(v) => v,
-                            Err(e) => format!("Uh oh, something went wrong. Error: {:?}", e),
-                        };
-                        println!("Regen response {}", response_msg);
-                        Some(SendMessage {
-                            msg_type: zulip::SendMessageType::Direct(msg.sender_id),
-                            msg: response_msg,
-                        })
-
-                    }
-                    _ => {
-                        let response_msg = match create_blog(&db, &msg) {
-                            Ok(subdomain) => format!(
-                                "Blog created successfully! You can access your beautiful new blog at https://{}.hypertxt.io",
-                                subdomain
-                            ),
-                            Err(e) => format!("Uh oh, something went wrong. Error: {:?}", e),
-                        };
-                        println!("Create response {}", response_msg);
-                        Some(SendMessage {
-                            msg_type: zulip::SendMessageType::Direct(msg.sender_id),
-                            msg: response_msg,
-                        })
-                }
-            }
+                let response_msg = match handle_dm(&db, &builds, &msg).await {
+                    Ok(v) => v,
+                    Err(e) => format!("Uh oh, something went wrong. Error: {:?}", e),
+                };
+                println!("DM response {}", response_msg);
+                Some(SendMessage {
+                    msg_type: zulip::SendMessageType::Direct(msg.sender_id),
+                    msg: response_msg,
+                })
             }
         })
         .await
@@ -68,12 +101,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mention_handle = tokio::spawn(async move {
         zulip::call_on_each_message(ListenType::Mention, EventType::Message, |msg| {
             let db = mention_db.clone();
+            let builds = mention_builds.clone();
             async move {
-                let response_msg = match add_post(&db, &msg).await {
-                    Ok(subdomain) => format!(
-                        "Post published successfully! You can view it at https://{}.hypertxt.io",
-                        subdomain
-                    ),
+                let response_msg = match handle_mention(&db, &builds, &msg, "published").await {
+                    Ok(v) => v,
                     Err(e) => format!("Uh oh, something went wrong. Error: {:?}", e),
                 };
                 println!("Response {}", response_msg);
@@ -93,12 +124,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let update_handle = tokio::spawn(async move {
         zulip::call_on_each_message(ListenType::Mention, EventType::UpdateMessage, |msg| {
             let db = update_db.clone();
+            let builds = update_builds.clone();
             async move {
-                let response_msg = match add_post(&db, &msg).await {
-                    Ok(subdomain) => format!(
-                        "Post edited successfully! You can view it at https://{}.hypertxt.io",
-                        subdomain
-                    ),
+                let response_msg = match handle_mention(&db, &builds, &msg, "edited").await {
+                    Ok(v) => v,
                     Err(e) => format!("Uh oh, something went wrong. Error: {:?}", e),
                 };
                 println!("Response {}", response_msg);
@@ -115,7 +144,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
     });
 
-    futures::future::join_all([dm_handle, mention_handle, update_handle]).await;
+    let mut handles = vec![dm_handle, mention_handle, update_handle];
+
+    // Serving the blogs ourselves is optional, since production has a proxy
+    // in front of STATIC_ROOT instead
+    if let Ok(addr) = env::var("SERVE_ADDR") {
+        let server_db = Arc::clone(&db);
+        let resolve_domain: server::ResolveDomain =
+            Arc::new(move |host| domain_subdomain(&server_db, host).ok().flatten());
+        let server = server::Server::from_env(resolve_domain)?;
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = server.run(&addr).await {
+                eprintln!("blog server stopped: {:?}", e);
+            }
+        }));
+    }
+
+    futures::future::join_all(handles).await;
 
     Ok(())
 }
@@ -124,52 +169,942 @@ const USER_ID_TO_SUBDOMAIN_TABLE: TableDefinition<u64, &str> =
     TableDefinition::new("user_id_to_subdomain");
 const SUBDOMAIN_TO_USER_ID_TABLE: TableDefinition<&str, u64> =
     TableDefinition::new("subdomain_to_user_id");
-const USER_ID_TO_POST_IDS_TABLE: TableDefinition<u64, Vec<u64>> =
-    TableDefinition::new("user_id_to_post_ids");
-const POST_ID_TO_POST_TABLE: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_post");
+// The blog's bloggen::BlogSettings, as JSON
+const USER_ID_TO_SETTINGS_TABLE: TableDefinition<u64, &str> =
+    TableDefinition::new("user_id_to_settings");
+// Every post each user has published, keyed by (user ID, when the message
+// was sent, post ID) so that they're in the order they were published. Posts
+// from before we recorded when, sort first, by ID.
+const USER_POSTS_TABLE: TableDefinition<(u64, u64, u64), ()> = TableDefinition::new("user_posts");
+// Each post's posts::PostRecord, as JSON
+const POST_ID_TO_RECORD_TABLE: TableDefinition<u64, &str> =
+    TableDefinition::new("post_id_to_record");
+// The post's current slug first, followed by any old ones it has had
+const POST_ID_TO_SLUGS_TABLE: TableDefinition<u64, Vec<String>> =
+    TableDefinition::new("post_id_to_slugs");
+// Slugs are unique per blog, and old ones stay claimed so that they keep
+// redirecting to the post that used to have them.
+const USER_SLUG_TO_POST_ID_TABLE: TableDefinition<(u64, &str), u64> =
+    TableDefinition::new("user_slug_to_post_id");
+
+// Every version of each post, as a posts::Revision in JSON. They're
+// numbered from 1 for each post.
+const POST_REV_TO_REVISION_TABLE: TableDefinition<(u64, u64), &str> =
+    TableDefinition::new("post_rev_to_revision");
+
+// Custom domains that have been verified, and which blog they belong to
+const DOMAIN_TO_USER_ID_TABLE: TableDefinition<&str, u64> =
+    TableDefinition::new("domain_to_user_id");
+// A custom domain being added, and the token that proves it points at us
+const USER_ID_TO_PENDING_DOMAIN_TABLE: TableDefinition<u64, (&str, &str)> =
+    TableDefinition::new("user_id_to_pending_domain");
+
+// Slugs that would clash with pages Zola generates itself, or the section
+// standalone pages live in
+const RESERVED_SLUGS: [&str; 4] = ["tags", "categories", "page", "pages"];
+
+// DMs are either commands, or `KEY: value` lines that create a blog (if
+// there's a SUBDOMAIN) or change an existing blog's settings.
+async fn handle_dm(
+    db: &Arc<Database>,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match msg.content.trim() {
+        "tags" => list_tags(db, msg),
+        "themes" => list_themes(db, msg),
+        "pages" => list_pages(db, msg),
+        "status" => build_status(db, builds, msg),
+        "rollback" => rollback(db, builds, msg),
+        "regenerate" => refresh_all_posts(db, builds, msg, false).await,
+        "regenerate --refetch" => refresh_all_posts(db, builds, msg, true).await,
+        "rebuild-all" => rebuild_all(db, builds, msg),
+        "fsck" => check_consistency(db, builds, msg, false).await,
+        "fsck --repair" => check_consistency(db, builds, msg, true).await,
+        content if content.split_whitespace().next() == Some("posts") => {
+            let args: Vec<&str> = content.split_whitespace().skip(1).collect();
+            list_posts(db, msg, &args)
+        }
+        content
+            if matches!(
+                content.split_whitespace().next(),
+                Some("history" | "diff" | "restore")
+            ) =>
+        {
+            let args: Vec<&str> = content.split_whitespace().collect();
+            revision_command(db, builds, msg, &args).await
+        }
+        content if content.split_whitespace().next() == Some("domain") => {
+            let args: Vec<&str> = content.split_whitespace().skip(1).collect();
+            domain_command(db, builds, msg, &args).await
+        }
+        content if content.lines().next() == Some("css") => {
+            set_custom_css(
+                db,
+                builds,
+                msg,
+                content.strip_prefix("css").unwrap_or_default(),
+            )
+            .await
+        }
+        content => {
+            let md = bloggen::parse_metadata(content)?;
+            if md.contains_key("SUBDOMAIN") {
+                let subdomain = create_blog(db, builds, msg, md)?;
+                Ok(format!(
+                    "Blog created successfully! Your beautiful new blog will be at https://{} in a moment.",
+                    domains::default_host(&subdomain)
+                ))
+            } else {
+                update_settings(db, builds, msg, md).await
+            }
+        }
+    }
+}
 
+// Writes the sender's blog out again and rebuilds it, from the posts we have
+// stored, or with `refetch` from their messages as they are on Zulip now (for
+// edits we missed).
 async fn refresh_all_posts(
     db: &Database,
+    builds: &BuildQueue,
     msg: &Message,
+    refetch: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
     let user_id = msg.sender_id;
+    let _lock = lock_blog(db, builds, user_id).await?;
+    let settings = read_settings(db, user_id)?;
+
+    let mut posts = user_posts(db, user_id)?;
+    let stored = posts.clone();
+    let missing = if refetch {
+        bloggen::refetch_posts(&mut posts).await?
+    } else {
+        Vec::new()
+    };
+
+    let staged = stage_rewrite(builds, &settings, &posts)
+        .await
+        .map_err(|e| {
+            format!(
+                "I couldn't regenerate your blog, so it's just as it was: {}",
+                e
+            )
+        })?;
+
+    // Keep whatever we learned about the posts from Zulip, now that we know
+    // the blog builds with it
+    let txn = db.begin_write()?;
+    for ((post_id, record), (_, old)) in posts.iter_mut().zip(&stored) {
+        if record == old {
+            continue;
+        }
+        record.build = posts::BuildState::Pending;
+        write_record(&txn, *post_id, record)?;
+        add_revision(&txn, *post_id, record)?;
+    }
+    txn.commit()?;
+    staged.swap_in()?;
+    builds.enqueue(&settings.subdomain, user_id, Changes::All)?;
+
+    let mut response = "Regenerating your blog, I'll let you know when it's done.".to_string();
+    if !refetch {
+        response.push_str(
+            " If you edited any posts while I wasn't listening, `regenerate --refetch` picks the edits up from Zulip.",
+        );
+    }
+    if !missing.is_empty() {
+        response.push_str(&format!(
+            "\n\nI couldn't find the messages for {} of your posts on Zulip any more, so they're as they were.",
+            missing.len()
+        ));
+    }
+    Ok(response)
+}
+
+// Whether the user is one of the people running the bot (OPERATORS, a comma
+// separated list of Zulip user IDs), who can use the commands that affect
+// every blog.
+fn is_operator(user_id: u64) -> bool {
+    env::var("OPERATORS")
+        .unwrap_or_default()
+        .split(',')
+        .any(|id| id.trim().parse() == Ok(user_id))
+}
+
+// How often rebuild-all tells the operator how it's getting on, in blogs
+const REBUILD_PROGRESS_EVERY: usize = 10;
+
+// Whether a rebuild-all is going, so another can't start until it's done
+static REBUILDING_ALL: AtomicBool = AtomicBool::new(false);
+
+// Held by the rebuild-all that's going, and lets another start when it's
+// dropped, however that one ends.
+struct RebuildingAll;
+
+impl RebuildingAll {
+    fn start() -> Option<Self> {
+        (!REBUILDING_ALL.swap(true, Ordering::SeqCst)).then_some(RebuildingAll)
+    }
+}
+
+impl Drop for RebuildingAll {
+    fn drop(&mut self) {
+        REBUILDING_ALL.store(false, Ordering::SeqCst);
+    }
+}
+
+// Writes every blog out again from the posts we have stored, through the
+// current templates, and rebuilds it. For after changing how blogs are
+// generated. It carries on in the background, telling the operator how it's
+// going by DM.
+fn rebuild_all(
+    db: &Arc<Database>,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !is_operator(msg.sender_id) {
+        return Err("Only the people running the bot can do that".into());
+    }
+    let Some(rebuilding) = RebuildingAll::start() else {
+        return Err(
+            "There's already a rebuild-all going. I'll let you know when it's done.".into(),
+        );
+    };
+    let blogs = all_blogs(db)?;
+    let response = format!(
+        "Rebuilding all {} blogs, {} at a time. I'll let you know how it's going.",
+        blogs.len(),
+        builds.parallel()
+    );
+    tokio::spawn(rebuild_blogs(
+        Arc::clone(db),
+        builds.clone(),
+        msg.sender_id,
+        blogs,
+        rebuilding,
+    ));
+    Ok(response)
+}
+
+async fn rebuild_blogs(
+    db: Arc<Database>,
+    builds: BuildQueue,
+    operator: u64,
+    blogs: Vec<(u64, String)>,
+    rebuilding: RebuildingAll,
+) {
+    use futures::StreamExt;
+
+    let total = blogs.len();
+    let mut results = futures::stream::iter(blogs)
+        .map(|(user_id, subdomain)| {
+            let (db, builds) = (&db, &builds);
+            async move {
+                let result = rebuild_blog(db, builds, user_id).await;
+                (subdomain, result)
+            }
+        })
+        .buffer_unordered(builds.parallel());
+
+    let mut done = 0;
+    let mut failures = Vec::new();
+    while let Some((subdomain, result)) = results.next().await {
+        done += 1;
+        match result {
+            Ok(()) => println!("rebuilt {} ({}/{})", subdomain, done, total),
+            Err(e) => {
+                eprintln!(
+                    "failed to rebuild {} ({}/{}): {}",
+                    subdomain, done, total, e
+                );
+                failures.push((subdomain, e));
+            }
+        }
+        if done % REBUILD_PROGRESS_EVERY == 0 && done < total {
+            let progress = format!(
+                "Rebuilt {} of {} blogs so far, {} failed.",
+                done,
+                total,
+                failures.len()
+            );
+            if let Err(e) = zulip::send_direct_message(&progress, operator).await {
+                eprintln!("failed to report rebuild progress: {}", e);
+            }
+        }
+    }
+
+    drop(rebuilding);
+    let summary = rebuild_summary(total, failures);
+    if let Err(e) = zulip::send_direct_message(&summary, operator).await {
+        eprintln!("failed to report rebuild: {}\n{}", e, summary);
+    }
+}
+
+async fn rebuild_blog(db: &Database, builds: &BuildQueue, user_id: u64) -> Result<(), String> {
+    let subdomain = rewrite_from_db(db, builds, user_id).await?;
+    let outcome = builds.rebuild(&subdomain, user_id, Changes::All).await?;
+    outcome.result.map_err(|e| e.reason)
+}
+
+// Writes the blog out again from what the database has for it (see
+// stage_rewrite), and swaps it in. Nothing else can write the blog in the
+// meantime. It still needs a full build afterwards. Returns the blog's
+// subdomain.
+async fn rewrite_from_db(
+    db: &Database,
+    builds: &BuildQueue,
+    user_id: u64,
+) -> Result<String, String> {
+    let subdomain = read_settings(db, user_id)
+        .map_err(|e| e.to_string())?
+        .subdomain;
+    let _lock = builds.lock_content(&subdomain).await;
+    let settings = read_settings(db, user_id).map_err(|e| e.to_string())?;
+    let posts = user_posts(db, user_id).map_err(|e| e.to_string())?;
+    stage_rewrite(builds, &settings, &posts)
+        .await?
+        .swap_in()
+        .map_err(|e| e.to_string())?;
+    Ok(subdomain)
+}
+
+// Writes a blog out again into a copy, through the current templates, and
+// checks the copy builds. Whoever swaps it in for the blog has to hold the
+// blog's content lock from before they read `settings` and `posts`, so that
+// nothing written in between is lost.
+async fn stage_rewrite(
+    builds: &BuildQueue,
+    settings: &bloggen::BlogSettings,
+    posts: &PostRecords,
+) -> Result<bloggen::StagedBlog, String> {
+    let staged = {
+        let (settings, posts) = (settings.clone(), posts.clone());
+        tokio::task::spawn_blocking(move || {
+            bloggen::stage_blog(&settings, &posts).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| format!("crashed: {:?}", e))??
+    };
+    let name = format!("{} rewritten", settings.subdomain);
+    builds
+        .run_one_off(&name, staged.check())
+        .await
+        .map_err(|e| format!("it doesn't build: {}", e))?;
+    Ok(staged)
+}
+
+// Blogs whose config.toml isn't what their settings make now (see
+// bloggen::config_is_current), as (user ID, subdomain).
+fn stale_configs(db: &Database) -> Result<Vec<(u64, String)>, Box<dyn std::error::Error>> {
+    let mut stale = Vec::new();
+    for (user_id, subdomain) in all_blogs(db)? {
+        let current = read_settings(db, user_id).and_then(|s| bloggen::config_is_current(&s));
+        match current {
+            Ok(true) => {}
+            Ok(false) => stale.push((user_id, subdomain)),
+            Err(e) => eprintln!("failed to check the config of {}: {}", subdomain, e),
+        }
+    }
+    Ok(stale)
+}
+
+// Writes the blogs out again with their configs as they are now, one at a
+// time, and rebuilds them.
+async fn rewrite_stale(db: Arc<Database>, builds: BuildQueue, blogs: Vec<(u64, String)>) {
+    for (user_id, subdomain) in blogs {
+        match rebuild_blog(&db, &builds, user_id).await {
+            Ok(()) => println!("rewrote {} with its config up to date", subdomain),
+            Err(e) => eprintln!("failed to rewrite {}: {}", subdomain, e),
+        }
+    }
+}
+
+fn rebuild_summary(total: usize, mut failures: Vec<(String, String)>) -> String {
+    if failures.is_empty() {
+        return format!("Rebuilt all {} blogs!", total);
+    }
+    failures.sort();
+    let mut summary = format!(
+        "Rebuilt {} of {} blogs. These ones failed:\n",
+        total - failures.len(),
+        total
+    );
+    for (subdomain, reason) in failures {
+        summary.push_str(&format!("* **{}**: {}\n", subdomain, reason));
+    }
+    summary
+}
 
+// Every blog, as (user ID, subdomain)
+fn all_blogs(db: &Database) -> Result<Vec<(u64, String)>, Box<dyn std::error::Error>> {
     let txn = db.begin_read()?;
+    let tbl = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
+    let mut blogs = Vec::new();
+    for entry in tbl.iter()? {
+        let (user_id, subdomain) = entry?;
+        blogs.push((user_id.value(), subdomain.value().to_string()));
+    }
+    Ok(blogs)
+}
+
+// The most problems check_consistency lists, so the DM isn't too long
+const MAX_PROBLEMS_LISTED: usize = 50;
+
+// Finds where the database and what's on disk disagree (see fsck), and with
+// `repair`, puts right what it can. Posts that are missing from disk are
+// written out again from the database, and their blogs rebuilt.
+async fn check_consistency(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    repair: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !is_operator(msg.sender_id) {
+        return Err("Only the people running the bot can do that".into());
+    }
+    let roots = fsck::Roots::from_env()?;
+    let (expected, mut problems) = expected_on_disk(db)?;
+    problems.extend(fsck::check(
+        &roots,
+        &expected,
+        std::time::SystemTime::now(),
+    )?);
+    if problems.is_empty() {
+        return Ok("Everything's consistent!".to_string());
+    }
+    if !repair {
+        let mut response = format!("Found {} problems:\n", problems.len());
+        response.push_str(&list_problems(problems.iter().map(|p| p.to_string())));
+        response.push_str("\nDM `fsck --repair` to fix the ones that can be fixed.");
+        return Ok(response);
+    }
 
-    let (post_ids, subdomain) = {
-        let posts_tbl = txn.open_table(USER_ID_TO_POST_IDS_TABLE)?;
-        let post_ids = {
-            match posts_tbl.get(&user_id)? {
-                Some(v) => v.value(),
-                None => vec![],
+    let total = problems.len();
+    let mut unfixed = Vec::new();
+    let mut rewrite = BTreeMap::new();
+    for problem in problems {
+        let result = match &problem {
+            fsck::Problem::UnmappedSubdomain { user_id, subdomain } => {
+                map_subdomain(db, *user_id, subdomain).map(|_| true)
+            }
+            fsck::Problem::MissingBlog { subdomain }
+            | fsck::Problem::MissingSite { subdomain }
+            | fsck::Problem::MissingPost { subdomain, .. } => {
+                rewrite
+                    .entry(subdomain.clone())
+                    .or_insert_with(Vec::new)
+                    .push(problem.clone());
+                continue;
             }
+            fsck::Problem::UnknownBlog { .. } => Err("it needs someone to look at it".into()),
+            _ => fsck::repair(&roots, &problem).map_err(|e| e.into()),
         };
-
-        let subdomain_tbl = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
-        let subdomain = {
-            match subdomain_tbl.get(&user_id)? {
-                Some(v) => String::from(v.value()),
-                None => "".to_string(),
+        match result {
+            Ok(true) => {}
+            Ok(false) => unfixed.push(format!("{} (not fixed)", problem)),
+            Err(e) => unfixed.push(format!("{} (not fixed: {})", problem, e)),
+        }
+    }
+    for (subdomain, problems) in rewrite {
+        if let Err(e) = rewrite_blog(db, builds, &subdomain).await {
+            for problem in problems {
+                unfixed.push(format!("{} (not fixed: {})", problem, e));
             }
+        }
+    }
+
+    let mut response = format!("Fixed {} of {} problems.", total - unfixed.len(), total);
+    if !unfixed.is_empty() {
+        response.push_str(" These are left:\n");
+        response.push_str(&list_problems(unfixed.into_iter()));
+    }
+    Ok(response)
+}
+
+fn list_problems(problems: impl ExactSizeIterator<Item = String>) -> String {
+    let more = problems.len().saturating_sub(MAX_PROBLEMS_LISTED);
+    let mut list = String::new();
+    for problem in problems.take(MAX_PROBLEMS_LISTED) {
+        list.push_str(&format!("* {}\n", problem));
+    }
+    if more > 0 {
+        list.push_str(&format!("* ...and {} more\n", more));
+    }
+    list
+}
+
+// What should be on disk according to the database, along with anything
+// wrong with the database itself.
+fn expected_on_disk(
+    db: &Database,
+) -> Result<(fsck::Expected, Vec<fsck::Problem>), Box<dyn std::error::Error>> {
+    let mut expected = fsck::Expected::default();
+    let mut problems = Vec::new();
+    for (user_id, subdomain) in all_blogs(db)? {
+        let owner = {
+            let txn = db.begin_read()?;
+            let tbl = txn.open_table(SUBDOMAIN_TO_USER_ID_TABLE)?;
+            let owner = tbl.get(subdomain.as_str())?.map(|v| v.value());
+            owner
         };
-        (post_ids, subdomain)
+        if owner != Some(user_id) {
+            problems.push(fsck::Problem::UnmappedSubdomain {
+                user_id,
+                subdomain: subdomain.clone(),
+            });
+        }
+
+        let mut posts = BTreeMap::new();
+        for (post_id, record) in user_posts(db, user_id)? {
+            posts.insert(post_id, record.page.is_some());
+            expected.uploads.extend(record.assets);
+        }
+        expected.blogs.insert(subdomain, posts);
+        if let Ok(settings) = read_settings(db, user_id) {
+            expected
+                .uploads
+                .extend(bloggen::extract_user_upload_urls(&settings.intro));
+        }
+    }
+
+    // Restoring an old revision needs its uploads too
+    let txn = db.begin_read()?;
+    for entry in txn.open_table(POST_REV_TO_REVISION_TABLE)?.iter()? {
+        let revision: posts::Revision = serde_json::from_str(entry?.1.value())?;
+        expected
+            .uploads
+            .extend(bloggen::extract_user_upload_urls(&revision.content));
+    }
+    Ok((expected, problems))
+}
+
+// Points the subdomain back at the user it belongs to, unless someone else
+// has it.
+fn map_subdomain(
+    db: &Database,
+    user_id: u64,
+    subdomain: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let txn = db.begin_write()?;
+    {
+        let mut tbl = txn.open_table(SUBDOMAIN_TO_USER_ID_TABLE)?;
+        if let Some(owner) = tbl.get(subdomain)?.map(|v| v.value()) {
+            return Err(format!("it's mapped to user {}", owner).into());
+        }
+        tbl.insert(subdomain, &user_id)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+// Writes a blog out again from the database, creating it if it's gone, and
+// rebuilds it.
+async fn rewrite_blog(
+    db: &Database,
+    builds: &BuildQueue,
+    subdomain: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let user_id = all_blogs(db)?
+        .into_iter()
+        .find(|(_, s)| s == subdomain)
+        .map(|(user_id, _)| user_id)
+        .ok_or("it isn't anyone's blog")?;
+    let settings = read_settings(db, user_id)?;
+    if paths::blog(subdomain).is_err() {
+        bloggen::create_blog(&settings)?;
+    }
+    rewrite_from_db(db, builds, user_id).await?;
+    builds.enqueue(subdomain, user_id, Changes::All)?;
+    Ok(())
+}
+
+// Whether the sender's blog has a build waiting or running, and how the last
+// one went.
+fn build_status(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let settings = read_settings(db, msg.sender_id)?;
+    let status = builds.status(&settings.subdomain);
+
+    let mut response = if status.running {
+        "Your blog is being rebuilt right now.".to_string()
+    } else if status.queued {
+        "Your blog is about to be rebuilt.".to_string()
+    } else {
+        format!("Your blog at {} is up to date.", settings.url())
+    };
+    if let Some(last) = status.last {
+        let when = last.finished_at.format("%Y-%m-%d %H:%M:%S UTC");
+        response.push_str(&match last.result {
+            Ok(()) => format!("\n\nThe last build finished at {}.", when),
+            Err(e) => format!("\n\nThe last build failed at {}: {}", when, e.reason),
+        });
+    }
+    if status.queued && status.running {
+        response.push_str("\n\nThere are more changes waiting to go in after it.");
+    }
+    for (_, record) in user_posts(db, msg.sender_id)? {
+        if let posts::BuildState::Failed { reason } = record.build {
+            response.push_str(&format!(
+                "\n\n**{}** is holding things up: {}",
+                record.title, reason
+            ));
+        }
+    }
+    Ok(response)
+}
+
+// Puts the sender's blog back to how it was before its last build. The
+// content stays as it is, so the next build brings everything back.
+fn rollback(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let settings = read_settings(db, msg.sender_id)?;
+    let status = builds.status(&settings.subdomain);
+    if status.queued || status.running {
+        return Err("Your blog is being rebuilt right now, try again once it's done".into());
+    }
+    deploys::Deployer::from_env(&settings.subdomain)?.rollback()?;
+    Ok(format!(
+        "Rolled your blog at {} back to the build before. Publishing or editing anything will bring it up to date again.",
+        settings.url()
+    ))
+}
+
+async fn revision_command(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    args: &[&str],
+) -> Result<String, Box<dyn std::error::Error>> {
+    match args {
+        ["history", post] => post_history(db, msg, post),
+        ["diff", post, rev] => diff_revision(db, msg, post, parse_revision(rev)?),
+        ["restore", post, rev] => {
+            restore_revision(db, builds, msg, post, parse_revision(rev)?).await
+        }
+        _ => Err("Try `history my-post`, `diff my-post 2` or `restore my-post 2`".into()),
+    }
+}
+
+fn parse_revision(rev: &str) -> Result<u64, String> {
+    rev.trim_start_matches('#')
+        .parse()
+        .map_err(|_| format!("{:?} isn't a revision number", rev))
+}
+
+// Finds one of the sender's posts from what they called it: its slug (or an
+// old one), its URL, or the ID of its message.
+fn find_post(
+    db: &Database,
+    user_id: u64,
+    name: &str,
+) -> Result<(u64, posts::PostRecord), Box<dyn std::error::Error>> {
+    let slug = name
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(name);
+    let txn = db.begin_read()?;
+    let post_id = match txn
+        .open_table(USER_SLUG_TO_POST_ID_TABLE)?
+        .get((user_id, slug))?
+    {
+        Some(v) => Some(v.value()),
+        None => slug.parse().ok(),
+    };
+    if let Some(post_id) = post_id {
+        let record = read_record(&txn.open_table(POST_ID_TO_RECORD_TABLE)?, post_id)?;
+        if let Some(record) = record.filter(|r| r.user_id == user_id) {
+            return Ok((post_id, record));
+        }
+    }
+    Err(format!(
+        "You don't have a post called {:?}. Use the last part of its URL, e.g. `my-post`.",
+        name
+    )
+    .into())
+}
+
+fn find_revision(
+    db: &Database,
+    post_id: u64,
+    record: &posts::PostRecord,
+    rev: u64,
+) -> Result<posts::Revision, Box<dyn std::error::Error>> {
+    match revisions(db, post_id)?.into_iter().find(|(r, _)| *r == rev) {
+        Some((_, revision)) => Ok(revision),
+        None => Err(format!(
+            "**{}** doesn't have a revision {}. DM `history {}` to see the ones it does.",
+            record.title,
+            rev,
+            post_name(post_id, record)
+        )
+        .into()),
+    }
+}
+
+// What to call a post in commands
+fn post_name(post_id: u64, record: &posts::PostRecord) -> String {
+    record.slug().map_or(post_id.to_string(), |s| s.slug)
+}
+
+// Lists every version of one of the sender's posts.
+fn post_history(
+    db: &Database,
+    msg: &Message,
+    name: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (post_id, record) = find_post(db, msg.sender_id, name)?;
+    let revisions = revisions(db, post_id)?;
+    let current = revisions.last().map(|(rev, _)| *rev);
+
+    let mut response = format!("Revisions of **{}**:\n", record.title);
+    for (rev, revision) in &revisions {
+        let when = chrono::DateTime::from_timestamp(revision.timestamp as i64, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_default();
+        response.push_str(&format!(
+            "* **{}** - {} - {}{}\n",
+            rev,
+            when,
+            revision.title,
+            if Some(*rev) == current {
+                " (current)"
+            } else {
+                ""
+            }
+        ));
+    }
+    let name = post_name(post_id, &record);
+    response.push_str(&format!(
+        "\nSee what's changed since one with `diff {} <revision>`, or go back to it with `restore {} <revision>`",
+        name, name
+    ));
+    Ok(response)
+}
+
+// Zulip won't send anything much longer than this
+const MAX_DIFF_LEN: usize = 8000;
+
+// Shows what's changed in one of the sender's posts since an earlier
+// revision.
+fn diff_revision(
+    db: &Database,
+    msg: &Message,
+    name: &str,
+    rev: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (post_id, record) = find_post(db, msg.sender_id, name)?;
+    let revision = find_revision(db, post_id, &record, rev)?;
+
+    let mut diff = posts::diff(
+        &revision.content,
+        &record.content,
+        &format!("revision {}", rev),
+        "now",
+    );
+    if diff.is_empty() {
+        return Ok(format!(
+            "**{}** is the same now as it was in revision {}.",
+            record.title, rev
+        ));
+    }
+    if diff.len() > MAX_DIFF_LEN {
+        let end = diff.as_bytes()[..MAX_DIFF_LEN]
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        diff.truncate(end);
+        diff.push_str("...\n");
+    }
+    Ok(format!(
+        "What's changed in **{}** since revision {}:\n````diff\n{}````",
+        record.title, rev, diff
+    ))
+}
+
+// Publishes an earlier revision of one of the sender's posts again, as if
+// they'd edited the message back to it.
+async fn restore_revision(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    name: &str,
+    rev: u64,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (post_id, record) = find_post(db, msg.sender_id, name)?;
+    let revision = find_revision(db, post_id, &record, rev)?;
+    if revision.content == record.content {
+        return Ok(format!(
+            "**{}** is already the same as revision {}.",
+            record.title, rev
+        ));
+    }
+
+    let timestamp = match record.timestamp {
+        Some(ts) => ts,
+        None => zulip::get_message(post_id).await?.timestamp,
+    };
+    let restored = Message {
+        content: revision.content,
+        id: post_id,
+        sender_id: msg.sender_id,
+        stream_id: record.stream_id,
+        timestamp,
+        subject: record.topic.clone(),
+        sender_full_name: record.author.clone(),
+        last_edit_timestamp: Some(msg.timestamp),
+    };
+    let published = add_post(db, builds, &restored).await?;
+    Ok(format!(
+        "{}\n\nYour message on Zulip still has the newer version, so editing it will replace this again.",
+        published.response("restored")
+    ))
+}
+
+// Keeps track of which posts have made it onto the live site. A build that
+// worked had everything in it, and one that failed is put down to the post it
+// failed on, if we can tell which one that was.
+fn record_build(
+    db: &Database,
+    outcome: &builds::Outcome,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let post_ids: Vec<u64> = match &outcome.result {
+        Ok(()) => user_posts(db, outcome.user_id)?
+            .into_iter()
+            .filter(|(_, record)| record.build != posts::BuildState::Built)
+            .map(|(post_id, _)| post_id)
+            .collect(),
+        Err(e) => e.post_id().into_iter().collect(),
     };
 
-    bloggen::refresh_all_posts(&subdomain, post_ids).await?;
+    let txn = db.begin_write()?;
+    for post_id in post_ids {
+        let record = read_record(&txn.open_table(POST_ID_TO_RECORD_TABLE)?, post_id)?;
+        let Some(mut record) = record else {
+            continue;
+        };
+        if record.user_id != outcome.user_id {
+            continue;
+        }
+        record.build = match &outcome.result {
+            Ok(()) => posts::BuildState::Built,
+            Err(e) => posts::BuildState::Failed {
+                reason: e.reason.clone(),
+            },
+        };
+        write_record(&txn, post_id, &record)?;
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+// What we DM an author once one of their builds has finished. Failures
+// point at the post that caused them, if we can tell which one it was.
+fn build_report(db: &Database, outcome: &builds::Outcome) -> String {
+    let url = read_settings(db, outcome.user_id)
+        .map(|s| s.url())
+        .unwrap_or_else(|_| format!("https://{}", domains::default_host(&outcome.subdomain)));
+    let e = match &outcome.result {
+        Ok(()) => return format!("Your blog at {} is up to date!", url),
+        Err(e) => e,
+    };
 
-    Ok("Blog regenerated successfully!".to_string())
+    let post = e.post_id().and_then(|post_id| {
+        let txn = db.begin_read().ok()?;
+        let record =
+            read_record(&txn.open_table(POST_ID_TO_RECORD_TABLE).ok()?, post_id).ok()??;
+        Some((post_id, record))
+    });
+    let mut msg = e.explain(
+        post.as_ref().map(|(_, record)| record.title.as_str()),
+        post.as_ref().map(|(_, record)| record.content.as_str()),
+    );
+    if let Some((post_id, _)) = post {
+        msg.push_str(&format!(
+            "\n\nEdit [your message]({}) to fix it.",
+            zulip::message_url(post_id)
+        ));
+    }
+    msg.push_str(&format!(
+        "\n\nYour blog at {} is still showing the last build that worked.",
+        url
+    ));
+    msg
 }
 
-fn create_blog(db: &Database, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
+// Lists the tags and categories used across the sender's published posts,
+// with how many posts use each.
+fn list_tags(db: &Database, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
     let user_id = msg.sender_id;
 
-    let md = bloggen::parse_metadata(&msg.content)?;
+    let blog_url = read_settings(db, user_id)?.url();
+
+    let mut counts: [BTreeMap<String, usize>; 2] = Default::default();
+    for (_, record) in user_posts(db, user_id)? {
+        if record.draft {
+            continue;
+        }
+        for (count, terms) in counts.iter_mut().zip([record.tags, record.categories]) {
+            for term in terms {
+                *count.entry(term).or_default() += 1;
+            }
+        }
+    }
+
+    let mut response = String::new();
+    for (name, count) in zola::TAXONOMIES.iter().zip(counts) {
+        if count.is_empty() {
+            continue;
+        }
+        response.push_str(&format!("**{}**\n", name));
+        for (term, n) in count {
+            response.push_str(&format!(
+                "* [{}]({}/{}/{}/) ({} post{})\n",
+                term,
+                blog_url,
+                name,
+                slug::slugify(&term),
+                n,
+                if n == 1 { "" } else { "s" }
+            ));
+        }
+    }
+    if response.is_empty() {
+        return Ok(
+            "You haven't tagged any posts yet! Add a `TAGS: ...` line to the top of a post."
+                .to_string(),
+        );
+    }
+    Ok(response)
+}
+
+fn create_blog(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    md: HashMap<String, String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
 
     let subdomain = match md.get("SUBDOMAIN") {
         Some(v) => v.clone(),
         None => return Err("couldn't find a requested SUBDOMAIN".into()),
     };
 
+    let mut settings = bloggen::BlogSettings::new(&subdomain);
+    for (key, value) in &md {
+        if key != "SUBDOMAIN" {
+            settings.set(key, value)?;
+        }
+    }
+
     let read_tx = db.begin_read()?;
     {
         let t1 = read_tx.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
@@ -177,7 +1112,11 @@ fn create_blog(db: &Database, msg: &Message) -> Result<String, Box<dyn std::erro
 
         if let Some(v) = t1.get(&user_id)? {
             if v.value() != subdomain {
-                return Err(format!("You've already got a blog at https://{}.hypertxt.io and you can only have one!", v.value()).into());
+                return Err(format!(
+                    "You've already got a blog at https://{} and you can only have one!",
+                    domains::default_host(v.value())
+                )
+                .into());
             }
             return Err("You've already got a blog at that subdomain".into());
         }
@@ -191,7 +1130,7 @@ fn create_blog(db: &Database, msg: &Message) -> Result<String, Box<dyn std::erro
     }
 
     println!("Creating blog for {}", msg.sender_full_name);
-    bloggen::create_blog(md)?;
+    bloggen::create_blog(&settings)?;
     println!("Created blog at {}", subdomain);
 
     let txn = db.begin_write()?;
@@ -201,47 +1140,1298 @@ fn create_blog(db: &Database, msg: &Message) -> Result<String, Box<dyn std::erro
 
         t1.insert(&user_id, subdomain.as_str())?;
         t2.insert(subdomain.as_str(), &user_id)?;
+        write_settings(&txn, user_id, &settings)?;
     }
     txn.commit()?;
     println!("Wrote metadata for {} to DB", subdomain);
 
+    builds.enqueue(&subdomain, user_id, Changes::All)?;
     Ok(subdomain)
 }
 
-async fn add_post(db: &Database, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
-    // assuming a blog is created, publish a post!
-    // in markdown at file: user_content/{sender_id}/{id}.md
-    // takes post_title from top of md file, demarcated by #
-
+// Changes settings on the sender's existing blog, e.g. `THEME: terminimal`,
+// then rebuilds it.
+async fn update_settings(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    md: HashMap<String, String>,
+) -> Result<String, Box<dyn std::error::Error>> {
     let user_id = msg.sender_id;
-    let message_id = msg.id;
+    let _lock = lock_blog(db, builds, user_id).await?;
+    let mut settings = read_settings(db, user_id)?;
 
-    let txn = db.begin_write()?;
-    let subdomain = {
-        let mut t1 = txn.open_table(USER_ID_TO_POST_IDS_TABLE)?;
-        let mut t2 = txn.open_table(POST_ID_TO_POST_TABLE)?;
-        let t3 = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
+    let mut changes = Vec::new();
+    for (key, value) in md {
+        settings.set(&key, &value)?;
+        if key == "MENU" {
+            let pages = user_pages(db, user_id)?;
+            for slug in &settings.menu {
+                if !pages.iter().any(|p| p.slug == *slug) {
+                    return Err(format!(
+                        "You don't have a page called {:?}. DM `pages` to see the ones you do.",
+                        slug
+                    )
+                    .into());
+                }
+            }
+        }
+        changes.push(key);
+    }
+    changes.sort();
 
-        let mut post_ids = match t1.get(&user_id)? {
-            Some(v) => v.value(),
-            None => vec![],
-        };
-        post_ids.push(message_id);
+    save_settings(db, builds, user_id, &settings)?;
+
+    Ok(format!(
+        "Updated {}, your blog at {} will be rebuilt in a moment",
+        changes.join(", "),
+        settings.url()
+    ))
+}
+
+// Replaces the sender's custom stylesheet with everything after the `css`
+// line, or removes it if there's nothing there.
+async fn set_custom_css(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    css: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
+    let _lock = lock_blog(db, builds, user_id).await?;
+    let mut settings = read_settings(db, user_id)?;
+    settings.custom_css = themes::sanitize_css(css)?;
+
+    let theme = themes::find(&themes::themes_root()?, &settings.theme)?;
+    if !settings.custom_css.is_empty()
+        && !theme.has_block(&themes::themes_root()?, "index.html", "extra_head")
+    {
+        return Err(format!(
+            "The {} theme doesn't support custom CSS, sorry!",
+            theme.name
+        )
+        .into());
+    }
+
+    save_settings(db, builds, user_id, &settings)?;
+
+    if settings.custom_css.is_empty() {
+        return Ok("Removed your custom CSS".to_string());
+    }
+    Ok(format!(
+        "Updated your custom CSS, see it at {}",
+        settings.url()
+    ))
+}
+
+// `domain add example.com` serves the sender's blog at a custom domain, once
+// we've checked that it's really theirs. `domain remove` goes back to just
+// the subdomain.
+async fn domain_command(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    args: &[&str],
+) -> Result<String, Box<dyn std::error::Error>> {
+    match args {
+        ["add", domain] => add_domain(db, builds, msg, domain).await,
+        ["remove"] => remove_domain(db, builds, msg).await,
+        _ => Err("Try `domain add example.com` or `domain remove`".into()),
+    }
+}
 
-        t1.insert(&user_id, post_ids)?;
-        t2.insert(&message_id, msg.content.as_str())?;
+async fn add_domain(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    domain: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
+    let domain = domains::check_hostname(domain)?;
+    let settings = read_settings(db, user_id)?;
+    if settings.domain.as_deref() == Some(domain.as_str()) {
+        return Ok(format!("Your blog is already at {}", settings.url()));
+    }
 
-        let subdomain = {
-            match t3.get(&user_id)? {
-                Some(v) => String::from(v.value()),
-                None => "".to_string(),
+    let challenges = domains::challenges_root()?;
+    let txn = db.begin_write()?;
+    let token = {
+        check_domain_owner(&txn, &domain, user_id)?;
+        let mut pending_tbl = txn.open_table(USER_ID_TO_PENDING_DOMAIN_TABLE)?;
+        let pending = pending_tbl.get(&user_id)?.map(|v| {
+            let (d, t) = v.value();
+            (d.to_string(), t.to_string())
+        });
+        match pending {
+            // Keep the same token when retrying, in case it's just DNS taking
+            // a while
+            Some((d, t)) if d == domain => t,
+            old => {
+                if let Some((old_domain, _)) = old {
+                    domains::remove_challenge(&challenges, &old_domain)?;
+                }
+                let token = domains::new_token();
+                pending_tbl.insert(&user_id, (domain.as_str(), token.as_str()))?;
+                token
             }
-        };
-        subdomain
+        }
     };
     txn.commit()?;
 
-    bloggen::add_post(&subdomain, message_id, &msg.content, msg.timestamp).await?;
+    domains::write_challenge(&challenges, &domain, &token)?;
+    if let Err(e) = domains::verify(&domain, &token).await {
+        return Ok(format!(
+            "To use {domain}, point it at {base} (with a CNAME record, or an A record with the same address as {base} for a bare domain). Once the DNS change has gone through, send `domain add {domain}` again and I'll check.\n\nI couldn't confirm it yet: {e}",
+            domain = domain,
+            base = domains::BASE_DOMAIN,
+            e = e
+        ));
+    }
+
+    // The domain is only claimed once the transaction commits, and we undo
+    // everything before that if anything fails, so it's never left half
+    // added
+    let _lock = builds.lock_content(&settings.subdomain).await;
+    let mut settings = read_settings(db, user_id)?;
+    let posts = user_posts(db, user_id)?;
+    let old_settings = settings.clone();
+    let old_domain = settings.domain.replace(domain.clone());
+    let claimed = (|| -> Result<(), Box<dyn std::error::Error>> {
+        domains::write_proxy_config(&domain, &settings.subdomain)?;
+        bloggen::update_settings(&settings, &posts)?;
+        let txn = db.begin_write()?;
+        // Someone else could have verified it while we were
+        check_domain_owner(&txn, &domain, user_id)?;
+        {
+            let mut owners = txn.open_table(DOMAIN_TO_USER_ID_TABLE)?;
+            if let Some(old) = &old_domain {
+                owners.remove(old.as_str())?;
+            }
+            owners.insert(domain.as_str(), &user_id)?;
+            txn.open_table(USER_ID_TO_PENDING_DOMAIN_TABLE)?
+                .remove(&user_id)?;
+        }
+        write_settings(&txn, user_id, &settings)?;
+        txn.commit()?;
+        Ok(())
+    })();
+    if let Err(e) = claimed {
+        if let Err(e) = domains::remove_proxy_config(&domain) {
+            eprintln!("failed to remove proxy config for {}: {}", domain, e);
+        }
+        if let Err(e) = bloggen::update_settings(&old_settings, &posts) {
+            eprintln!("failed to restore settings for {}: {}", user_id, e);
+        }
+        return Err(e);
+    }
+
+    // It's ours now whatever happens, so these are just tidying up
+    if let Some(old) = &old_domain {
+        if let Err(e) = domains::remove_proxy_config(old) {
+            eprintln!("failed to remove proxy config for {}: {}", old, e);
+        }
+    }
+    if let Err(e) = domains::remove_challenge(&challenges, &domain) {
+        eprintln!("failed to remove challenge for {}: {}", domain, e);
+    }
+    builds.enqueue(&settings.subdomain, user_id, Changes::All)?;
+
+    Ok(format!(
+        "Your blog is now at {}! It can take a minute for the HTTPS certificate to be issued.",
+        settings.url()
+    ))
+}
+
+fn check_domain_owner(
+    txn: &WriteTransaction,
+    domain: &str,
+    user_id: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(owner) = txn.open_table(DOMAIN_TO_USER_ID_TABLE)?.get(domain)? {
+        if owner.value() != user_id {
+            return Err(format!("{} is already someone else's blog", domain).into());
+        }
+    }
+    Ok(())
+}
+
+async fn remove_domain(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
+    let _lock = lock_blog(db, builds, user_id).await?;
+    let mut settings = read_settings(db, user_id)?;
+    let posts = user_posts(db, user_id)?;
+    let old_settings = settings.clone();
+    let Some(domain) = settings.domain.take() else {
+        return Err("You don't have a custom domain".into());
+    };
+
+    let removed = (|| -> Result<(), Box<dyn std::error::Error>> {
+        bloggen::update_settings(&settings, &posts)?;
+        let txn = db.begin_write()?;
+        txn.open_table(DOMAIN_TO_USER_ID_TABLE)?
+            .remove(domain.as_str())?;
+        write_settings(&txn, user_id, &settings)?;
+        txn.commit()?;
+        Ok(())
+    })();
+    if let Err(e) = removed {
+        if let Err(e) = bloggen::update_settings(&old_settings, &posts) {
+            eprintln!("failed to restore settings for {}: {}", user_id, e);
+        }
+        return Err(e);
+    }
+    if let Err(e) = domains::remove_proxy_config(&domain) {
+        eprintln!("failed to remove proxy config for {}: {}", domain, e);
+    }
+    builds.enqueue(&settings.subdomain, user_id, Changes::All)?;
+
+    Ok(format!(
+        "Removed {}, your blog is back at {}",
+        domain,
+        settings.url()
+    ))
+}
 
+// Which blog a verified custom domain belongs to, if any.
+fn domain_subdomain(
+    db: &Database,
+    domain: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let txn = db.begin_read()?;
+    let Some(user_id) = txn.open_table(DOMAIN_TO_USER_ID_TABLE)?.get(domain)? else {
+        return Ok(None);
+    };
+    let subdomain = txn
+        .open_table(USER_ID_TO_SUBDOMAIN_TABLE)?
+        .get(&user_id.value())?
+        .map(|v| v.value().to_string());
     Ok(subdomain)
 }
+
+// Writes the blog's config with new settings and keeps them, then queues a
+// rebuild with them. The caller holds the blog's content lock, from before
+// it read the settings it changed.
+// Waits until nothing else is writing the user's blog, and holds it (see
+// BuildQueue::lock_content).
+async fn lock_blog(
+    db: &Database,
+    builds: &BuildQueue,
+    user_id: u64,
+) -> Result<tokio::sync::OwnedMutexGuard<()>, Box<dyn std::error::Error>> {
+    let subdomain = read_settings(db, user_id)?.subdomain;
+    Ok(builds.lock_content(&subdomain).await)
+}
+
+fn save_settings(
+    db: &Database,
+    builds: &BuildQueue,
+    user_id: u64,
+    settings: &bloggen::BlogSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    bloggen::update_settings(settings, &user_posts(db, user_id)?)?;
+
+    let txn = db.begin_write()?;
+    write_settings(&txn, user_id, settings)?;
+    txn.commit()?;
+
+    builds.enqueue(&settings.subdomain, user_id, Changes::All)?;
+    Ok(())
+}
+
+fn read_settings(
+    db: &Database,
+    user_id: u64,
+) -> Result<bloggen::BlogSettings, Box<dyn std::error::Error>> {
+    let txn = db.begin_read()?;
+    if let Some(v) = txn.open_table(USER_ID_TO_SETTINGS_TABLE)?.get(&user_id)? {
+        return Ok(serde_json::from_str(v.value())?);
+    }
+    match txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?.get(&user_id)? {
+        Some(v) => bloggen::BlogSettings::from_config(v.value()),
+        None => Err("You don't have a blog yet! Send me a SUBDOMAIN: line to make one.".into()),
+    }
+}
+
+fn write_settings(
+    txn: &WriteTransaction,
+    user_id: u64,
+    settings: &bloggen::BlogSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tbl = txn.open_table(USER_ID_TO_SETTINGS_TABLE)?;
+    tbl.insert(&user_id, serde_json::to_string(settings)?.as_str())?;
+    Ok(())
+}
+
+// Lists every theme a blog can use, marking the one the sender's blog has.
+fn list_themes(db: &Database, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
+    let current = read_settings(db, msg.sender_id).ok().map(|s| s.theme);
+
+    let mut response = String::from("Themes you can use, with `THEME: name`:\n");
+    for theme in themes::installed(&themes::themes_root()?)? {
+        response.push_str(&format!("* **{}**", theme.name));
+        if current.as_deref() == Some(theme.name.as_str()) {
+            response.push_str(" (current)");
+        }
+        if !theme.description.is_empty() {
+            response.push_str(&format!(" - {}", theme.description));
+        }
+        if let Some(preview) = &theme.preview {
+            response.push_str(&format!(" ([preview]({}))", preview));
+        }
+        response.push('\n');
+    }
+    Ok(response)
+}
+
+struct UserPage {
+    slug: String,
+    title: String,
+}
+
+// The standalone pages (rather than posts) the user has published.
+fn user_pages(db: &Database, user_id: u64) -> Result<Vec<UserPage>, Box<dyn std::error::Error>> {
+    let mut pages = Vec::new();
+    for (_, record) in user_posts(db, user_id)? {
+        if record.page.is_none() {
+            continue;
+        }
+        let Some(slug) = record.slug() else {
+            continue;
+        };
+        pages.push(UserPage {
+            slug: slug.slug,
+            title: record.title,
+        });
+    }
+    pages.sort_by(|a, b| a.slug.cmp(&b.slug));
+    Ok(pages)
+}
+
+// Lists the sender's standalone pages, and which ones are in the menu.
+fn list_pages(db: &Database, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
+    let settings = read_settings(db, msg.sender_id)?;
+    let pages = user_pages(db, msg.sender_id)?;
+    if pages.is_empty() {
+        return Ok(
+            "You don't have any pages yet! Add a `PAGE: about` line to the top of a post to make one."
+                .to_string(),
+        );
+    }
+
+    let mut response = String::new();
+    for page in pages {
+        response.push_str(&format!(
+            "* [{}]({}/{}/) (`{}`){}\n",
+            page.title,
+            settings.url(),
+            page.slug,
+            page.slug,
+            if settings.menu.contains(&page.slug) {
+                " - in the menu"
+            } else {
+                ""
+            }
+        ));
+    }
+    response.push_str("\nChoose which ones go in the menu with `MENU: about, projects`");
+    Ok(response)
+}
+
+const POSTS_PER_PAGE: usize = 20;
+
+// Lists the sender's posts and pages, newest first, a page at a time.
+fn list_posts(
+    db: &Database,
+    msg: &Message,
+    args: &[&str],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let before = match args {
+        [] => None,
+        ["before", name] => {
+            let (post_id, record) = find_post(db, msg.sender_id, name)?;
+            Some(index_key(post_id, &record))
+        }
+        _ => return Err("Try `posts`, or `posts before my-post` for older ones".into()),
+    };
+    let settings = read_settings(db, msg.sender_id)?;
+    let (posts, more) = user_posts_page(db, msg.sender_id, before, POSTS_PER_PAGE)?;
+    if posts.is_empty() {
+        if before.is_none() {
+            return Ok("You haven't published anything yet!".to_string());
+        }
+        return Ok("There's nothing older than that.".to_string());
+    }
+
+    let mut response = String::new();
+    let last = posts
+        .last()
+        .map(|(post_id, record)| post_name(*post_id, record));
+    for (post_id, record) in posts {
+        let when = record
+            .timestamp
+            .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+            .map(|t| format!(" - {}", t.format("%Y-%m-%d")))
+            .unwrap_or_default();
+        let mut notes = Vec::new();
+        if record.draft {
+            notes.push("draft");
+        }
+        if record.page.is_some() {
+            notes.push("page");
+        }
+        response.push_str(&format!(
+            "* [{}]({}/{}/){}{}\n",
+            record.title,
+            settings.url(),
+            post_name(post_id, &record),
+            when,
+            if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            }
+        ));
+    }
+    if let (true, Some(last)) = (more, last) {
+        response.push_str(&format!("\nDM `posts before {}` for older ones.", last));
+    }
+    Ok(response)
+}
+
+// Mentions (and edits to them) publish a post or page, or set the homepage
+// intro if they start with #homepage.
+async fn handle_mention(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    verb: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(intro) = bloggen::homepage_intro(&msg.content) {
+        return set_intro(db, builds, msg, intro).await;
+    }
+    Ok(add_post(db, builds, msg).await?.response(verb))
+}
+
+async fn set_intro(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+    intro: String,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
+    bloggen::download_intro_images(&intro).await?;
+    let _lock = lock_blog(db, builds, user_id).await?;
+    let mut settings = read_settings(db, user_id)?;
+    settings.intro = intro;
+    save_settings(db, builds, user_id, &settings)?;
+    Ok(format!(
+        "Homepage updated successfully! It'll be live at {} in a moment.",
+        settings.url()
+    ))
+}
+
+// What the mention handlers tell the author once something is published
+struct Published {
+    url: String,
+    // "Post" or "Page"
+    kind: &'static str,
+    warnings: Vec<String>,
+}
+
+impl Published {
+    fn response(&self, verb: &str) -> String {
+        with_warnings(
+            format!(
+                "{} {} successfully! It'll be live at {} in a moment.",
+                self.kind, verb, self.url
+            ),
+            &self.warnings,
+        )
+    }
+}
+
+async fn add_post(
+    db: &Database,
+    builds: &BuildQueue,
+    msg: &Message,
+) -> Result<Published, Box<dyn std::error::Error>> {
+    // assuming a blog is created, publish a post!
+    // in markdown at file: user_content/{sender_id}/{id}.md
+    // takes post_title from top of md file, demarcated by #
+
+    let user_id = msg.sender_id;
+    let message_id = msg.id;
+    let _lock = lock_blog(db, builds, user_id).await?;
+
+    // Work out where the post would go without keeping anything, and check
+    // that the blog still builds with it there. Only then is that record
+    // saved and written into the blog, so a post that breaks the build
+    // leaves both the DB and the blog as they were.
+    let txn = db.begin_write()?;
+    let (subdomain, record) = new_record(&txn, msg)?;
+    txn.abort()?;
+    let mut posts = user_posts(db, user_id)?;
+    match posts.iter_mut().find(|(post_id, _)| *post_id == message_id) {
+        Some((_, old)) => *old = record.clone(),
+        None => posts.push((message_id, record.clone())),
+    }
+    let warnings = bloggen::check_post(builds, &subdomain, message_id, &record, &posts).await?;
+
+    let txn = db.begin_write()?;
+    save_record(&txn, message_id, &record)?;
+    let slug = record.slug().map_or(message_id.to_string(), |s| s.slug);
+    let url = format!("{}/{}/", read_settings(db, user_id)?.url(), slug);
+    let previous = bloggen::add_post(&subdomain, message_id, &record)?;
+    if let Err(e) = txn.commit() {
+        previous.restore()?;
+        return Err(e.into());
+    }
+
+    // The feed goes in with the post, now that both have been checked
+    feed::write_json_feed(&paths::blog(&subdomain)?, &user_posts(db, user_id)?)?;
+
+    let kind = if record.page.is_some() {
+        "Page"
+    } else {
+        "Post"
+    };
+    builds.enqueue(&subdomain, user_id, Changes::post(message_id))?;
+
+    Ok(Published {
+        url,
+        kind,
+        warnings,
+    })
+}
+
+// Works out the post's record, including the slug it would get, along with
+// the author's subdomain. Picking the slug writes to `txn`, but nothing else
+// does.
+fn new_record(
+    txn: &WriteTransaction,
+    msg: &Message,
+) -> Result<(String, posts::PostRecord), Box<dyn std::error::Error>> {
+    let user_id = msg.sender_id;
+    let message_id = msg.id;
+
+    let desired_slug = bloggen::desired_slug(&msg.content, msg.timestamp);
+
+    let t3 = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE)?;
+
+    let previous = read_record(&txn.open_table(POST_ID_TO_RECORD_TABLE)?, message_id)?;
+
+    let slug = assign_slug(txn, user_id, message_id, desired_slug, previous.is_some())?;
+
+    let mut slugs = vec![slug.slug.clone()];
+    slugs.extend(slug.aliases.iter().cloned());
+    let record = posts::PostRecord::new(msg, slugs, previous.as_ref());
+
+    let subdomain = {
+        match t3.get(&user_id)? {
+            Some(v) => String::from(v.value()),
+            None => "".to_string(),
+        }
+    };
+    Ok((subdomain, record))
+}
+
+// Saves a record from `new_record` as it is, slugs and all. Fails if one of
+// its slugs has gone to another post since it was worked out.
+fn save_record(
+    txn: &WriteTransaction,
+    post_id: u64,
+    record: &posts::PostRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    {
+        let mut owners_tbl = txn.open_table(USER_SLUG_TO_POST_ID_TABLE)?;
+        for slug in &record.slugs {
+            if let Some(owner) = owners_tbl.get((record.user_id, slug.as_str()))? {
+                if owner.value() != post_id {
+                    return Err(format!(
+                        "Another post took /{}/ while I was checking this one. Send it again and I'll find it a new URL.",
+                        slug
+                    )
+                    .into());
+                }
+            }
+        }
+        for slug in &record.slugs {
+            owners_tbl.insert((record.user_id, slug.as_str()), &post_id)?;
+        }
+        txn.open_table(POST_ID_TO_SLUGS_TABLE)?
+            .insert(&post_id, &record.slugs)?;
+    }
+    write_record(txn, post_id, record)?;
+    add_revision(txn, post_id, record)?;
+    Ok(())
+}
+
+fn read_record(
+    tbl: &impl ReadableTable<u64, &'static str>,
+    post_id: u64,
+) -> Result<Option<posts::PostRecord>, Box<dyn std::error::Error>> {
+    match tbl.get(&post_id)? {
+        Some(v) => Ok(Some(serde_json::from_str(v.value())?)),
+        None => Ok(None),
+    }
+}
+
+// Saves a post's record, and keeps its place in its author's list of posts.
+fn write_record(
+    txn: &WriteTransaction,
+    post_id: u64,
+    record: &posts::PostRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut records_tbl = txn.open_table(POST_ID_TO_RECORD_TABLE)?;
+    let mut index_tbl = txn.open_table(USER_POSTS_TABLE)?;
+    // When it was sent can go from unknown to known
+    if let Some(previous) = read_record(&records_tbl, post_id)? {
+        index_tbl.remove(index_key(post_id, &previous))?;
+    }
+    index_tbl.insert(index_key(post_id, record), ())?;
+    records_tbl.insert(&post_id, serde_json::to_string(record)?.as_str())?;
+    Ok(())
+}
+
+fn index_key(post_id: u64, record: &posts::PostRecord) -> (u64, u64, u64) {
+    (record.user_id, record.timestamp.unwrap_or(0), post_id)
+}
+
+// Keeps the post as it is now as its next revision, unless that's what the
+// last one already has.
+fn add_revision(
+    txn: &WriteTransaction,
+    post_id: u64,
+    record: &posts::PostRecord,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut tbl = txn.open_table(POST_REV_TO_REVISION_TABLE)?;
+    let last = match tbl.range((post_id, 0)..=(post_id, u64::MAX))?.next_back() {
+        Some(entry) => {
+            let (key, value) = entry?;
+            let revision: posts::Revision = serde_json::from_str(value.value())?;
+            Some((key.value().1, revision))
+        }
+        None => None,
+    };
+    if let Some((_, revision)) = &last {
+        if revision.content == record.content {
+            return Ok(());
+        }
+    }
+    let rev = last.map_or(1, |(rev, _)| rev + 1);
+    let revision = serde_json::to_string(&posts::Revision::of(record))?;
+    tbl.insert((post_id, rev), revision.as_str())?;
+    Ok(())
+}
+
+// Every revision of a post, oldest first.
+fn revisions(
+    db: &Database,
+    post_id: u64,
+) -> Result<Vec<(u64, posts::Revision)>, Box<dyn std::error::Error>> {
+    let txn = db.begin_read()?;
+    let tbl = txn.open_table(POST_REV_TO_REVISION_TABLE)?;
+    let mut revisions = Vec::new();
+    for entry in tbl.range((post_id, 0)..=(post_id, u64::MAX))? {
+        let (key, value) = entry?;
+        revisions.push((key.value().1, serde_json::from_str(value.value())?));
+    }
+    Ok(revisions)
+}
+
+// Posts along with their IDs
+type PostRecords = Vec<(u64, posts::PostRecord)>;
+
+// Everything the user has published, in the order it was published.
+fn user_posts(db: &Database, user_id: u64) -> Result<PostRecords, Box<dyn std::error::Error>> {
+    let post_ids = user_post_ids(db, user_id)?;
+    read_records(db, &post_ids)
+}
+
+// One page of what the user has published, newest first, along with whether
+// there are older posts after it. The page starts after `before`, the last
+// post on the page before (as its place in USER_POSTS_TABLE), so only the
+// page's own rows get read.
+fn user_posts_page(
+    db: &Database,
+    user_id: u64,
+    before: Option<(u64, u64, u64)>,
+    per_page: usize,
+) -> Result<(PostRecords, bool), Box<dyn std::error::Error>> {
+    use std::ops::Bound;
+
+    let end = match before {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Included((user_id, u64::MAX, u64::MAX)),
+    };
+    let mut post_ids = Vec::new();
+    {
+        let txn = db.begin_read()?;
+        let index_tbl = txn.open_table(USER_POSTS_TABLE)?;
+        let range = index_tbl.range::<(u64, u64, u64)>((Bound::Included((user_id, 0, 0)), end))?;
+        // One more than fits, to tell whether there's another page
+        for entry in range.rev().take(per_page + 1) {
+            post_ids.push(entry?.0.value().2);
+        }
+    }
+    let more = post_ids.len() > per_page;
+    post_ids.truncate(per_page);
+    Ok((read_records(db, &post_ids)?, more))
+}
+
+fn user_post_ids(db: &Database, user_id: u64) -> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    let txn = db.begin_read()?;
+    let index_tbl = txn.open_table(USER_POSTS_TABLE)?;
+    let mut post_ids = Vec::new();
+    for entry in index_tbl.range((user_id, 0, 0)..=(user_id, u64::MAX, u64::MAX))? {
+        post_ids.push(entry?.0.value().2);
+    }
+    Ok(post_ids)
+}
+
+fn read_records(
+    db: &Database,
+    post_ids: &[u64],
+) -> Result<PostRecords, Box<dyn std::error::Error>> {
+    let txn = db.begin_read()?;
+    let records_tbl = txn.open_table(POST_ID_TO_RECORD_TABLE)?;
+    let mut posts = Vec::with_capacity(post_ids.len());
+    for post_id in post_ids {
+        if let Some(record) = read_record(&records_tbl, *post_id)? {
+            posts.push((*post_id, record));
+        }
+    }
+    Ok(posts)
+}
+
+// Picks a slug for a post that's unique across the author's blog, by adding a
+// numeric suffix if needed. If the post already had a different slug, the old
+// one is kept around as an alias so existing links don't break.
+fn assign_slug(
+    txn: &WriteTransaction,
+    user_id: u64,
+    post_id: u64,
+    desired: String,
+    already_published: bool,
+) -> Result<bloggen::PostSlug, Box<dyn std::error::Error>> {
+    let mut slugs_tbl = txn.open_table(POST_ID_TO_SLUGS_TABLE)?;
+    let mut owners_tbl = txn.open_table(USER_SLUG_TO_POST_ID_TABLE)?;
+
+    let mut slugs = match slugs_tbl.get(&post_id)? {
+        Some(v) => v.value(),
+        // Posts from before slugs existed were served at /{post_id}/
+        None if already_published => vec![post_id.to_string()],
+        None => vec![],
+    };
+
+    let base = if desired.is_empty() {
+        post_id.to_string()
+    } else {
+        desired
+    };
+    let mut slug = base.clone();
+    let mut n = 1;
+    loop {
+        let taken = RESERVED_SLUGS.contains(&slug.as_str())
+            || match owners_tbl.get((user_id, slug.as_str()))? {
+                Some(v) => v.value() != post_id,
+                None => false,
+            };
+        if !taken {
+            break;
+        }
+        n += 1;
+        slug = format!("{}-{}", base, n);
+    }
+
+    slugs.retain(|s| *s != slug);
+    slugs.insert(0, slug.clone());
+    for s in &slugs {
+        owners_tbl.insert((user_id, s.as_str()), &post_id)?;
+    }
+    slugs_tbl.insert(&post_id, &slugs)?;
+
+    Ok(post_slug(slugs))
+}
+
+fn post_slug(mut slugs: Vec<String>) -> bloggen::PostSlug {
+    let slug = if slugs.is_empty() {
+        String::new()
+    } else {
+        slugs.remove(0)
+    };
+    bloggen::PostSlug {
+        slug,
+        aliases: slugs,
+    }
+}
+
+// Appends anything the author should know about (e.g. metadata we couldn't
+// understand) to a response.
+fn with_warnings(msg: String, warnings: &[String]) -> String {
+    if warnings.is_empty() {
+        return msg;
+    }
+    let mut msg = msg;
+    msg.push_str("\n\nHeads up:");
+    for w in warnings {
+        msg.push_str("\n* ");
+        msg.push_str(w);
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_db() -> (tempfile::TempDir, Database) {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::create(tmp.path().join("test.db")).unwrap();
+        (tmp, db)
+    }
+
+    fn slug_for(db: &Database, user_id: u64, post_id: u64, desired: &str) -> bloggen::PostSlug {
+        slug_for_existing(db, user_id, post_id, desired, false)
+    }
+
+    fn slug_for_existing(
+        db: &Database,
+        user_id: u64,
+        post_id: u64,
+        desired: &str,
+        already_published: bool,
+    ) -> bloggen::PostSlug {
+        let txn = db.begin_write().unwrap();
+        let slug = assign_slug(
+            &txn,
+            user_id,
+            post_id,
+            desired.to_string(),
+            already_published,
+        )
+        .unwrap();
+        txn.commit().unwrap();
+        slug
+    }
+
+    fn test_message(sender_id: u64, content: &str) -> Message {
+        Message {
+            content: content.to_string(),
+            id: 1,
+            sender_id,
+            stream_id: None,
+            timestamp: 0,
+            subject: String::new(),
+            sender_full_name: "Test User".to_string(),
+            last_edit_timestamp: None,
+        }
+    }
+
+    // Records a post the way add_post does, without checking it builds.
+    fn record_post(
+        txn: &WriteTransaction,
+        msg: &Message,
+    ) -> Result<(String, posts::PostRecord), Box<dyn std::error::Error>> {
+        let (subdomain, record) = new_record(txn, msg)?;
+        save_record(txn, msg.id, &record)?;
+        Ok((subdomain, record))
+    }
+
+    fn record_posts(db: &Database, user_id: u64, posts: &[(u64, &str)]) {
+        let txn = db.begin_write().unwrap();
+        for (post_id, content) in posts {
+            let msg = Message {
+                id: *post_id,
+                ..test_message(user_id, content)
+            };
+            record_post(&txn, &msg).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+
+    #[test]
+    fn test_list_tags_counts_published_posts() {
+        let (_tmp, db) = test_db();
+        let txn = db.begin_write().unwrap();
+        write_settings(&txn, 1, &bloggen::BlogSettings::new("myblog")).unwrap();
+        txn.commit().unwrap();
+        // 100 gets edited, and only counts once
+        record_posts(
+            &db,
+            1,
+            &[
+                (100, "TAGS: rust\n\nBody"),
+                (101, "TAGS: rust\n\nBody"),
+                (100, "TAGS: rust, zulip\nCATEGORIES: Code\n\nBody"),
+                (102, "TAGS: secret\nDRAFT: true\n\nBody"),
+            ],
+        );
+
+        let response = list_tags(&db, &test_message(1, "tags")).unwrap();
+        assert!(response.contains("[rust](https://myblog.hypertxt.io/tags/rust/) (2 posts)"));
+        assert!(response.contains("[zulip](https://myblog.hypertxt.io/tags/zulip/) (1 post)"));
+        assert!(response.contains("[Code](https://myblog.hypertxt.io/categories/code/) (1 post)"));
+        assert!(!response.contains("secret"));
+    }
+
+    #[test]
+    fn test_list_pages() {
+        let (_tmp, db) = test_db();
+        let settings = bloggen::BlogSettings {
+            menu: vec!["about".to_string()],
+            ..bloggen::BlogSettings::new("myblog")
+        };
+        let txn = db.begin_write().unwrap();
+        write_settings(&txn, 1, &settings).unwrap();
+        txn.commit().unwrap();
+        record_posts(
+            &db,
+            1,
+            &[
+                (100, "TITLE: A post\n\nBody"),
+                (101, "PAGE: about\n\n# About me\n\nBody"),
+                (102, "PAGE: now\n\nBody"),
+                (101, "PAGE: about\n\n# About me\n\nBody"),
+            ],
+        );
+
+        let pages = user_pages(&db, 1).unwrap();
+        let slugs: Vec<_> = pages.iter().map(|p| p.slug.as_str()).collect();
+        assert_eq!(slugs, vec!["about", "now"]);
+
+        let response = list_pages(&db, &test_message(1, "pages")).unwrap();
+        assert!(response
+            .contains("* [About me](https://myblog.hypertxt.io/about/) (`about`) - in the menu\n"));
+        assert!(response.contains("(https://myblog.hypertxt.io/now/) (`now`)\n"));
+        assert!(!response.contains("a-post"));
+    }
+
+    #[test]
+    fn test_saves_the_record_that_was_checked() {
+        let (_tmp, db) = test_db();
+        let msg = Message {
+            id: 100,
+            ..test_message(1, "# Hello\n\nBody")
+        };
+        let txn = db.begin_write().unwrap();
+        let (_, checked) = new_record(&txn, &msg).unwrap();
+        txn.abort().unwrap();
+        assert_eq!(checked.slugs, vec!["hello"]);
+
+        // Another post gets there first while it's being checked
+        record_posts(&db, 1, &[(101, "# Hello\n\nSomething else")]);
+        let txn = db.begin_write().unwrap();
+        let err = save_record(&txn, 100, &checked).unwrap_err();
+        assert!(err.to_string().contains("/hello/"), "{}", err);
+        txn.abort().unwrap();
+        assert_eq!(user_post_ids(&db, 1).unwrap(), vec![101]);
+
+        // Otherwise it's saved just as it was checked
+        let msg = Message {
+            id: 102,
+            ..test_message(1, "# Hi\n\nBody")
+        };
+        let txn = db.begin_write().unwrap();
+        let (_, checked) = new_record(&txn, &msg).unwrap();
+        txn.abort().unwrap();
+        let txn = db.begin_write().unwrap();
+        save_record(&txn, 102, &checked).unwrap();
+        txn.commit().unwrap();
+        let posts = user_posts(&db, 1).unwrap();
+        assert_eq!(posts.last().unwrap(), &(102, checked));
+    }
+
+    #[test]
+    fn test_record_post_keeps_history() {
+        let (_tmp, db) = test_db();
+        record_posts(&db, 1, &[(100, "# First title\n\nBody")]);
+        let edit = Message {
+            id: 100,
+            last_edit_timestamp: Some(500),
+            ..test_message(1, "# Second title\n\nBody")
+        };
+        let txn = db.begin_write().unwrap();
+        record_post(&txn, &edit).unwrap();
+        txn.commit().unwrap();
+
+        let posts = user_posts(&db, 1).unwrap();
+        assert_eq!(posts.len(), 1);
+        let record = &posts[0].1;
+        assert_eq!(record.title, "Second title");
+        assert_eq!(record.slugs, vec!["second-title", "first-title"]);
+        assert_eq!(record.edited_at, vec![500]);
+        assert_eq!(record.author, "Test User");
+    }
+
+    #[test]
+    fn test_posts_are_listed_once_by_publish_time() {
+        let (_tmp, db) = test_db();
+        let txn = db.begin_write().unwrap();
+        write_settings(&txn, 1, &bloggen::BlogSettings::new("myblog")).unwrap();
+        for (post_id, timestamp, content) in [
+            (100, 1_700_000_200, "# Newest"),
+            (101, 1_700_000_000, "# Oldest"),
+            (102, 1_700_000_100, "PAGE: about\n\n# About"),
+            // An edit keeps the post where it was
+            (101, 1_700_000_000, "# Oldest, edited"),
+        ] {
+            let msg = Message {
+                id: post_id,
+                timestamp,
+                ..test_message(1, content)
+            };
+            record_post(&txn, &msg).unwrap();
+        }
+        txn.commit().unwrap();
+        record_posts(&db, 2, &[(200, "# Someone else's")]);
+
+        let ids: Vec<u64> = user_posts(&db, 1)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![101, 102, 100]);
+
+        let (page, more) = user_posts_page(&db, 1, None, 2).unwrap();
+        assert!(more);
+        let titles: Vec<_> = page.iter().map(|(_, r)| r.title.as_str()).collect();
+        assert_eq!(titles, vec!["Newest", "About"]);
+        let (id, record) = &page[1];
+        let (page, more) = user_posts_page(&db, 1, Some(index_key(*id, record)), 2).unwrap();
+        assert!(!more);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].1.title, "Oldest, edited");
+        let (id, record) = &page[0];
+        let (page, more) = user_posts_page(&db, 1, Some(index_key(*id, record)), 2).unwrap();
+        assert!(page.is_empty() && !more);
+
+        let response = list_posts(&db, &test_message(1, "posts"), &[]).unwrap();
+        assert_eq!(
+            response,
+            "* [Newest](https://myblog.hypertxt.io/newest/) - 2023-11-14\n\
+             * [About](https://myblog.hypertxt.io/about/) - 2023-11-14 (page)\n\
+             * [Oldest, edited](https://myblog.hypertxt.io/oldest-edited/) - 2023-11-14\n"
+        );
+        let older = list_posts(&db, &test_message(1, ""), &["before", "about"]).unwrap();
+        assert_eq!(
+            older,
+            "* [Oldest, edited](https://myblog.hypertxt.io/oldest-edited/) - 2023-11-14\n"
+        );
+        assert!(list_posts(&db, &test_message(1, ""), &["2"]).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_summary() {
+        let (_tmp, db) = test_db();
+        let txn = db.begin_write().unwrap();
+        let mut tbl = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE).unwrap();
+        tbl.insert(&2, "bob").unwrap();
+        tbl.insert(&1, "alice").unwrap();
+        drop(tbl);
+        txn.commit().unwrap();
+        assert_eq!(
+            all_blogs(&db).unwrap(),
+            vec![(1, "alice".to_string()), (2, "bob".to_string())]
+        );
+
+        assert_eq!(rebuild_summary(2, vec![]), "Rebuilt all 2 blogs!");
+        let failures = vec![
+            ("carol".to_string(), "bad template".to_string()),
+            ("bob".to_string(), "it took too long".to_string()),
+        ];
+        assert_eq!(
+            rebuild_summary(3, failures),
+            "Rebuilt 1 of 3 blogs. These ones failed:\n\
+             * **bob**: it took too long\n\
+             * **carol**: bad template\n"
+        );
+    }
+
+    #[test]
+    fn test_expected_on_disk() {
+        let (_tmp, db) = test_db();
+        let txn = db.begin_write().unwrap();
+        let mut tbl = txn.open_table(USER_ID_TO_SUBDOMAIN_TABLE).unwrap();
+        tbl.insert(&1, "alice").unwrap();
+        tbl.insert(&2, "bob").unwrap();
+        drop(tbl);
+        txn.open_table(SUBDOMAIN_TO_USER_ID_TABLE)
+            .unwrap()
+            .insert("alice", &1)
+            .unwrap();
+        let settings = bloggen::BlogSettings {
+            intro: "Hi ![me](/user_uploads/1/aa/me.png)".to_string(),
+            ..bloggen::BlogSettings::new("alice")
+        };
+        write_settings(&txn, 1, &settings).unwrap();
+        txn.commit().unwrap();
+        record_posts(
+            &db,
+            1,
+            &[
+                (100, "# One\n\n![](/user_uploads/1/bb/old.png)"),
+                (100, "# One\n\n![](/user_uploads/1/cc/new.png)"),
+                (101, "PAGE: about\n\n# About"),
+            ],
+        );
+
+        let (expected, problems) = expected_on_disk(&db).unwrap();
+        assert_eq!(
+            problems,
+            vec![fsck::Problem::UnmappedSubdomain {
+                user_id: 2,
+                subdomain: "bob".to_string()
+            }]
+        );
+        assert_eq!(
+            expected.blogs["alice"],
+            BTreeMap::from([(100, false), (101, true)])
+        );
+        assert!(expected.blogs["bob"].is_empty());
+        let mut uploads = expected.uploads;
+        uploads.sort();
+        uploads.dedup();
+        assert_eq!(
+            uploads,
+            vec![
+                "/user_uploads/1/aa/me.png",
+                "/user_uploads/1/bb/old.png",
+                "/user_uploads/1/cc/new.png"
+            ]
+        );
+
+        map_subdomain(&db, 2, "bob").unwrap();
+        assert!(expected_on_disk(&db).unwrap().1.is_empty());
+        assert!(map_subdomain(&db, 1, "bob").is_err());
+    }
+
+    #[test]
+    fn test_revisions() {
+        let (_tmp, db) = test_db();
+        record_posts(
+            &db,
+            1,
+            &[
+                (100, "# Hello\n\nOne\nTwo"),
+                // Moving the message isn't a new revision
+                (100, "# Hello\n\nOne\nTwo"),
+                (100, "# Hello again\n\nOne\nThree"),
+            ],
+        );
+        let revisions = revisions(&db, 100).unwrap();
+        let titles: Vec<_> = revisions
+            .iter()
+            .map(|(r, v)| (*r, v.title.as_str()))
+            .collect();
+        assert_eq!(titles, vec![(1, "Hello"), (2, "Hello again")]);
+
+        let history = post_history(&db, &test_message(1, ""), "hello-again").unwrap();
+        assert!(history.contains("* **1** - 1970-01-01 00:00 UTC - Hello\n"));
+        assert!(history.contains(" - Hello again (current)\n"));
+
+        // Old slugs, URLs and message IDs all find the post
+        for name in ["hello", "https://myblog.hypertxt.io/hello-again/", "100"] {
+            let diff = diff_revision(&db, &test_message(1, ""), name, 1).unwrap();
+            assert!(diff.contains("-Two\n+Three\n"), "{}", diff);
+        }
+        let same = diff_revision(&db, &test_message(1, ""), "hello-again", 2).unwrap();
+        assert!(same.contains("is the same now"));
+
+        assert!(diff_revision(&db, &test_message(1, ""), "hello-again", 3).is_err());
+        // Other people's posts aren't found
+        assert!(post_history(&db, &test_message(2, ""), "100").is_err());
+    }
+
+    #[test]
+    fn test_record_build() {
+        let (_tmp, db) = test_db();
+        record_posts(&db, 1, &[(100, "# One"), (101, "# Two")]);
+        let outcome = |result| builds::Outcome {
+            subdomain: "myblog".to_string(),
+            user_id: 1,
+            finished_at: chrono::Utc::now(),
+            result,
+            quiet: false,
+        };
+        let states = || -> Vec<posts::BuildState> {
+            user_posts(&db, 1)
+                .unwrap()
+                .into_iter()
+                .map(|(_, r)| r.build)
+                .collect()
+        };
+
+        let mut e = render::BuildError::new("bad shortcode");
+        e.file = Some("content/101.md".to_string());
+        record_build(&db, &outcome(Err(e))).unwrap();
+        assert_eq!(
+            states(),
+            vec![
+                posts::BuildState::Pending,
+                posts::BuildState::Failed {
+                    reason: "bad shortcode".to_string()
+                }
+            ]
+        );
+
+        record_build(&db, &outcome(Ok(()))).unwrap();
+        assert_eq!(states(), vec![posts::BuildState::Built; 2]);
+    }
+
+    #[test]
+    fn test_assign_slug_collisions() {
+        let (_tmp, db) = test_db();
+        assert_eq!(slug_for(&db, 1, 100, "hello").slug, "hello");
+        assert_eq!(slug_for(&db, 1, 101, "hello").slug, "hello-2");
+        assert_eq!(slug_for(&db, 1, 102, "hello").slug, "hello-3");
+        // Different blogs don't collide
+        assert_eq!(slug_for(&db, 2, 200, "hello").slug, "hello");
+    }
+
+    #[test]
+    fn test_assign_slug_stable_across_edits() {
+        let (_tmp, db) = test_db();
+        slug_for(&db, 1, 100, "hello");
+        assert_eq!(slug_for(&db, 1, 101, "hello").slug, "hello-2");
+        let again = slug_for(&db, 1, 101, "hello");
+        assert_eq!(again.slug, "hello-2");
+        assert!(again.aliases.is_empty());
+    }
+
+    #[test]
+    fn test_assign_slug_rename_keeps_alias() {
+        let (_tmp, db) = test_db();
+        slug_for(&db, 1, 100, "first-title");
+        let renamed = slug_for(&db, 1, 100, "second-title");
+        assert_eq!(renamed.slug, "second-title");
+        assert_eq!(renamed.aliases, vec!["first-title".to_string()]);
+
+        // The old slug stays claimed by the renamed post
+        assert_eq!(slug_for(&db, 1, 101, "first-title").slug, "first-title-2");
+
+        // Renaming back swaps them around
+        let back = slug_for(&db, 1, 100, "first-title");
+        assert_eq!(back.slug, "first-title");
+        assert_eq!(back.aliases, vec!["second-title".to_string()]);
+    }
+
+    #[test]
+    fn test_assign_slug_legacy_post_keeps_id_url() {
+        let (_tmp, db) = test_db();
+        let slug = slug_for_existing(&db, 1, 100, "hello", true);
+        assert_eq!(slug.slug, "hello");
+        assert_eq!(slug.aliases, vec!["100".to_string()]);
+    }
+
+    #[test]
+    fn test_assign_slug_fallbacks() {
+        let (_tmp, db) = test_db();
+        assert_eq!(slug_for(&db, 1, 100, "").slug, "100");
+        assert_eq!(slug_for(&db, 1, 101, "tags").slug, "tags-2");
+    }
+}
@@ -0,0 +1,248 @@
+use chrono::{DateTime, NaiveDate};
+
+// Metadata a post can set with `KEY: value` lines at the top of the message,
+// before any of the actual content. e.g.
+//
+//   TITLE: My post
+//   TAGS: rust, zulip
+//   DRAFT: true
+//
+//   Words! Things! Stuff!
+#[derive(Debug, Default, PartialEq)]
+pub struct PostMetadata {
+    pub title: Option<String>,
+    pub tags: Vec<String>,
+    pub categories: Vec<String>,
+    pub description: Option<String>,
+    pub slug: Option<String>,
+    pub date: Option<String>,
+    pub updated: Option<String>,
+    pub draft: bool,
+    pub summary: Option<String>,
+    // Publishes the message as a standalone page at /{page}/, rather than as
+    // a post
+    pub page: Option<String>,
+}
+
+pub struct ParsedPost {
+    pub metadata: PostMetadata,
+    // The message with the metadata block (and title, if any) removed
+    pub body: String,
+    // Things the author should know about, e.g. unknown keys we ignored
+    pub warnings: Vec<String>,
+}
+
+// Splits the metadata block off the top of a message. Every line in the block
+// looks like `KEY: value`, where KEY is all caps. The block ends at the first
+// line that doesn't look like that (blank lines are skipped). If there's no
+// TITLE in the block, the first `# Heading` (or a stray `TITLE:` line) in the
+// rest of the message is used instead, as before the block existed.
+pub fn parse_post(msg: &str) -> ParsedPost {
+    let mut metadata = PostMetadata::default();
+    let mut warnings = Vec::new();
+
+    let lines: Vec<&str> = msg.lines().collect();
+    let mut body_start = 0;
+    for (idx, line) in lines.iter().enumerate() {
+        if line.trim().is_empty() {
+            body_start = idx + 1;
+            continue;
+        }
+        let Some((key, value)) = metadata_line(line) else {
+            break;
+        };
+        if let Err(e) = metadata.set(key, value) {
+            warnings.push(e);
+        }
+        body_start = idx + 1;
+    }
+
+    let mut body_lines = lines[body_start.min(lines.len())..].to_vec();
+    if metadata.title.is_none() {
+        if let Some((title, idx)) = find_title(&body_lines) {
+            metadata.title = Some(title.to_string());
+            body_lines.remove(idx);
+        }
+    }
+
+    ParsedPost {
+        metadata,
+        body: body_lines.join("\n").trim().to_string(),
+        warnings,
+    }
+}
+
+fn metadata_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.trim_start().split_once(": ")?;
+    let is_key = !key.is_empty() && key.chars().all(|c| c.is_ascii_uppercase() || c == '_');
+    if !is_key {
+        return None;
+    }
+    Some((key, value.trim()))
+}
+
+fn find_title<'a>(lines: &[&'a str]) -> Option<(&'a str, usize)> {
+    for (idx, line) in lines.iter().enumerate() {
+        if let Some(title) = line.strip_prefix("# ") {
+            if !title.is_empty() {
+                return Some((title, idx));
+            }
+        }
+        if let Some(title) = line.strip_prefix("TITLE: ") {
+            if !title.is_empty() {
+                return Some((title, idx));
+            }
+        }
+    }
+    None
+}
+
+impl PostMetadata {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "TITLE" => self.title = non_empty(value),
+            "TAGS" => self.tags = split_list(value),
+            "CATEGORIES" => self.categories = split_list(value),
+            "DESCRIPTION" => self.description = non_empty(value),
+            "SLUG" => self.slug = non_empty(value),
+            "SUMMARY" => self.summary = non_empty(value),
+            "PAGE" => self.page = non_empty(value),
+            "DATE" => self.date = Some(parse_date(key, value)?),
+            "UPDATED" => self.updated = Some(parse_date(key, value)?),
+            "DRAFT" => {
+                self.draft = match value.to_ascii_lowercase().as_str() {
+                    "true" | "yes" => true,
+                    "false" | "no" => false,
+                    _ => return Err(format!("DRAFT should be true or false, not {:?}", value)),
+                }
+            }
+            _ => return Err(format!("Ignored unknown metadata key {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// Zola accepts either a plain date or a full RFC 3339 datetime.
+fn parse_date(key: &str, value: &str) -> Result<String, String> {
+    if NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+        || DateTime::parse_from_rfc3339(value).is_ok()
+    {
+        return Ok(value.to_string());
+    }
+    Err(format!(
+        "{} should look like 2024-11-20 or 2024-11-20T15:00:00Z, not {:?}",
+        key, value
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_full_metadata_block() {
+        let msg = r#"
+TITLE: My "great" post
+TAGS: rust, zulip , ,blogging
+CATEGORIES: Programming
+DESCRIPTION: A post about things
+SLUG: my-great-post
+DATE: 2024-11-20
+UPDATED: 2024-11-21T10:00:00Z
+DRAFT: yes
+SUMMARY: The short version
+
+Words! Things! Stuff!
+"#;
+        let parsed = parse_post(msg);
+        assert_eq!(
+            parsed.metadata,
+            PostMetadata {
+                title: Some(r#"My "great" post"#.to_string()),
+                tags: vec!["rust".into(), "zulip".into(), "blogging".into()],
+                categories: vec!["Programming".into()],
+                description: Some("A post about things".into()),
+                slug: Some("my-great-post".into()),
+                date: Some("2024-11-20".into()),
+                updated: Some("2024-11-21T10:00:00Z".into()),
+                draft: true,
+                summary: Some("The short version".into()),
+                page: None,
+            }
+        );
+        assert_eq!(parsed.body, "Words! Things! Stuff!");
+        assert!(parsed.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_page() {
+        let parsed = parse_post("PAGE: about\n\n# About me\n\nHi!");
+        assert_eq!(parsed.metadata.page, Some("about".to_string()));
+        assert_eq!(parsed.metadata.title, Some("About me".to_string()));
+        assert_eq!(parsed.body, "Hi!");
+    }
+
+    #[test]
+    fn test_heading_title_fallback() {
+        let parsed = parse_post("TAGS: a\n\n# The Title\n\nBody text");
+        assert_eq!(parsed.metadata.title, Some("The Title".to_string()));
+        assert_eq!(parsed.metadata.tags, vec!["a".to_string()]);
+        assert_eq!(parsed.body, "Body text");
+    }
+
+    #[test]
+    fn test_block_ends_at_content() {
+        let parsed = parse_post("TITLE: A\nSome text\nTAGS: not, metadata");
+        assert_eq!(parsed.metadata.title, Some("A".to_string()));
+        assert!(parsed.metadata.tags.is_empty());
+        assert_eq!(parsed.body, "Some text\nTAGS: not, metadata");
+    }
+
+    #[test]
+    fn test_prose_with_colon_isnt_metadata() {
+        let parsed = parse_post("Note: this is just text\nMore text");
+        assert_eq!(parsed.metadata, PostMetadata::default());
+        assert_eq!(parsed.body, "Note: this is just text\nMore text");
+    }
+
+    #[test]
+    fn test_unknown_keys_warn_and_are_stripped() {
+        let parsed = parse_post("TITLE: A\nAUTHOR: Someone\n\nBody");
+        assert_eq!(parsed.body, "Body");
+        assert_eq!(parsed.warnings.len(), 1);
+        assert!(parsed.warnings[0].contains("AUTHOR"));
+    }
+
+    #[test]
+    fn test_invalid_values_warn() {
+        let parsed = parse_post("DATE: yesterday\nDRAFT: maybe\n\nBody");
+        assert_eq!(parsed.metadata.date, None);
+        assert!(!parsed.metadata.draft);
+        assert_eq!(parsed.warnings.len(), 2);
+        assert_eq!(parsed.body, "Body");
+    }
+
+    #[test]
+    fn test_no_metadata() {
+        let parsed = parse_post("\n\nJust a body\n");
+        assert_eq!(parsed.metadata, PostMetadata::default());
+        assert_eq!(parsed.body, "Just a body");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    env, fs,
+    path::{Component, Path, PathBuf},
+};
+
+// A directory that everything we write lives under, e.g. BLOG_ROOT or
+// STATIC_ROOT. Paths built from user input (subdomains, upload URLs, etc) are
+// only ever joined onto a Root, which refuses anything that would end up
+// outside of it, whether via `..`, absolute paths or symlinks.
+#[derive(Debug, Clone)]
+pub struct Root {
+    dir: PathBuf,
+}
+
+impl Root {
+    // The directory must already exist. It's canonicalized so that later
+    // containment checks compare like with like.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Root, String> {
+        let dir = dir.as_ref();
+        let dir = dir
+            .canonicalize()
+            .map_err(|e| format!("failed to canonicalize root {:?}: {:?}", dir, e))?;
+        Ok(Root { dir })
+    }
+
+    // Like `new`, but creates the directory first if it doesn't exist yet.
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Root, String> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|e| format!("failed to create root {:?}: {:?}", dir, e))?;
+        Root::new(dir)
+    }
+
+    pub fn from_env(var: &str) -> Result<Root, String> {
+        let dir = env::var(var).map_err(|e| format!("failed to get {}: {:?}", var, e))?;
+        Root::create(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    // Joins a relative path onto the root, e.g. "content/123.md". Every
+    // component must be a plain name, and whatever part of the result already
+    // exists on disk must canonicalize to somewhere inside the root.
+    pub fn join<P: AsRef<Path>>(&self, rel: P) -> Result<PathBuf, String> {
+        let rel = rel.as_ref();
+        let mut joined = self.dir.clone();
+        for component in rel.components() {
+            match component {
+                Component::Normal(name) => joined.push(name),
+                Component::CurDir => {}
+                _ => return Err(format!("path {:?} escapes {:?}", rel, self.dir)),
+            }
+        }
+        if joined == self.dir {
+            return Err(format!(
+                "path {:?} doesn't name anything inside {:?}",
+                rel, self.dir
+            ));
+        }
+        self.check_contained(&joined)?;
+        Ok(joined)
+    }
+
+    // Like `join`, but for a single name, e.g. a subdomain. Anything with a
+    // separator in it is rejected rather than treated as a nested path.
+    pub fn child(&self, name: &str) -> Result<PathBuf, String> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(n)), None) if n == name => self.join(name),
+            _ => Err(format!("{:?} isn't a valid name", name)),
+        }
+    }
+
+    // Like `child`, but doesn't follow the entry if it's a symlink. That's for
+    // symlinks we made ourselves, like a blog's live site, which points at a
+    // build that's kept outside of STATIC_ROOT.
+    pub fn entry(&self, name: &str) -> Result<PathBuf, String> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(n)), None) if n == name => Ok(self.dir.join(name)),
+            _ => Err(format!("{:?} isn't a valid name", name)),
+        }
+    }
+
+    // Like `child`, but returns the result as a Root of its own, creating the
+    // directory if needed.
+    pub fn subroot(&self, name: &str) -> Result<Root, String> {
+        Root::create(self.child(name)?)
+    }
+
+    fn check_contained(&self, path: &Path) -> Result<(), String> {
+        // Walk up to the deepest part of the path that exists, since that's
+        // the only bit that can be resolved (and hence hide a symlink).
+        let mut existing = path;
+        while fs::symlink_metadata(existing).is_err() {
+            existing = match existing.parent() {
+                Some(p) => p,
+                None => break,
+            };
+        }
+        let resolved = existing
+            .canonicalize()
+            .map_err(|e| format!("failed to canonicalize {:?}: {:?}", existing, e))?;
+        if !resolved.starts_with(&self.dir) {
+            return Err(format!("path {:?} escapes {:?}", path, self.dir));
+        }
+        Ok(())
+    }
+}
+
+// Where each blog's Zola project lives, e.g. BLOG_ROOT/{subdomain}.
+pub fn blog_dir(subdomain: &str) -> Result<PathBuf, String> {
+    Root::from_env("BLOG_ROOT")?.child(subdomain)
+}
+
+// The Zola project for a blog that has already been created.
+pub fn blog(subdomain: &str) -> Result<Root, String> {
+    Root::new(blog_dir(subdomain)?)
+}
+
+// Where each blog's built site is served from, e.g. STATIC_ROOT/{subdomain}.
+// It's a symlink to the live build (see deploys).
+pub fn out_dir(subdomain: &str) -> Result<PathBuf, String> {
+    Root::from_env("STATIC_ROOT")?.entry(subdomain)
+}
+
+// Uploaded images are served from a sibling of STATIC_ROOT, so that all blogs
+// can share them: STATIC_ROOT/../user_uploads.
+pub fn uploads_root() -> Result<Root, String> {
+    let static_root = Root::from_env("STATIC_ROOT")?;
+    let parent = static_root
+        .path()
+        .parent()
+        .ok_or("STATIC_ROOT has no parent directory")?;
+    Root::create(parent)?.subroot("user_uploads")
+}
+
+// Maps a Zulip upload URL like /user_uploads/13/abc/DSC_0583.png onto where
+// we store it locally under the uploads root.
+pub fn upload_path(uploads: &Root, url: &str) -> Result<PathBuf, String> {
+    let rel = url
+        .strip_prefix("/user_uploads/")
+        .ok_or_else(|| format!("{:?} isn't a user upload URL", url))?;
+    uploads.join(rel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_root() -> (tempfile::TempDir, Root) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Root::create(tmp.path().join("root")).unwrap();
+        (tmp, root)
+    }
+
+    #[test]
+    fn test_join_plain_path() {
+        let (_tmp, root) = test_root();
+        let p = root.join("content/123.md").unwrap();
+        assert_eq!(p, root.path().join("content").join("123.md"));
+    }
+
+    #[test]
+    fn test_join_rejects_parent_dir() {
+        let (_tmp, root) = test_root();
+        assert!(root.join("../evil").is_err());
+        assert!(root.join("content/../../evil").is_err());
+        assert!(root.join("content/..").is_err());
+    }
+
+    #[test]
+    fn test_join_rejects_absolute() {
+        let (_tmp, root) = test_root();
+        assert!(root.join("/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_join_rejects_root_itself() {
+        let (_tmp, root) = test_root();
+        assert!(root.join("").is_err());
+        assert!(root.join(".").is_err());
+    }
+
+    #[test]
+    fn test_join_rejects_symlink_escape() {
+        let (tmp, root) = test_root();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.path().join("link")).unwrap();
+
+        assert!(root.join("link").is_err());
+        assert!(root.join("link/file.md").is_err());
+    }
+
+    #[test]
+    fn test_join_allows_symlink_inside() {
+        let (_tmp, root) = test_root();
+        fs::create_dir(root.path().join("real")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("real"), root.path().join("link")).unwrap();
+
+        assert!(root.join("link/file.md").is_ok());
+    }
+
+    #[test]
+    fn test_child_rejects_malicious_subdomains() {
+        let (_tmp, root) = test_root();
+        for name in ["..", ".", "", "a/b", "../a", "/a", "a/", "a/..", "./a"] {
+            assert!(root.child(name).is_err(), "{:?} should be rejected", name);
+        }
+        assert!(root.child("myblog").is_ok());
+    }
+
+    #[test]
+    fn test_entry_keeps_symlinks() {
+        let (tmp, root) = test_root();
+        let outside = tmp.path().join("outside");
+        fs::create_dir(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.path().join("myblog")).unwrap();
+
+        assert_eq!(root.entry("myblog").unwrap(), root.path().join("myblog"));
+        for name in ["..", ".", "", "a/b", "../a", "/a"] {
+            assert!(root.entry(name).is_err(), "{:?} should be rejected", name);
+        }
+    }
+
+    #[test]
+    fn test_upload_path() {
+        let (_tmp, root) = test_root();
+        let p = upload_path(&root, "/user_uploads/13/abc/DSC_0583.png").unwrap();
+        assert_eq!(p, root.path().join("13/abc/DSC_0583.png"));
+    }
+
+    #[test]
+    fn test_upload_path_rejects_traversal() {
+        let (_tmp, root) = test_root();
+        for url in [
+            "/user_uploads/../../etc/passwd",
+            "/user_uploads/13/../../../x.png",
+            "/user_uploads//etc/passwd",
+            "/user_uploads/",
+            "/etc/passwd",
+            "user_uploads/13/abc.png",
+        ] {
+            assert!(
+                upload_path(&root, url).is_err(),
+                "{:?} should be rejected",
+                url
+            );
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+use crate::bloggen::{self, ParsedMessage, PostSlug};
+use crate::zulip::Message;
+
+// Everything we know about a published post, as stored in the DB (as JSON).
+// The message's content is what everything else about the post comes from,
+// and the rest is worked out from it when it's published, so that listing
+// and rebuilding posts doesn't have to parse every message again.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PostRecord {
+    pub user_id: u64,
+    // The author's name on Zulip. Not known for posts published before we
+    // started recording it, nor are the timestamp, stream or topic.
+    #[serde(default)]
+    pub author: String,
+    pub content: String,
+    pub title: String,
+    // When the message was sent
+    pub timestamp: Option<u64>,
+    // When it was edited after that, oldest first
+    #[serde(default)]
+    pub edited_at: Vec<u64>,
+    pub stream_id: Option<u64>,
+    #[serde(default)]
+    pub topic: String,
+    // The current slug first, followed by any old ones. A copy of what's in
+    // POST_ID_TO_SLUGS_TABLE, which is what assigning slugs goes by.
+    #[serde(default)]
+    pub slugs: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    #[serde(default)]
+    pub draft: bool,
+    // Set for standalone pages, which live at /{page}/
+    #[serde(default)]
+    pub page: Option<String>,
+    // The uploads the post links to, which get copied into the blog
+    #[serde(default)]
+    pub assets: Vec<String>,
+    #[serde(default)]
+    pub build: BuildState,
+}
+
+// Whether the post has made it onto the live site yet.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum BuildState {
+    // Waiting on a build
+    #[default]
+    Pending,
+    Built,
+    // The last build failed because of this post
+    Failed {
+        reason: String,
+    },
+}
+
+impl PostRecord {
+    // A record for a message that's just been published or edited. When it's
+    // been edited, `previous` is what we had for it before.
+    pub fn new(msg: &Message, slugs: Vec<String>, previous: Option<&PostRecord>) -> PostRecord {
+        let mut edited_at = previous.map(|p| p.edited_at.clone()).unwrap_or_default();
+        // Zulip counts moving a message to another topic as an edit too
+        if previous.is_some_and(|p| p.content != msg.content) {
+            edited_at.push(
+                msg.last_edit_timestamp
+                    .unwrap_or_else(|| Utc::now().timestamp() as u64),
+            );
+        }
+        let mut record = PostRecord {
+            user_id: msg.sender_id,
+            author: msg.sender_full_name.clone(),
+            timestamp: Some(msg.timestamp),
+            edited_at,
+            stream_id: msg.stream_id,
+            topic: msg.subject.clone(),
+            slugs,
+            ..Default::default()
+        };
+        record.set_content(&msg.content);
+        record
+    }
+
+    // Catches the record up with the message as it is on Zulip now, which
+    // also fills in what we didn't record for older posts.
+    pub fn refresh(&mut self, msg: &Message) {
+        self.author = msg.sender_full_name.clone();
+        self.timestamp = Some(msg.timestamp);
+        self.stream_id = msg.stream_id;
+        self.topic = msg.subject.clone();
+        if let Some(edited) = msg.last_edit_timestamp {
+            if self.edited_at.last().is_none_or(|last| edited > *last) {
+                self.edited_at.push(edited);
+            }
+        }
+        self.set_content(&msg.content);
+    }
+
+    // Replaces the post's content, and everything that comes from it.
+    pub fn set_content(&mut self, content: &str) {
+        self.content = content.to_string();
+        let parsed = self.parsed();
+        self.title = parsed.title;
+        self.tags = parsed.metadata.tags;
+        self.categories = parsed.metadata.categories;
+        self.draft = parsed.metadata.draft;
+        self.page = parsed.metadata.page;
+        self.assets = parsed.image_urls;
+    }
+
+    // The content, parsed the same way as when it was published.
+    pub fn parsed(&self) -> ParsedMessage {
+        bloggen::parse_raw_message(&self.content, self.timestamp.unwrap_or(0))
+    }
+
+    // When the post's content last changed.
+    pub fn updated_at(&self) -> Option<u64> {
+        self.edited_at.last().copied().or(self.timestamp)
+    }
+
+    // Where the post lives on its blog. None for posts that have never had a
+    // slug, which are still at /{post_id}/.
+    pub fn slug(&self) -> Option<PostSlug> {
+        let (slug, aliases) = self.slugs.split_first()?;
+        Some(PostSlug {
+            slug: slug.clone(),
+            aliases: aliases.to_vec(),
+        })
+    }
+}
+
+// One version of a post, kept so that its author can see how it's changed and
+// go back to it. Stored as JSON, like PostRecord.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    // When it was published, or the edit that made it was
+    pub timestamp: u64,
+    pub title: String,
+    pub content: String,
+}
+
+impl Revision {
+    pub fn of(record: &PostRecord) -> Revision {
+        Revision {
+            timestamp: record.updated_at().unwrap_or(0),
+            title: record.title.clone(),
+            content: record.content.clone(),
+        }
+    }
+}
+
+// What changed between two versions of a post, as a unified diff. Messages
+// never end in a newline, so there's no point saying so.
+pub fn diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let mut diff = TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(2)
+        .missing_newline_hint(false)
+        .header(old_name, new_name)
+        .to_string();
+    if !diff.is_empty() && !diff.ends_with('\n') {
+        diff.push('\n');
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message {
+            content: content.to_string(),
+            id: 100,
+            sender_id: 1,
+            stream_id: Some(7),
+            timestamp: 1_700_000_000,
+            subject: "blogging".to_string(),
+            sender_full_name: "Test User".to_string(),
+            last_edit_timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_record_from_message() {
+        let msg = message(
+            "@**Blog Bot (HyperTXT)** TAGS: rust\nDRAFT: true\n\n# Hello\n\nSee [pic](/user_uploads/1/ab/pic.png)",
+        );
+        let record = PostRecord::new(&msg, vec!["hello".to_string()], None);
+        assert_eq!(record.title, "Hello");
+        assert_eq!(record.author, "Test User");
+        assert_eq!(record.timestamp, Some(1_700_000_000));
+        assert_eq!(record.stream_id, Some(7));
+        assert_eq!(record.topic, "blogging");
+        assert_eq!(record.tags, vec!["rust".to_string()]);
+        assert!(record.draft);
+        assert_eq!(
+            record.assets,
+            vec!["/user_uploads/1/ab/pic.png".to_string()]
+        );
+        assert_eq!(record.build, BuildState::Pending);
+        assert!(record.edited_at.is_empty());
+        assert_eq!(record.slug().unwrap().slug, "hello");
+    }
+
+    #[test]
+    fn test_edits_are_recorded() {
+        let first = PostRecord::new(&message("# Hello"), vec![], None);
+        let mut edit = message("# Hello again");
+        edit.last_edit_timestamp = Some(1_700_000_500);
+        let second = PostRecord::new(&edit, vec![], Some(&first));
+        assert_eq!(second.title, "Hello again");
+        assert_eq!(second.timestamp, Some(1_700_000_000));
+        assert_eq!(second.edited_at, vec![1_700_000_500]);
+        assert_eq!(second.slug(), None);
+    }
+
+    #[test]
+    fn test_moving_topics_isnt_an_edit() {
+        let first = PostRecord::new(&message("# Hello"), vec![], None);
+        let mut moved = message("# Hello");
+        moved.subject = "elsewhere".to_string();
+        moved.last_edit_timestamp = Some(1_700_000_500);
+        let second = PostRecord::new(&moved, vec![], Some(&first));
+        assert!(second.edited_at.is_empty());
+        assert_eq!(second.topic, "elsewhere");
+        assert_eq!(second.updated_at(), Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = diff("# Hello\n\nOne\nTwo", "# Hello\n\nOne\nThree", "r1", "r2");
+        assert_eq!(
+            diff,
+            "--- r1\n+++ r2\n@@ -2,3 +2,3 @@\n \n One\n-Two\n+Three\n"
+        );
+        assert_eq!(super::diff("Same\n", "Same\n", "r1", "r2"), "");
+    }
+
+    #[test]
+    fn test_stable_encoding() {
+        let mut record = PostRecord::new(&message("PAGE: about\n\n# About"), vec![], None);
+        record.build = BuildState::Failed {
+            reason: "oops".to_string(),
+        };
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(json.contains(r#""build":{"state":"failed","reason":"oops"}"#));
+        assert_eq!(serde_json::from_str::<PostRecord>(&json).unwrap(), record);
+
+        // Fields added later fall back to their defaults
+        let minimal: PostRecord = serde_json::from_str(
+            r#"{"user_id":1,"content":"Hi","title":"Hi","timestamp":null,"stream_id":null}"#,
+        )
+        .unwrap();
+        assert_eq!(minimal.build, BuildState::Pending);
+        assert!(minimal.slugs.is_empty());
+    }
+}
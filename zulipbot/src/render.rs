@@ -0,0 +1,1463 @@
+use chrono::DateTime;
+use pulldown_cmark::{html, Options, Parser};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use std::{env, fmt, fs, thread};
+use tera::Tera;
+
+use crate::feed;
+use crate::paths::Root;
+use crate::sandbox::{self, Sandbox};
+use crate::themes;
+use crate::zola::{self, PageFrontMatter};
+
+// Turns a blog's Zola project (config.toml, content/, static/) into the built
+// site in its output dir. Either by running zola itself, or by rendering it
+// in-process, which doesn't need a zola binary and can skip everything a
+// change didn't touch, at the cost of ignoring the blog's theme.
+
+// What changed since the last build, so a renderer can skip the rest.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Changes {
+    All,
+    // Some posts or pages, by their post IDs
+    Posts(BTreeSet<u64>),
+}
+
+impl Changes {
+    pub fn post(post_id: u64) -> Changes {
+        Changes::Posts(BTreeSet::from([post_id]))
+    }
+
+    // Everything either of them changed, so that one build covers both.
+    pub fn merge(self, other: Changes) -> Changes {
+        match (self, other) {
+            (Changes::Posts(mut a), Changes::Posts(b)) => {
+                a.extend(b);
+                Changes::Posts(a)
+            }
+            _ => Changes::All,
+        }
+    }
+}
+
+pub trait Renderer: Send + Sync {
+    fn build(&self, blog: &Root, out_dir: &Path, changes: Changes) -> Result<(), Box<dyn Error>>;
+}
+
+// RENDERER picks which one to use: "zola" (the default) or "native".
+pub fn from_env() -> Result<Box<dyn Renderer>, String> {
+    match env::var("RENDERER").as_deref() {
+        Ok("zola") | Err(_) => Ok(Box::new(ZolaRenderer {
+            timeout: build_timeout(),
+            sandbox: Sandbox::from_env()?,
+        })),
+        Ok("native") => {
+            let templates = Path::new(
+                &env::var("TEMPLATES_ROOT")
+                    .map_err(|e| format!("failed to get TEMPLATES_ROOT: {:?}", e))?,
+            )
+            .join("native");
+            Ok(Box::new(NativeRenderer::new(&templates)?))
+        }
+        Ok(other) => Err(format!(
+            "unknown RENDERER {:?}, expected \"zola\" or \"native\"",
+            other
+        )),
+    }
+}
+
+// The renderer from the environment, made the first time it's needed and
+// kept, so that the native renderer's templates are only loaded and compiled
+// once. Changing them needs a restart.
+pub fn shared() -> Result<Arc<dyn Renderer>, String> {
+    static SHARED: OnceLock<Arc<dyn Renderer>> = OnceLock::new();
+    if let Some(renderer) = SHARED.get() {
+        return Ok(Arc::clone(renderer));
+    }
+    let renderer: Arc<dyn Renderer> = from_env()?.into();
+    Ok(Arc::clone(SHARED.get_or_init(|| renderer)))
+}
+
+// Why a build failed, with whatever we could work out about where, so the
+// author can be pointed at the post that broke it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildError {
+    // The most specific reason the renderer gave
+    pub reason: String,
+    // The content file it failed on, relative to the blog, e.g. content/123.md
+    pub file: Option<String>,
+    // The line it failed on, as the renderer counted it, i.e. in the
+    // content file rather than in the author's message
+    pub line: Option<usize>,
+    // What's on that line, if the renderer quoted it
+    pub source_line: Option<String>,
+    pub front_matter: bool,
+    // Everything the build printed, for the logs
+    pub output: String,
+}
+
+impl BuildError {
+    pub fn new<S: Into<String>>(reason: S) -> BuildError {
+        BuildError {
+            reason: reason.into(),
+            ..Default::default()
+        }
+    }
+
+    // Keeps the details if a renderer returned a BuildError, otherwise just
+    // describes the error.
+    pub fn from_error(e: Box<dyn Error>) -> BuildError {
+        match e.downcast::<BuildError>() {
+            Ok(e) => *e,
+            Err(e) => {
+                let mut reason = e.to_string();
+                let mut source = e.source();
+                while let Some(e) = source {
+                    reason.push_str(&format!(": {}", e));
+                    source = e.source();
+                }
+                BuildError::new(reason)
+            }
+        }
+    }
+
+    // The post (or page) whose content file it failed on.
+    pub fn post_id(&self) -> Option<u64> {
+        Path::new(self.file.as_ref()?)
+            .file_stem()?
+            .to_str()?
+            .parse()
+            .ok()
+    }
+
+    // A short explanation for the author, given the title and content of the
+    // post it failed on if we know them.
+    pub fn explain(&self, title: Option<&str>, message: Option<&str>) -> String {
+        let subject = match title {
+            Some(title) => format!("Your post '{}'", title),
+            None => "Your blog".to_string(),
+        };
+        let near = match self.message_line(message) {
+            Some(line) => format!(" near line {}", line),
+            None => String::new(),
+        };
+        let mut msg = if self.front_matter {
+            format!(
+                "{} has invalid front matter{}: {}",
+                subject, near, self.reason
+            )
+        } else {
+            format!("{} couldn't be built{}: {}", subject, near, self.reason)
+        };
+        // Tera's name for a post's content, when zola renders it as a
+        // template to expand shortcodes
+        if self.reason.contains("__tera_one_off") {
+            msg.push_str(
+                "\n\nAnything in `{{ }}` or `{% %}` is treated as a shortcode, so put code like that in a code block.",
+            );
+        }
+        msg
+    }
+
+    // The line of the author's message it failed on. Our line numbers count
+    // the front matter we generate, and the metadata lines we strip from
+    // the message, so we look for the line the renderer quoted instead. A
+    // line in the front matter isn't one the author wrote at all.
+    fn message_line(&self, message: Option<&str>) -> Option<usize> {
+        if self.front_matter {
+            return None;
+        }
+        let source = self.source_line.as_deref()?.trim();
+        if source.is_empty() {
+            return None;
+        }
+        message?
+            .lines()
+            .position(|line| line.trim() == source)
+            .map(|i| i + 1)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason)?;
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => write!(f, " ({}, line {})", file, line),
+            (Some(file), None) => write!(f, " ({})", file),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+// Picks the failure out of zola's output. It prints an `Error: ` line for
+// each cause, outermost first, with `Reason: ` on the ones after the first,
+// and sometimes a snippet of the file after the last one, e.g.
+//
+//   Error: Failed to build the site
+//   Error: Error when parsing front matter of page `/blogs/me/content/12.md`
+//   Error: Reason: TOML parse error at line 3, column 8
+//     |
+//   3 | title = oops
+//     |         ^
+//   invalid string
+pub fn parse_zola_output(output: &str) -> BuildError {
+    let mut err = BuildError {
+        output: output.to_string(),
+        ..Default::default()
+    };
+    let mut reason: Option<String> = None;
+    let mut in_last_error = false;
+    for line in output.lines() {
+        let line = line.trim_end();
+        if let Some(msg) = line.trim_start().strip_prefix("Error: ") {
+            if err.file.is_none() {
+                err.file = content_file(msg);
+            }
+            if msg.to_ascii_lowercase().contains("front matter") {
+                err.front_matter = true;
+            }
+            reason = Some(msg.strip_prefix("Reason: ").unwrap_or(msg).to_string());
+            in_last_error = true;
+        } else if line.is_empty() {
+            in_last_error = false;
+        } else if in_last_error && !line.contains('|') {
+            // The rest of the message, rather than the snippet of the file
+            if let Some(reason) = &mut reason {
+                reason.push_str(": ");
+                reason.push_str(line.trim());
+            }
+        } else if in_last_error && err.source_line.is_none() {
+            err.source_line = quoted_line(line);
+        }
+    }
+    err.reason = reason.unwrap_or_else(|| "zola failed without saying why".to_string());
+    err.line = line_number(&err.reason);
+    err
+}
+
+// e.g. "title = oops" from the snippet line "3 | title = oops"
+fn quoted_line(line: &str) -> Option<String> {
+    let (number, text) = line.trim_start().split_once(" | ")?;
+    number.parse::<usize>().ok()?;
+    Some(text.to_string())
+}
+
+// e.g. "content/12.md" from "... of page `/blogs/me/content/12.md`"
+fn content_file(msg: &str) -> Option<String> {
+    msg.split(|c: char| c.is_whitespace() || "`'\"".contains(c))
+        .filter(|word| word.ends_with(".md"))
+        .find_map(|word| {
+            let start = if word.starts_with("content/") {
+                0
+            } else {
+                word.rfind("/content/")? + 1
+            };
+            Some(word[start..].to_string())
+        })
+}
+
+// e.g. 3 from "TOML parse error at line 3, column 8", or from Tera's
+// "--> 3:8"
+fn line_number(reason: &str) -> Option<usize> {
+    let digits = |s: &str| -> Option<usize> {
+        let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        s[..end].parse().ok()
+    };
+    // "line " as a word, so not the end of e.g. "inline "
+    let after_line = reason.match_indices("line ").find_map(|(i, m)| {
+        let word_start = !reason[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric());
+        word_start.then(|| digits(&reason[i + m.len()..]))?
+    });
+    if after_line.is_some() {
+        return after_line;
+    }
+    let (_, rest) = reason.split_once("--> ")?;
+    digits(rest)
+}
+
+// How long a build can take before we give up on it, from
+// BUILD_TIMEOUT_SECS (two minutes by default).
+pub fn build_timeout() -> Duration {
+    let secs = env::var("BUILD_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(120);
+    Duration::from_secs(secs)
+}
+
+pub fn build<Q: AsRef<Path>>(
+    blog: &Root,
+    out_dir: Q,
+    changes: Changes,
+) -> Result<(), Box<dyn Error>> {
+    shared()?.build(blog, out_dir.as_ref(), changes)
+}
+
+// Shells out to `zola build`, which always rebuilds the whole site. It runs
+// in the sandbox, where it can only see the blog and the themes and only
+// write to its output dir, and is killed if it runs for longer than
+// `timeout` or writes more than the sandbox's output limit.
+pub struct ZolaRenderer {
+    pub timeout: Duration,
+    pub sandbox: Sandbox,
+}
+
+// Most of what zola prints we only keep for the logs
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+
+// Zola replaces its output dir rather than writing into it, and it can't
+// replace the dir it's been given to write to, so it builds into this one
+// inside it.
+const ZOLA_OUTPUT: &str = ".zola";
+
+impl Renderer for ZolaRenderer {
+    fn build(&self, blog: &Root, out_dir: &Path, _changes: Changes) -> Result<(), Box<dyn Error>> {
+        let zola = sandbox::find_program("zola")?;
+        // The themes are a symlink to somewhere outside the blog
+        let themes = blog.path().join(fs::read_link(blog.entry("themes")?)?);
+        fs::create_dir_all(out_dir)?;
+        let site = out_dir.join(ZOLA_OUTPUT);
+        let sandbox = self
+            .sandbox
+            .clone()
+            .read(&zola)
+            .read(blog.path())
+            .read(&themes)
+            .write(out_dir);
+
+        let mut build_cmd = Command::new(&zola);
+        build_cmd.arg("build");
+        build_cmd.arg("--force");
+        build_cmd.current_dir(blog.path());
+        build_cmd.args(["--output-dir", site.to_str().unwrap()]);
+        build_cmd.stdout(Stdio::piped());
+        build_cmd.stderr(Stdio::piped());
+        let _sandbox = sandbox.apply(&mut build_cmd)?;
+        let mut child = build_cmd.spawn()?;
+        // Read both as they come, so zola never blocks on a full pipe
+        let stdout = read_in_background(child.stdout.take());
+        let stderr = read_in_background(child.stderr.take());
+
+        let started = Instant::now();
+        let output_bytes = self.sandbox.limits.output_bytes;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            let stop = if started.elapsed() > self.timeout {
+                Some(format!(
+                    "zola took longer than {} seconds",
+                    self.timeout.as_secs()
+                ))
+            } else if sandbox::dir_size(out_dir) > output_bytes {
+                Some(too_big(output_bytes))
+            } else {
+                None
+            };
+            if let Some(reason) = stop {
+                child.kill()?;
+                child.wait()?;
+                fs::remove_dir_all(&site).ok();
+                return Err(Box::new(BuildError::new(reason)));
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+
+        let output = format!(
+            "{}{}",
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default()
+        );
+        // It might have finished writing since we last looked
+        let failed = if !status.success() {
+            Some(parse_zola_output(&output))
+        } else if sandbox::dir_size(out_dir) > output_bytes {
+            Some(BuildError::new(too_big(output_bytes)))
+        } else {
+            None
+        };
+        if let Some(e) = failed {
+            fs::remove_dir_all(&site).ok();
+            return Err(Box::new(e));
+        }
+
+        // Zola always builds everything, so whatever else is there is stale
+        for entry in fs::read_dir(out_dir)? {
+            let path = entry?.path();
+            if path == site {
+                continue;
+            }
+            if path.is_dir() && !path.is_symlink() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+        for entry in fs::read_dir(&site)? {
+            let entry = entry?;
+            fs::rename(entry.path(), out_dir.join(entry.file_name()))?;
+        }
+        fs::remove_dir(&site)?;
+        Ok(())
+    }
+}
+
+fn too_big(output_bytes: u64) -> String {
+    format!(
+        "the built site is over {} MB, the most a blog can be",
+        output_bytes / 1024 / 1024
+    )
+}
+
+// Marks an error rendering a page as being that post's fault.
+fn page_error(page: &PageContext, e: Box<dyn Error>) -> Box<dyn Error> {
+    let mut err = BuildError::from_error(e);
+    err.file = Some(format!("content/{}.md", page.id));
+    Box::new(err)
+}
+
+fn read_in_background<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        if let Some(pipe) = pipe {
+            let mut bytes = Vec::new();
+            let mut pipe = pipe.take(MAX_LOG_BYTES);
+            pipe.read_to_end(&mut bytes).ok();
+            // Keep draining it, so zola doesn't block on a full pipe
+            std::io::copy(pipe.get_mut(), &mut std::io::sink()).ok();
+            output = String::from_utf8_lossy(&bytes).into_owned();
+        }
+        output
+    })
+}
+
+// Renders with pulldown-cmark and our own Tera templates
+// (TEMPLATES_ROOT/native), into the same layout zola produces: /{slug}/ for
+// posts, /{path}/ for pages, /tags/{term}/ and so on, with aliases as
+// redirects and the same feeds.
+//
+// It can't be killed the way zola can, so it stops itself, between one page
+// and the next, once it's been going for longer than `timeout`.
+pub struct NativeRenderer {
+    tera: Tera,
+    pub timeout: Duration,
+}
+
+impl NativeRenderer {
+    pub fn new(templates: &Path) -> Result<NativeRenderer, String> {
+        let glob = templates.join("*");
+        let tera = Tera::new(glob.to_str().ok_or("templates path isn't UTF-8")?)
+            .map_err(|e| format!("failed to load templates from {:?}: {:?}", templates, e))?;
+        Ok(NativeRenderer {
+            tera,
+            timeout: build_timeout(),
+        })
+    }
+}
+
+// Where a native build writes to, and when it has to be done by
+struct Output {
+    root: Root,
+    deadline: Instant,
+    timeout: Duration,
+}
+
+impl Output {
+    fn join<P: AsRef<Path>>(&self, rel: P) -> Result<PathBuf, String> {
+        self.root.join(rel)
+    }
+
+    fn check_deadline(&self) -> Result<(), String> {
+        if Instant::now() > self.deadline {
+            return Err(format!(
+                "the build took longer than {} seconds",
+                self.timeout.as_secs()
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Renderer for NativeRenderer {
+    fn build(&self, blog: &Root, out_dir: &Path, changes: Changes) -> Result<(), Box<dyn Error>> {
+        self.build_by(blog, out_dir, changes, Instant::now() + self.timeout)
+    }
+}
+
+impl NativeRenderer {
+    fn build_by(
+        &self,
+        blog: &Root,
+        out_dir: &Path,
+        changes: Changes,
+        deadline: Instant,
+    ) -> Result<(), Box<dyn Error>> {
+        if changes == Changes::All && out_dir.exists() {
+            // Built next to it and swapped in, so a build that fails part way
+            // leaves what was there
+            let parent = out_dir.parent().ok_or("output dir has no parent")?;
+            let scratch = tempfile::tempdir_in(parent)?;
+            let fresh = scratch.path().join("out");
+            self.build_by(blog, &fresh, Changes::All, deadline)?;
+            fs::remove_dir_all(out_dir)?;
+            fs::rename(&fresh, out_dir)?;
+            return Ok(());
+        }
+        let site = Site::load(blog)?;
+        fs::create_dir_all(out_dir)?;
+        let out = Output {
+            root: Root::new(out_dir)?,
+            deadline,
+            timeout: self.timeout,
+        };
+
+        let mut ctx = tera::Context::new();
+        ctx.insert("config", &site.config);
+        ctx.insert(
+            "custom_css",
+            &blog
+                .join("static")?
+                .join(themes::CUSTOM_CSS_FILENAME)
+                .exists(),
+        );
+
+        match changes {
+            Changes::All => {
+                for page in site.posts.iter().chain(&site.pages) {
+                    out.check_deadline()?;
+                    self.write_page(&out, &ctx, page)
+                        .map_err(|e| page_error(page, e))?;
+                }
+                self.write(&out, "404.html", "404.html", &ctx)?;
+            }
+            Changes::Posts(post_ids) => {
+                for post_id in post_ids {
+                    out.check_deadline()?;
+                    let id = post_id.to_string();
+                    match site.posts.iter().chain(&site.pages).find(|p| p.id == id) {
+                        Some(page) => self
+                            .write_page(&out, &ctx, page)
+                            .map_err(|e| page_error(page, e))?,
+                        // Deleted, or now a draft. We don't know where it
+                        // used to be, so a full build is the only way to tidy
+                        // it up.
+                        None => return self.build_by(blog, out_dir, Changes::All, deadline),
+                    }
+                }
+            }
+        }
+
+        // Everything below lists posts, so any change can affect it
+        let mut index_ctx = ctx.clone();
+        index_ctx.insert(
+            "section",
+            &SectionContext {
+                content: site.intro.clone(),
+                pages: site.posts.clone(),
+            },
+        );
+        self.write(&out, "index.html", "index.html", &index_ctx)?;
+        self.write_feeds(&out, &ctx, "", &site.config_title(), &site.posts)?;
+        self.write_taxonomies(&out, &ctx, &site)?;
+        copy_dir(&blog.join("static")?, &out.root)?;
+        Ok(())
+    }
+
+    fn write(
+        &self,
+        out: &Output,
+        template: &str,
+        dst: &str,
+        ctx: &tera::Context,
+    ) -> Result<(), Box<dyn Error>> {
+        let rendered = self.tera.render(template, ctx)?;
+        let path = out.join(dst)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, rendered)?;
+        Ok(())
+    }
+
+    fn write_page(
+        &self,
+        out: &Output,
+        ctx: &tera::Context,
+        page: &PageContext,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut page_ctx = ctx.clone();
+        page_ctx.insert("page", page);
+        self.write(
+            out,
+            "page.html",
+            &format!("{}/index.html", page.path),
+            &page_ctx,
+        )?;
+
+        for alias in &page.aliases {
+            let alias = alias.trim_matches('/');
+            if alias.is_empty() {
+                continue;
+            }
+            let mut alias_ctx = tera::Context::new();
+            alias_ctx.insert("permalink", &page.permalink);
+            self.write(
+                out,
+                "alias.html",
+                &format!("{}/index.html", alias),
+                &alias_ctx,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Both feed formats, for the whole blog (dir "") or a single term
+    fn write_feeds(
+        &self,
+        out: &Output,
+        ctx: &tera::Context,
+        dir: &str,
+        title: &str,
+        pages: &[PageContext],
+    ) -> Result<(), Box<dyn Error>> {
+        let base_url = ctx
+            .get("config")
+            .and_then(|c| c.get("base_url"))
+            .and_then(|u| u.as_str())
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string();
+        let home_url = if dir.is_empty() {
+            format!("{}/", base_url)
+        } else {
+            format!("{}/{}/", base_url, dir)
+        };
+        let updated = pages
+            .iter()
+            .filter_map(|p| p.updated.clone().or(p.date.clone()))
+            .max()
+            .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+
+        for filename in ["atom.xml", "rss.xml"] {
+            let mut feed_ctx = ctx.clone();
+            feed_ctx.insert(
+                "feed",
+                &FeedContext {
+                    title: title.to_string(),
+                    home_url: home_url.clone(),
+                    feed_url: format!("{}{}", home_url, filename),
+                    rss_updated: rfc2822(&updated),
+                    updated: updated.clone(),
+                    pages: pages.to_vec(),
+                },
+            );
+            let dst = if dir.is_empty() {
+                filename.to_string()
+            } else {
+                format!("{}/{}", dir, filename)
+            };
+            self.write(out, filename, &dst, &feed_ctx)?;
+        }
+        Ok(())
+    }
+
+    fn write_taxonomies(
+        &self,
+        out: &Output,
+        ctx: &tera::Context,
+        site: &Site,
+    ) -> Result<(), Box<dyn Error>> {
+        for name in zola::TAXONOMIES {
+            // Terms that no longer have any posts should disappear
+            let dir = out.join(name)?;
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+
+            let terms = site.terms(name);
+            if terms.is_empty() {
+                continue;
+            }
+            let mut list_ctx = ctx.clone();
+            list_ctx.insert(
+                "taxonomy",
+                &TaxonomyContext {
+                    name: name.to_string(),
+                },
+            );
+            list_ctx.insert("terms", &terms);
+            self.write(
+                out,
+                "taxonomy_list.html",
+                &format!("{}/index.html", name),
+                &list_ctx,
+            )?;
+
+            for term in &terms {
+                out.check_deadline()?;
+                let mut term_ctx = list_ctx.clone();
+                term_ctx.insert("term", term);
+                let term_dir = format!("{}/{}", name, term.slug);
+                self.write(
+                    out,
+                    "taxonomy_single.html",
+                    &format!("{}/index.html", term_dir),
+                    &term_ctx,
+                )?;
+                let title = format!("{} - {}", site.config_title(), term.name);
+                self.write_feeds(out, ctx, &term_dir, &title, &term.pages)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Everything in the blog's content dir, ready to go into templates
+struct Site {
+    config: toml::Table,
+    intro: String,
+    // Newest first
+    posts: Vec<PageContext>,
+    pages: Vec<PageContext>,
+}
+
+impl Site {
+    fn load(blog: &Root) -> Result<Site, Box<dyn Error>> {
+        let config_path = blog.join("config.toml")?;
+        let mut config: toml::Table = toml::from_str(&fs::read_to_string(config_path)?)?;
+        let base_url = config
+            .get("base_url")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string();
+        config.insert("base_url".to_string(), base_url.clone().into());
+
+        let intro = match fs::read_to_string(blog.join("content/_index.md")?) {
+            Ok(raw) => markdown_to_html(split_front_matter(&raw).1),
+            Err(_) => String::new(),
+        };
+
+        let mut posts = load_pages(&blog.join("content")?, &base_url, false)?;
+        let pages = load_pages(&blog.join("content/pages")?, &base_url, true)?;
+        // Same as the section's sort_by = "update_date"
+        posts.sort_by(|a, b| {
+            let a_date = a.updated.as_ref().or(a.date.as_ref());
+            let b_date = b.updated.as_ref().or(b.date.as_ref());
+            b_date.cmp(&a_date)
+        });
+
+        Ok(Site {
+            config,
+            intro,
+            posts,
+            pages,
+        })
+    }
+
+    fn config_title(&self) -> String {
+        self.config
+            .get("title")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    // Every term used in a taxonomy, sorted by name, each with its posts
+    fn terms(&self, taxonomy: &str) -> Vec<TermContext> {
+        let mut terms: BTreeMap<String, TermContext> = BTreeMap::new();
+        for post in &self.posts {
+            for term in post.taxonomies.get(taxonomy).into_iter().flatten() {
+                terms
+                    .entry(term.slug.clone())
+                    .or_insert_with(|| TermContext {
+                        name: term.name.clone(),
+                        slug: term.slug.clone(),
+                        permalink: term.permalink.clone(),
+                        pages: Vec::new(),
+                    })
+                    .pages
+                    .push(post.clone());
+            }
+        }
+        terms.into_values().collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct PageContext {
+    // The content file's name, which is the post ID
+    id: String,
+    title: String,
+    // Where the page goes in the output, without slashes
+    path: String,
+    permalink: String,
+    date: Option<String>,
+    updated: Option<String>,
+    rss_date: Option<String>,
+    description: Option<String>,
+    content: String,
+    // Everything before <!-- more -->, if there's a marker
+    summary: Option<String>,
+    taxonomies: BTreeMap<String, Vec<TermLink>>,
+    aliases: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TermLink {
+    name: String,
+    slug: String,
+    permalink: String,
+}
+
+#[derive(Serialize)]
+struct SectionContext {
+    content: String,
+    pages: Vec<PageContext>,
+}
+
+#[derive(Serialize)]
+struct TaxonomyContext {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct TermContext {
+    name: String,
+    slug: String,
+    permalink: String,
+    pages: Vec<PageContext>,
+}
+
+#[derive(Serialize)]
+struct FeedContext {
+    title: String,
+    home_url: String,
+    feed_url: String,
+    updated: String,
+    rss_updated: String,
+    pages: Vec<PageContext>,
+}
+
+// Loads every content file in a dir (but not its subdirs or _index.md),
+// leaving out drafts the way `zola build` does.
+fn load_pages(
+    dir: &Path,
+    base_url: &str,
+    standalone: bool,
+) -> Result<Vec<PageContext>, Box<dyn Error>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut pages = Vec::new();
+    for entry in entries {
+        let path: PathBuf = entry?.path();
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(String::from) else {
+            continue;
+        };
+        if !path.is_file() || path.extension().is_none_or(|e| e != "md") || id == "_index" {
+            continue;
+        }
+        let raw = fs::read_to_string(&path)?;
+        let (front_matter, body) = split_front_matter(&raw);
+        let fm: PageFrontMatter = toml::from_str(front_matter).map_err(|e| {
+            let line = e
+                .span()
+                .map(|span| front_matter[..span.start].matches('\n').count() + 1);
+            BuildError {
+                reason: e.message().to_string(),
+                file: content_file(&path.to_string_lossy()),
+                line,
+                source_line: None,
+                front_matter: true,
+                output: String::new(),
+            }
+        })?;
+        if fm.draft {
+            continue;
+        }
+        pages.push(page_context(id, fm, body, base_url, standalone));
+    }
+    Ok(pages)
+}
+
+fn page_context(
+    id: String,
+    fm: PageFrontMatter,
+    body: &str,
+    base_url: &str,
+    standalone: bool,
+) -> PageContext {
+    let path = match (&fm.path, &fm.slug) {
+        (Some(path), _) => path.trim_matches('/').to_string(),
+        (None, Some(slug)) => slug.clone(),
+        (None, None) => slug::slugify(&id),
+    };
+    let summary = body
+        .split_once("<!-- more -->")
+        .map(|(summary, _)| markdown_to_html(summary));
+    let taxonomies = fm
+        .taxonomies
+        .iter()
+        .map(|(name, terms)| {
+            let links = terms
+                .iter()
+                .map(|term| {
+                    let slug = slug::slugify(term);
+                    TermLink {
+                        name: term.clone(),
+                        permalink: format!("{}/{}/{}/", base_url, name, slug),
+                        slug,
+                    }
+                })
+                .collect();
+            (name.clone(), links)
+        })
+        .collect();
+    let date = if standalone {
+        None
+    } else {
+        fm.date.as_deref().and_then(feed::rfc3339)
+    };
+
+    PageContext {
+        id,
+        title: fm.title,
+        permalink: format!("{}/{}/", base_url, path),
+        path,
+        rss_date: date.as_deref().map(rfc2822),
+        date,
+        updated: fm.updated.as_deref().and_then(feed::rfc3339),
+        description: fm.description,
+        content: markdown_to_html(body),
+        summary,
+        taxonomies,
+        aliases: fm.aliases,
+    }
+}
+
+// Splits `+++\n{front matter}+++\n\n{body}` as written by zola::content_file
+fn split_front_matter(raw: &str) -> (&str, &str) {
+    let Some(rest) = raw.strip_prefix("+++\n") else {
+        return ("", raw);
+    };
+    match rest.split_once("\n+++\n") {
+        Some((fm, body)) => (fm, body.trim_start_matches('\n')),
+        None => match rest.strip_prefix("+++\n") {
+            // Empty front matter
+            Some(body) => ("", body.trim_start_matches('\n')),
+            None => ("", raw),
+        },
+    }
+}
+
+fn markdown_to_html(markdown: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    let mut out = String::new();
+    html::push_html(&mut out, Parser::new_ext(markdown, options));
+    out
+}
+
+fn rfc2822(date: &str) -> String {
+    match DateTime::parse_from_rfc3339(date) {
+        Ok(d) => d.to_rfc2822(),
+        Err(_) => date.to_string(),
+    }
+}
+
+// Copies the blog's static dir over the top of the output, like zola does
+pub fn copy_dir(src: &Path, dst: &Root) -> Result<(), Box<dyn Error>> {
+    let Ok(entries) = fs::read_dir(src) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let target = dst.child(name)?;
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &Root::new(&target)?)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestBlog {
+        _tmp: tempfile::TempDir,
+        blog: Root,
+        out_dir: PathBuf,
+    }
+
+    fn test_blog() -> TestBlog {
+        let tmp = tempfile::tempdir().unwrap();
+        let blog = Root::create(tmp.path().join("blog")).unwrap();
+        fs::write(
+            blog.join("config.toml").unwrap(),
+            "base_url = \"https://myblog.hypertxt.io\"\ntitle = \"My <Blog>\"\n\n[extra]\nmenu_items = [{ name = \"about\", url = \"$BASE_URL/about\" }]\n",
+        )
+        .unwrap();
+        fs::create_dir_all(blog.join("content/pages").unwrap()).unwrap();
+        fs::create_dir_all(blog.join("static").unwrap()).unwrap();
+        fs::write(blog.join("static/feed.json").unwrap(), "{}").unwrap();
+        fs::write(
+            blog.join("content/_index.md").unwrap(),
+            zola::content_file(&zola::SectionFrontMatter::default(), "Hi, I'm **me**").unwrap(),
+        )
+        .unwrap();
+        let out_dir = tmp.path().join("out");
+        TestBlog {
+            _tmp: tmp,
+            blog,
+            out_dir,
+        }
+    }
+
+    fn write_content(blog: &Root, file: &str, fm: &PageFrontMatter, body: &str) {
+        fs::write(
+            blog.join(format!("content/{}", file)).unwrap(),
+            zola::content_file(fm, body).unwrap(),
+        )
+        .unwrap();
+    }
+
+    fn post(title: &str, date: &str, slug: &str) -> PageFrontMatter {
+        PageFrontMatter {
+            title: title.to_string(),
+            date: Some(date.to_string()),
+            slug: Some(slug.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn renderer() -> NativeRenderer {
+        NativeRenderer::new(&Path::new(env!("CARGO_MANIFEST_DIR")).join("templates/native"))
+            .unwrap()
+    }
+
+    // Tera escapes slashes in everything it outputs, which browsers undo
+    fn read(t: &TestBlog, path: &str) -> String {
+        fs::read_to_string(t.out_dir.join(path))
+            .unwrap()
+            .replace("&#x2F;", "/")
+    }
+
+    #[test]
+    fn test_full_build_layout() {
+        let t = test_blog();
+        let mut first = post("First <post>", "2024-11-20", "first");
+        first.taxonomies = BTreeMap::from([("tags".to_string(), vec!["Rust Lang".to_string()])]);
+        first.aliases = vec!["/100/".to_string()];
+        write_content(
+            &t.blog,
+            "100.md",
+            &first,
+            "Summary\n\n<!-- more -->\n\nBody *text*",
+        );
+        write_content(
+            &t.blog,
+            "101.md",
+            &post("Second", "2024-11-21T10:00:00Z", "second"),
+            "Two",
+        );
+        let mut draft = post("Secret", "2024-11-22", "secret");
+        draft.draft = true;
+        write_content(&t.blog, "102.md", &draft, "Shh");
+        let about = PageFrontMatter {
+            title: "About".to_string(),
+            path: Some("about".to_string()),
+            ..Default::default()
+        };
+        write_content(&t.blog, "pages/103.md", &about, "About me");
+
+        renderer().build(&t.blog, &t.out_dir, Changes::All).unwrap();
+
+        let index = read(&t, "index.html");
+        assert!(index.contains("My &lt;Blog&gt;"));
+        assert!(
+            index.contains("Hi, I&#x27;m <strong>me</strong>")
+                || index.contains("Hi, I'm <strong>me</strong>")
+        );
+        assert!(index.contains(r#"<a href="https://myblog.hypertxt.io/about">about</a>"#));
+        // Newest first, without drafts or pages
+        let second = index.find("https://myblog.hypertxt.io/second/").unwrap();
+        let first = index.find("https://myblog.hypertxt.io/first/").unwrap();
+        assert!(second < first);
+        assert!(!index.contains("secret"));
+        assert!(!index.contains("https://myblog.hypertxt.io/about/"));
+
+        let page = read(&t, "first/index.html");
+        assert!(page.contains("First &lt;post&gt;"));
+        assert!(page.contains("<em>text</em>"));
+        assert!(page.contains(r#"href="https://myblog.hypertxt.io/tags/rust-lang/""#));
+        assert!(read(&t, "100/index.html").contains(r#"url=https://myblog.hypertxt.io/first/"#));
+        assert!(read(&t, "about/index.html").contains("About me"));
+        assert!(!t.out_dir.join("secret").exists());
+
+        assert!(read(&t, "tags/index.html").contains("Rust Lang"));
+        assert!(read(&t, "tags/rust-lang/index.html").contains("First &lt;post&gt;"));
+        assert!(t.out_dir.join("tags/rust-lang/atom.xml").exists());
+        assert!(!t.out_dir.join("categories").exists());
+        assert!(t.out_dir.join("404.html").exists());
+        assert_eq!(read(&t, "feed.json"), "{}");
+
+        let atom = read(&t, "atom.xml");
+        assert!(atom.contains("<published>2024-11-20T00:00:00Z</published>"));
+        assert!(
+            atom.contains("&lt;em&gt;text&lt;&#x2F;em&gt;")
+                || atom.contains("&lt;em&gt;text&lt;/em&gt;")
+        );
+        assert!(!atom.contains("About me"));
+        let rss = read(&t, "rss.xml");
+        assert!(rss.contains("<pubDate>Wed, 20 Nov 2024 00:00:00 +0000</pubDate>"));
+    }
+
+    #[test]
+    fn test_feeds_parse() {
+        let t = test_blog();
+        let mut first = post("First <post>", "2024-11-20", "first");
+        first.taxonomies = BTreeMap::from([("tags".to_string(), vec!["Rust Lang".to_string()])]);
+        write_content(
+            &t.blog,
+            "100.md",
+            &first,
+            "Summary & more\n\n<!-- more -->\n\nBody *text*",
+        );
+        write_content(
+            &t.blog,
+            "101.md",
+            &post("Second", "2024-11-21T10:00:00Z", "second"),
+            "Two",
+        );
+        renderer().build(&t.blog, &t.out_dir, Changes::All).unwrap();
+        let read = |path: &str| fs::read_to_string(t.out_dir.join(path)).unwrap();
+
+        let xml = read("atom.xml");
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let feed = doc.root_element();
+        assert_eq!(
+            feed.tag_name().namespace(),
+            Some("http://www.w3.org/2005/Atom")
+        );
+        assert_eq!(feed.tag_name().name(), "feed");
+        let text = |node: roxmltree::Node, name: &str| {
+            node.children()
+                .find(|n| n.has_tag_name(name))
+                .and_then(|n| n.text())
+                .map(|s| s.to_string())
+        };
+        assert_eq!(text(feed, "title").as_deref(), Some("My <Blog>"));
+        let entries: Vec<_> = feed
+            .children()
+            .filter(|n| n.has_tag_name("entry"))
+            .collect();
+        let titles: Vec<_> = entries.iter().map(|e| text(*e, "title").unwrap()).collect();
+        assert_eq!(titles, ["Second", "First <post>"]);
+        let first = entries[1];
+        assert_eq!(
+            text(first, "published").as_deref(),
+            Some("2024-11-20T00:00:00Z")
+        );
+        let link = first.children().find(|n| n.has_tag_name("link")).unwrap();
+        assert_eq!(
+            link.attribute("href"),
+            Some("https://myblog.hypertxt.io/first/")
+        );
+        assert!(text(first, "summary")
+            .unwrap()
+            .contains("Summary &amp; more"));
+        assert!(text(first, "content")
+            .unwrap()
+            .replace("&#x2F;", "/")
+            .contains("<em>text</em>"));
+
+        let xml = read("tags/rust-lang/atom.xml");
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let entries = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("entry"))
+            .count();
+        assert_eq!(entries, 1);
+
+        let xml = read("rss.xml");
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let rss = doc.root_element();
+        assert_eq!(rss.tag_name().name(), "rss");
+        assert_eq!(rss.attribute("version"), Some("2.0"));
+        let channel = rss.children().find(|n| n.has_tag_name("channel")).unwrap();
+        assert_eq!(text(channel, "title").as_deref(), Some("My <Blog>"));
+        let items: Vec<_> = channel
+            .children()
+            .filter(|n| n.has_tag_name("item"))
+            .collect();
+        assert_eq!(items.len(), 2);
+        assert_eq!(text(items[1], "title").as_deref(), Some("First <post>"));
+        assert_eq!(
+            text(items[1], "pubDate").as_deref(),
+            Some("Wed, 20 Nov 2024 00:00:00 +0000")
+        );
+        assert_eq!(
+            text(items[1], "link").as_deref(),
+            Some("https://myblog.hypertxt.io/first/")
+        );
+        assert!(text(items[1], "description")
+            .unwrap()
+            .replace("&#x2F;", "/")
+            .contains("<em>text</em>"));
+    }
+
+    #[test]
+    fn test_incremental_build_only_touches_changed_post() {
+        let t = test_blog();
+        write_content(
+            &t.blog,
+            "100.md",
+            &post("First", "2024-11-20", "first"),
+            "One",
+        );
+        write_content(
+            &t.blog,
+            "101.md",
+            &post("Second", "2024-11-21", "second"),
+            "Two",
+        );
+        let r = renderer();
+        r.build(&t.blog, &t.out_dir, Changes::All).unwrap();
+
+        // If the second post got re-rendered, this would be overwritten
+        fs::write(t.out_dir.join("second/index.html"), "untouched").unwrap();
+        let mut edited = post("First, edited", "2024-11-20", "first-edited");
+        edited.aliases = vec!["/first/".to_string()];
+        edited.taxonomies = BTreeMap::from([("categories".to_string(), vec!["Code".to_string()])]);
+        write_content(&t.blog, "100.md", &edited, "One, edited");
+        r.build(&t.blog, &t.out_dir, Changes::post(100)).unwrap();
+
+        assert_eq!(read(&t, "second/index.html"), "untouched");
+        assert!(read(&t, "first-edited/index.html").contains("One, edited"));
+        assert!(
+            read(&t, "first/index.html").contains("url=https://myblog.hypertxt.io/first-edited/")
+        );
+        assert!(read(&t, "index.html").contains("First, edited"));
+        assert!(read(&t, "categories/code/index.html").contains("First, edited"));
+
+        // Drafting a post needs the full build to take it down
+        let mut drafted = post("Second", "2024-11-21", "second");
+        drafted.draft = true;
+        write_content(&t.blog, "101.md", &drafted, "Two");
+        r.build(&t.blog, &t.out_dir, Changes::post(101)).unwrap();
+        assert!(!t.out_dir.join("second").exists());
+    }
+
+    #[test]
+    fn test_native_stops_at_timeout() {
+        let t = test_blog();
+        write_content(
+            &t.blog,
+            "100.md",
+            &post("First", "2024-11-20", "first"),
+            "One",
+        );
+        let renderer = NativeRenderer {
+            timeout: Duration::ZERO,
+            ..renderer()
+        };
+        let err = BuildError::from_error(
+            renderer
+                .build(&t.blog, &t.out_dir, Changes::All)
+                .unwrap_err(),
+        );
+        assert_eq!(err.reason, "the build took longer than 0 seconds");
+        assert_eq!(err.file, None);
+    }
+
+    #[test]
+    fn test_failed_full_build_leaves_the_last_one() {
+        let t = test_blog();
+        write_content(
+            &t.blog,
+            "100.md",
+            &post("First", "2024-11-20", "first"),
+            "One",
+        );
+        renderer().build(&t.blog, &t.out_dir, Changes::All).unwrap();
+        let slow = NativeRenderer {
+            timeout: Duration::ZERO,
+            ..renderer()
+        };
+        slow.build(&t.blog, &t.out_dir, Changes::All).unwrap_err();
+        assert!(read(&t, "first/index.html").contains("One"));
+        // Without any scratch dirs left next to it
+        assert_eq!(
+            fs::read_dir(t.out_dir.parent().unwrap()).unwrap().count(),
+            2
+        );
+
+        write_content(
+            &t.blog,
+            "100.md",
+            &post("First", "2024-11-20", "first"),
+            "Changed",
+        );
+        renderer().build(&t.blog, &t.out_dir, Changes::All).unwrap();
+        assert!(read(&t, "first/index.html").contains("Changed"));
+        assert_eq!(
+            fs::read_dir(t.out_dir.parent().unwrap()).unwrap().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_merge_changes() {
+        assert_eq!(
+            Changes::post(1).merge(Changes::post(2)),
+            Changes::Posts(BTreeSet::from([1, 2]))
+        );
+        assert_eq!(Changes::post(1).merge(Changes::post(1)), Changes::post(1));
+        assert_eq!(Changes::post(1).merge(Changes::All), Changes::All);
+        assert_eq!(Changes::All.merge(Changes::post(1)), Changes::All);
+    }
+
+    #[test]
+    fn test_parse_zola_front_matter_error() {
+        let output = "Building site...\n\
+            Error: Failed to build the site\n\
+            Error: Error when parsing front matter of page `/blogs/me/content/12.md`\n\
+            Error: Reason: TOML parse error at line 3, column 8\n  \
+              |\n\
+            3 | title = oops\n  \
+              |         ^\n\
+            invalid string\n";
+        let err = parse_zola_output(output);
+        assert_eq!(err.file.as_deref(), Some("content/12.md"));
+        assert_eq!(err.post_id(), Some(12));
+        assert_eq!(err.line, Some(3));
+        assert!(err.front_matter);
+        assert_eq!(
+            err.reason,
+            "TOML parse error at line 3, column 8: invalid string"
+        );
+        assert_eq!(err.source_line.as_deref(), Some("title = oops"));
+        // The line's in the front matter we wrote, not in the message
+        assert_eq!(
+            err.explain(Some("Hello"), Some("# Hello\n\ntitle = oops")),
+            "Your post 'Hello' has invalid front matter: TOML parse error at line 3, column 8: invalid string"
+        );
+    }
+
+    #[test]
+    fn test_parse_zola_shortcode_error() {
+        let output = "Error: Failed to build the site\n\
+            Error: Failed to render content of /srv/content/blogs/me/content/pages/7.md\n\
+            Error: Reason: Failed to render '__tera_one_off'\n\
+            Error: Reason: Variable `name` not found in context while rendering '__tera_one_off'\n";
+        let err = parse_zola_output(output);
+        assert_eq!(err.file.as_deref(), Some("content/pages/7.md"));
+        assert_eq!(err.post_id(), Some(7));
+        assert_eq!(err.line, None);
+        assert!(!err.front_matter);
+        let explained = err.explain(Some("About"), None);
+        assert!(explained.starts_with(
+            "Your post 'About' couldn't be built: Variable `name` not found in context"
+        ));
+        assert!(explained.contains("shortcode"));
+
+        let err = parse_zola_output("Error: Failed to build the site\nError: Reason: oh no\n");
+        assert_eq!(err.file, None);
+        assert_eq!(
+            err.explain(None, None),
+            "Your blog couldn't be built: oh no"
+        );
+        assert_eq!(
+            parse_zola_output("").reason,
+            "zola failed without saying why"
+        );
+    }
+
+    #[test]
+    fn test_parse_zola_tera_error_line() {
+        let output = "Error: Failed to build the site\n\
+            Error: Failed to render content of /srv/content/blogs/me/content/7.md\n\
+            Error: Reason: Failed to parse '__tera_one_off'\n\
+            Error: Reason:  --> 5:4\n  \
+              |\n\
+            5 | {{ oops(\n  \
+              |    ^---\n";
+        let err = parse_zola_output(output);
+        assert_eq!(err.line, Some(5));
+        assert_eq!(err.source_line.as_deref(), Some("{{ oops("));
+        // The message is shorter than the content file: no front matter,
+        // and no metadata lines
+        let message = "TITLE: Hi\n\nSome text\n{{ oops(";
+        assert!(err
+            .explain(Some("Hi"), Some(message))
+            .starts_with("Your post 'Hi' couldn't be built near line 4: "));
+        assert!(err
+            .explain(Some("Hi"), Some("Not in here"))
+            .starts_with("Your post 'Hi' couldn't be built: "));
+    }
+
+    #[test]
+    fn test_line_number() {
+        assert_eq!(line_number("TOML parse error at line 3, column 8"), Some(3));
+        assert_eq!(line_number("bad inline 4, then line 6"), Some(6));
+        assert_eq!(line_number("bad inline 4"), None);
+        assert_eq!(line_number(" --> 2:1"), Some(2));
+        assert_eq!(line_number("Line 2"), None);
+    }
+
+    #[test]
+    fn test_native_front_matter_error() {
+        let t = test_blog();
+        fs::write(
+            t.blog.join("content/12.md").unwrap(),
+            "+++\ntitle = \"Hi\"\ndate = \"2024-11-20\"\ndraft = nope\n+++\n\nBody",
+        )
+        .unwrap();
+        let err = BuildError::from_error(
+            renderer()
+                .build(&t.blog, &t.out_dir, Changes::All)
+                .unwrap_err(),
+        );
+        assert_eq!(err.post_id(), Some(12));
+        assert_eq!(err.line, Some(3));
+        assert!(err.front_matter);
+    }
+
+    #[test]
+    fn test_split_front_matter() {
+        assert_eq!(
+            split_front_matter("+++\na = 1\n+++\n\nBody"),
+            ("a = 1", "Body")
+        );
+        assert_eq!(split_front_matter("+++\n+++\n\nBody"), ("", "Body"));
+        assert_eq!(
+            split_front_matter("No front matter"),
+            ("", "No front matter")
+        );
+    }
+}
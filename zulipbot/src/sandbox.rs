@@ -0,0 +1,539 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs};
+
+// Builds run zola on content and config that anyone can write, so zola runs
+// in a sandbox. It always gets limits on CPU time, memory and how much it can
+// write. Where the kernel lets us make unprivileged user namespaces, it also
+// gets no network, and a filesystem with nothing in it but the system's
+// binaries and libraries, the blog, the themes and the output dir (the only
+// thing it can write to).
+
+// System paths builds need, e.g. for zola's shared libraries
+const SYSTEM_PATHS: [&str; 6] = [
+    "/usr",
+    "/bin",
+    "/lib",
+    "/lib64",
+    "/etc/ld.so.cache",
+    "/etc/localtime",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub cpu_secs: u64,
+    pub memory_bytes: u64,
+    // For each file written
+    pub file_bytes: u64,
+    // For the whole built site
+    pub output_bytes: u64,
+}
+
+impl Limits {
+    // From SANDBOX_CPU_SECS (60), SANDBOX_MEMORY_MB (2048), SANDBOX_FILE_MB
+    // (50) and SANDBOX_OUTPUT_MB (500).
+    pub fn from_env() -> Limits {
+        let var = |name: &str, default: u64| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        const MB: u64 = 1024 * 1024;
+        Limits {
+            cpu_secs: var("SANDBOX_CPU_SECS", 60),
+            memory_bytes: var("SANDBOX_MEMORY_MB", 2048) * MB,
+            file_bytes: var("SANDBOX_FILE_MB", 50) * MB,
+            output_bytes: var("SANDBOX_OUTPUT_MB", 500) * MB,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Isolation {
+    // Just the limits
+    Off,
+    // Namespaces if the kernel allows them, otherwise just the limits
+    Auto,
+    // Namespaces, or the build fails
+    Strict,
+}
+
+impl Isolation {
+    // SANDBOX is "off", "auto" (the default) or "strict".
+    pub fn from_env() -> Result<Isolation, String> {
+        match env::var("SANDBOX").as_deref() {
+            Ok("auto") | Err(_) => Ok(Isolation::Auto),
+            Ok("off") => Ok(Isolation::Off),
+            Ok("strict") => Ok(Isolation::Strict),
+            Ok(other) => Err(format!(
+                "unknown SANDBOX {:?}, expected \"off\", \"auto\" or \"strict\"",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    pub limits: Limits,
+    pub isolation: Isolation,
+    // Visible inside at the same paths, read-only
+    readable: Vec<PathBuf>,
+    // Visible inside at the same paths
+    writable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    pub fn new(limits: Limits, isolation: Isolation) -> Sandbox {
+        Sandbox {
+            limits,
+            isolation,
+            readable: SYSTEM_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .filter(|p| p.exists())
+                .collect(),
+            writable: Vec::new(),
+        }
+    }
+
+    pub fn from_env() -> Result<Sandbox, String> {
+        Ok(Sandbox::new(Limits::from_env(), Isolation::from_env()?))
+    }
+
+    pub fn read<P: AsRef<Path>>(mut self, path: P) -> Sandbox {
+        self.readable.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn write<P: AsRef<Path>>(mut self, path: P) -> Sandbox {
+        self.writable.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    // Sets `cmd` up to run in the sandbox. Its program needs to be an
+    // absolute path that's readable inside, and the returned guard has to be
+    // kept until it exits.
+    pub fn apply(&self, cmd: &mut Command) -> Result<Guard, String> {
+        let guard = Guard {
+            root: tempfile::tempdir()
+                .map_err(|e| format!("failed to make sandbox root: {:?}", e))?,
+        };
+        let plan = Plan::new(self, cmd, guard.root.path())?;
+        // Safe because `enter` only makes system calls, with everything it
+        // needs allocated up front
+        unsafe {
+            cmd.pre_exec(move || plan.enter());
+        }
+        Ok(guard)
+    }
+}
+
+// The directory the sandbox's filesystem is mounted on
+pub struct Guard {
+    root: tempfile::TempDir,
+}
+
+// Everything the child does between fork and exec to lock itself down,
+// worked out beforehand.
+struct Plan {
+    limits: Vec<(libc::__rlimit_resource_t, u64)>,
+    isolation: Isolation,
+    uid_map: CString,
+    gid_map: CString,
+    root: CString,
+    old_root: CString,
+    // In order, so that parents are mounted before anything inside them
+    binds: Vec<Bind>,
+    workdir: Option<CString>,
+}
+
+struct Bind {
+    source: CString,
+    target: CString,
+    // The target's directory and its parents inside the new root, outermost
+    // first, which may need creating
+    dirs: Vec<CString>,
+    is_file: bool,
+    read_only: bool,
+    // Flags the source is mounted with, which a remount has to keep
+    flags: libc::c_ulong,
+}
+
+fn cstring<P: AsRef<Path>>(path: P) -> Result<CString, String> {
+    CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|_| format!("{:?} has a NUL in it", path.as_ref()))
+}
+
+impl Plan {
+    fn new(sandbox: &Sandbox, cmd: &Command, root: &Path) -> Result<Plan, String> {
+        let limits = &sandbox.limits;
+        let mut plan = Plan {
+            limits: vec![
+                (libc::RLIMIT_CPU, limits.cpu_secs),
+                (libc::RLIMIT_AS, limits.memory_bytes),
+                (libc::RLIMIT_FSIZE, limits.file_bytes),
+                (libc::RLIMIT_CORE, 0),
+            ],
+            isolation: sandbox.isolation,
+            // Keep being the same user inside, rather than nobody
+            uid_map: cstring(format!("{0} {0} 1", unsafe { libc::getuid() }))?,
+            gid_map: cstring(format!("{0} {0} 1", unsafe { libc::getgid() }))?,
+            root: cstring(root)?,
+            old_root: cstring(root.join(".old"))?,
+            binds: Vec::new(),
+            workdir: cmd.get_current_dir().map(cstring).transpose()?,
+        };
+
+        let mut paths: Vec<(&PathBuf, bool)> = sandbox
+            .readable
+            .iter()
+            .map(|p| (p, true))
+            .chain(sandbox.writable.iter().map(|p| (p, false)))
+            .collect();
+        // Parents before anything mounted inside them
+        paths.sort_by_key(|(p, _)| p.components().count());
+
+        for (path, read_only) in paths {
+            if !path.is_absolute() {
+                return Err(format!("sandbox path {:?} isn't absolute", path));
+            }
+            let meta = fs::metadata(path).map_err(|e| format!("can't use {:?}: {:?}", path, e))?;
+            let target = root.join(path.strip_prefix("/").unwrap_or(path));
+            let parents = if meta.is_dir() {
+                target.as_path()
+            } else {
+                target.parent().unwrap_or(root)
+            };
+            let mut dirs = Vec::new();
+            for dir in parents.ancestors().collect::<Vec<_>>().into_iter().rev() {
+                if dir.starts_with(root) && dir != root {
+                    dirs.push(cstring(dir)?);
+                }
+            }
+            plan.binds.push(Bind {
+                source: cstring(path)?,
+                target: cstring(&target)?,
+                dirs,
+                is_file: !meta.is_dir(),
+                read_only,
+                flags: mount_flags(path)?,
+            });
+        }
+        Ok(plan)
+    }
+
+    // Runs in the child, so it mustn't allocate.
+    fn enter(&self) -> io::Result<()> {
+        for (resource, limit) in &self.limits {
+            let rlimit = libc::rlimit {
+                rlim_cur: *limit,
+                rlim_max: *limit,
+            };
+            check(unsafe { libc::setrlimit(*resource, &rlimit) })?;
+        }
+        if self.isolation == Isolation::Off {
+            return Ok(());
+        }
+
+        let flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET;
+        if unsafe { libc::unshare(flags) } != 0 {
+            if self.isolation == Isolation::Strict {
+                return Err(io::Error::last_os_error());
+            }
+            let warning = b"sandbox: no user namespaces here, so the build isn't isolated\n";
+            unsafe { libc::write(2, warning.as_ptr().cast(), warning.len()) };
+            return Ok(());
+        }
+        write_file(c"/proc/self/setgroups", c"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+
+        unsafe {
+            // Nothing we mount should leak back out
+            check(libc::mount(
+                std::ptr::null(),
+                c"/".as_ptr(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+                std::ptr::null(),
+            ))?;
+            check(libc::mount(
+                c"tmpfs".as_ptr(),
+                self.root.as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                c"mode=0755".as_ptr().cast(),
+            ))?;
+            for bind in &self.binds {
+                // Mount points, unless they're already there from a mount
+                // further out
+                for dir in &bind.dirs {
+                    if libc::mkdir(dir.as_ptr(), 0o755) != 0
+                        && io::Error::last_os_error().raw_os_error() != Some(libc::EEXIST)
+                    {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if bind.is_file {
+                    let fd =
+                        libc::open(bind.target.as_ptr(), libc::O_CREAT | libc::O_RDONLY, 0o644);
+                    check(fd)?;
+                    libc::close(fd);
+                }
+                check(libc::mount(
+                    bind.source.as_ptr(),
+                    bind.target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REC,
+                    std::ptr::null(),
+                ))?;
+                if bind.read_only {
+                    check(libc::mount(
+                        std::ptr::null(),
+                        bind.target.as_ptr(),
+                        std::ptr::null(),
+                        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | bind.flags,
+                        std::ptr::null(),
+                    ))?;
+                }
+            }
+
+            // Swap the new root in, and drop the old one entirely
+            check(libc::mkdir(self.old_root.as_ptr(), 0o700))?;
+            check(libc::syscall(
+                libc::SYS_pivot_root,
+                self.root.as_ptr(),
+                self.old_root.as_ptr(),
+            ) as libc::c_int)?;
+            check(libc::chdir(c"/".as_ptr()))?;
+            check(libc::umount2(c"/.old".as_ptr(), libc::MNT_DETACH))?;
+            check(libc::rmdir(c"/.old".as_ptr()))?;
+            if let Some(workdir) = &self.workdir {
+                check(libc::chdir(workdir.as_ptr()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn write_file(path: &std::ffi::CStr, contents: &std::ffi::CStr) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+        check(fd)?;
+        let bytes = contents.to_bytes();
+        let written = libc::write(fd, bytes.as_ptr().cast(), bytes.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// The flags a path's mount has that we aren't allowed to drop when we
+// remount it read-only inside a user namespace.
+fn mount_flags(path: &Path) -> Result<libc::c_ulong, String> {
+    let c_path = cstring(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(format!(
+            "failed to stat {:?}: {:?}",
+            path,
+            io::Error::last_os_error()
+        ));
+    }
+    let mut flags = libc::MS_NOSUID | libc::MS_NODEV;
+    for (st, ms) in [
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ] {
+        if stat.f_flag & st != 0 {
+            flags |= ms;
+        }
+    }
+    Ok(flags)
+}
+
+// Finds a program on PATH, since inside the sandbox it has to be run by its
+// full path.
+pub fn find_program(name: &str) -> Result<PathBuf, String> {
+    let path = env::var_os("PATH").ok_or("PATH isn't set")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|p| p.is_file())
+        .and_then(|p| p.canonicalize().ok())
+        .ok_or_else(|| format!("couldn't find {} on PATH", name))
+}
+
+// How much is in a directory, for checking a build against its output
+// limit. Symlinks aren't followed.
+pub fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| match e.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&e.path()),
+            Ok(t) if t.is_file() => e.metadata().map(|m| m.len()).unwrap_or(0),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            cpu_secs: 7,
+            memory_bytes: 1024 * 1024 * 1024,
+            file_bytes: 4096,
+            output_bytes: 1024 * 1024,
+        }
+    }
+
+    fn sh(sandbox: &Sandbox, script: &str, workdir: &Path) -> io::Result<std::process::Output> {
+        let mut cmd = Command::new(find_program("sh").unwrap());
+        cmd.args(["-c", script]).current_dir(workdir);
+        let _guard = sandbox.apply(&mut cmd).unwrap();
+        cmd.output()
+    }
+
+    #[test]
+    fn test_limits() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::new(limits(), Isolation::Off);
+        let out = sh(&sandbox, "cat /proc/self/limits", tmp.path()).unwrap();
+        let limits = String::from_utf8(out.stdout).unwrap();
+        let line = |name: &str| {
+            limits
+                .lines()
+                .find(|l| l.starts_with(name))
+                .unwrap()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        assert_eq!(line("Max cpu time"), "Max cpu time 7 7 seconds");
+        assert_eq!(line("Max file size"), "Max file size 4096 4096 bytes");
+
+        // Writing past the file size limit fails
+        let out = sh(
+            &sandbox,
+            "head -c 10000 /dev/zero > big; echo $?",
+            tmp.path(),
+        )
+        .unwrap();
+        assert_ne!(String::from_utf8(out.stdout).unwrap().trim(), "0");
+        assert!(fs::metadata(tmp.path().join("big")).unwrap().len() <= 4096);
+    }
+
+    #[test]
+    fn test_isolated_filesystem() {
+        let tmp = tempfile::tempdir().unwrap();
+        let blog = tmp.path().join("blog");
+        let out = tmp.path().join("out");
+        fs::create_dir_all(&blog).unwrap();
+        fs::create_dir_all(&out).unwrap();
+        fs::write(blog.join("post.md"), "Hello").unwrap();
+        fs::write(tmp.path().join("secret"), "secret").unwrap();
+
+        let sandbox = Sandbox::new(limits(), Isolation::Strict)
+            .read(&blog)
+            .write(&out);
+        let script = format!(
+            "cat post.md; echo; cat {secret}; echo; touch post2.md; echo made > {out}/index.html; ls /",
+            secret = tmp.path().join("secret").display(),
+            out = out.display()
+        );
+        let result = match sh(&sandbox, &script, &blog) {
+            Ok(result) => result,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("no user namespaces here, skipping: {:?}", e);
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let stdout = String::from_utf8(result.stdout).unwrap();
+        let mut lines = stdout.lines();
+        assert_eq!(lines.next(), Some("Hello"));
+        // Nothing outside the blog can be read
+        assert_eq!(lines.next(), Some(""));
+        assert!(!blog.join("post2.md").exists());
+        assert_eq!(
+            fs::read_to_string(out.join("index.html")).unwrap(),
+            "made\n"
+        );
+        let root: Vec<&str> = lines.collect();
+        assert!(
+            !root.contains(&"root") && !root.contains(&"home"),
+            "{:?}",
+            root
+        );
+        assert!(root.contains(&"usr"), "{:?}", root);
+    }
+
+    #[test]
+    fn test_nested_and_file_binds() {
+        // Like a scratch build: a read-only blog inside a writable dir, and
+        // a program that's a single file
+        let tmp = tempfile::tempdir().unwrap();
+        let scratch = tmp.path().join("scratch");
+        let blog = scratch.join("blog");
+        fs::create_dir_all(&blog).unwrap();
+        let tool = tmp.path().join("tool.txt");
+        fs::write(&tool, "tool").unwrap();
+
+        let sandbox = Sandbox::new(limits(), Isolation::Strict)
+            .write(&scratch)
+            .read(&blog)
+            .read(&tool);
+        let script = format!(
+            "cat {tool}; echo; touch ../made; touch not-made; ls {tmp}",
+            tool = tool.display(),
+            tmp = tmp.path().display()
+        );
+        let result = match sh(&sandbox, &script, &blog) {
+            Ok(result) => result,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("no user namespaces here, skipping: {:?}", e);
+                return;
+            }
+            Err(e) => panic!("{:?}", e),
+        };
+        let stdout = String::from_utf8(result.stdout).unwrap();
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[0], "tool");
+        let mut listed = lines[1..].to_vec();
+        listed.sort();
+        assert_eq!(listed, vec!["scratch", "tool.txt"]);
+        assert!(scratch.join("made").exists());
+        assert!(!blog.join("not-made").exists());
+    }
+
+    #[test]
+    fn test_dir_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::create_dir(tmp.path().join("sub")).unwrap();
+        fs::write(tmp.path().join("a"), "12345").unwrap();
+        fs::write(tmp.path().join("sub/b"), "123").unwrap();
+        assert_eq!(dir_size(tmp.path()), 8);
+    }
+}
@@ -0,0 +1,778 @@
+use redb::{Database, ReadableTable, TableDefinition, TableHandle, WriteTransaction};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::bloggen;
+use crate::builds;
+
+// The database records which version of the schema it's at, and is upgraded
+// to the latest one on startup, so that changing what a table stores comes
+// with a migration rather than a one-off script.
+//
+// 1: everything from before the schema was versioned
+// 2: every post has its slugs recorded
+// 3: posts are stored as a posts::PostRecord, rather than just their content
+// 4: posts have revisions
+// 5: each post is in its author's list of posts once, in the order they were
+//    published
+
+// Things about the database itself, rather than any blog
+const METADATA_TABLE: TableDefinition<&str, u64> = TableDefinition::new("metadata");
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+// Upgrades a database from one version to the next. Migrations spell out the
+// tables they use, and the records in them, as they were at the time, so that
+// they keep working after those change again.
+type Migration = fn(&WriteTransaction) -> Result<(), Box<dyn Error>>;
+
+// MIGRATIONS[n] upgrades version n + 1 to n + 2.
+const MIGRATIONS: &[Migration] = &[
+    record_old_slugs,
+    make_post_records,
+    record_first_revisions,
+    index_posts_by_time,
+];
+
+pub const CURRENT_VERSION: u64 = MIGRATIONS.len() as u64 + 1;
+
+// Brings the database up to the current version and makes sure every table
+// exists, all in one transaction, so a migration that fails leaves the
+// database as it was. Returns the version it started at.
+pub fn migrate(db: &Database) -> Result<u64, Box<dyn Error>> {
+    run_migrations(db, MIGRATIONS)
+}
+
+fn run_migrations(db: &Database, migrations: &[Migration]) -> Result<u64, Box<dyn Error>> {
+    let latest = migrations.len() as u64 + 1;
+    let txn = db.begin_write()?;
+
+    let from = match read_version(&txn)? {
+        Some(version) => version,
+        // Brand new databases start out at the latest version
+        None if txn.list_tables()?.next().is_none() => latest,
+        None => 1,
+    };
+    // Versions start at 1, so 0 can only be a corrupt database
+    if from == 0 {
+        return Err("the database is at schema version 0, which doesn't exist".into());
+    }
+    if from > latest {
+        return Err(format!(
+            "the database is at schema version {}, but this bot only knows up to version {}",
+            from, latest
+        )
+        .into());
+    }
+
+    for (version, migration) in (from..latest).zip(&migrations[from as usize - 1..]) {
+        migration(&txn).map_err(|e| {
+            format!(
+                "failed to migrate the database from version {} to {}: {}",
+                version,
+                version + 1,
+                e
+            )
+        })?;
+    }
+
+    create_tables(&txn)?;
+    txn.open_table(METADATA_TABLE)?
+        .insert(SCHEMA_VERSION_KEY, &latest)?;
+    txn.commit()?;
+    Ok(from)
+}
+
+fn read_version(txn: &WriteTransaction) -> Result<Option<u64>, Box<dyn Error>> {
+    if !txn
+        .list_tables()?
+        .any(|t| t.name() == METADATA_TABLE.name())
+    {
+        return Ok(None);
+    }
+    let version = txn
+        .open_table(METADATA_TABLE)?
+        .get(SCHEMA_VERSION_KEY)?
+        .map(|v| v.value());
+    Ok(version)
+}
+
+fn create_tables(txn: &WriteTransaction) -> Result<(), Box<dyn Error>> {
+    txn.open_table(crate::USER_ID_TO_SUBDOMAIN_TABLE)?;
+    txn.open_table(crate::SUBDOMAIN_TO_USER_ID_TABLE)?;
+    txn.open_table(crate::USER_ID_TO_SETTINGS_TABLE)?;
+    txn.open_table(crate::USER_POSTS_TABLE)?;
+    txn.open_table(crate::POST_ID_TO_RECORD_TABLE)?;
+    txn.open_table(crate::POST_ID_TO_SLUGS_TABLE)?;
+    txn.open_table(crate::USER_SLUG_TO_POST_ID_TABLE)?;
+    txn.open_table(crate::POST_REV_TO_REVISION_TABLE)?;
+    txn.open_table(crate::DOMAIN_TO_USER_ID_TABLE)?;
+    txn.open_table(crate::USER_ID_TO_PENDING_DOMAIN_TABLE)?;
+    txn.open_table(builds::QUEUED_BUILDS_TABLE)?;
+    Ok(())
+}
+
+// 1 -> 2: posts from before slugs existed were served at /{post_id}/, and
+// only got that recorded as their slug when they were next edited. Record it
+// for all of them, so that every post has a slug.
+fn record_old_slugs(txn: &WriteTransaction) -> Result<(), Box<dyn Error>> {
+    const POST_IDS: TableDefinition<u64, Vec<u64>> = TableDefinition::new("user_id_to_post_ids");
+    const POSTS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_post");
+    const SLUGS: TableDefinition<u64, Vec<String>> = TableDefinition::new("post_id_to_slugs");
+    const OWNERS: TableDefinition<(u64, &str), u64> = TableDefinition::new("user_slug_to_post_id");
+
+    let post_ids_tbl = txn.open_table(POST_IDS)?;
+    let posts_tbl = txn.open_table(POSTS)?;
+    let mut slugs_tbl = txn.open_table(SLUGS)?;
+    let mut owners_tbl = txn.open_table(OWNERS)?;
+
+    for entry in post_ids_tbl.iter()? {
+        let (user_id, post_ids) = entry?;
+        let user_id = user_id.value();
+        for post_id in post_ids.value() {
+            if posts_tbl.get(&post_id)?.is_none() || slugs_tbl.get(&post_id)?.is_some() {
+                continue;
+            }
+            let slug = post_id.to_string();
+            // Someone could have picked another post's ID as a slug since.
+            // That post keeps it, and this one gets a new slug when it's
+            // next edited.
+            if owners_tbl.get((user_id, slug.as_str()))?.is_some() {
+                continue;
+            }
+            owners_tbl.insert((user_id, slug.as_str()), &post_id)?;
+            slugs_tbl.insert(&post_id, vec![slug])?;
+        }
+    }
+    Ok(())
+}
+
+// 2 -> 3: the content of each post, its timestamp and its slugs become a
+// PostRecord, and the tables that only had the content and timestamps go.
+// Content that isn't in anyone's list of posts was never shown anywhere, so
+// it goes too.
+fn make_post_records(txn: &WriteTransaction) -> Result<(), Box<dyn Error>> {
+    // A posts::PostRecord as it was at 3
+    #[derive(Serialize)]
+    struct PostRecord {
+        user_id: u64,
+        author: String,
+        content: String,
+        title: String,
+        timestamp: Option<u64>,
+        edited_at: Vec<u64>,
+        stream_id: Option<u64>,
+        topic: String,
+        slugs: Vec<String>,
+        tags: Vec<String>,
+        categories: Vec<String>,
+        draft: bool,
+        page: Option<String>,
+        assets: Vec<String>,
+        build: BuildState,
+    }
+    #[derive(Serialize)]
+    #[serde(tag = "state", rename_all = "snake_case")]
+    enum BuildState {
+        Built,
+    }
+
+    const POST_IDS: TableDefinition<u64, Vec<u64>> = TableDefinition::new("user_id_to_post_ids");
+    const POSTS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_post");
+    const TIMESTAMPS: TableDefinition<u64, u64> = TableDefinition::new("post_id_to_timestamp");
+    const SLUGS: TableDefinition<u64, Vec<String>> = TableDefinition::new("post_id_to_slugs");
+    const RECORDS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_record");
+
+    {
+        let post_ids_tbl = txn.open_table(POST_IDS)?;
+        let posts_tbl = txn.open_table(POSTS)?;
+        let timestamps_tbl = txn.open_table(TIMESTAMPS)?;
+        let slugs_tbl = txn.open_table(SLUGS)?;
+        let mut records_tbl = txn.open_table(RECORDS)?;
+
+        for entry in post_ids_tbl.iter()? {
+            let (user_id, post_ids) = entry?;
+            for post_id in post_ids.value() {
+                if records_tbl.get(&post_id)?.is_some() {
+                    continue;
+                }
+                let Some(content) = posts_tbl.get(&post_id)? else {
+                    continue;
+                };
+                let content = content.value().to_string();
+                let timestamp = timestamps_tbl.get(&post_id)?.map(|v| v.value());
+                // The rest is worked out from the content, like publishing
+                // it does
+                let parsed = bloggen::parse_raw_message(&content, timestamp.unwrap_or(0));
+                let record = PostRecord {
+                    user_id: user_id.value(),
+                    author: String::new(),
+                    content,
+                    title: parsed.title,
+                    timestamp,
+                    edited_at: Vec::new(),
+                    stream_id: None,
+                    topic: String::new(),
+                    slugs: slugs_tbl
+                        .get(&post_id)?
+                        .map(|v| v.value())
+                        .unwrap_or_default(),
+                    tags: parsed.metadata.tags,
+                    categories: parsed.metadata.categories,
+                    draft: parsed.metadata.draft,
+                    page: parsed.metadata.page,
+                    assets: parsed.image_urls,
+                    // It's live already
+                    build: BuildState::Built,
+                };
+                records_tbl.insert(&post_id, serde_json::to_string(&record)?.as_str())?;
+            }
+        }
+    }
+
+    txn.delete_table(POSTS)?;
+    txn.delete_table(TIMESTAMPS)?;
+    Ok(())
+}
+
+// 3 -> 4: what each post says now becomes its first revision. Anything it
+// said before that is gone.
+fn record_first_revisions(txn: &WriteTransaction) -> Result<(), Box<dyn Error>> {
+    // What this needs of a posts::PostRecord, as it was at 3
+    #[derive(Deserialize)]
+    struct PostRecord {
+        content: String,
+        title: String,
+        timestamp: Option<u64>,
+        #[serde(default)]
+        edited_at: Vec<u64>,
+    }
+    // A posts::Revision as it was at 4
+    #[derive(Serialize)]
+    struct Revision {
+        timestamp: u64,
+        title: String,
+        content: String,
+    }
+
+    const RECORDS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_record");
+    const REVISIONS: TableDefinition<(u64, u64), &str> =
+        TableDefinition::new("post_rev_to_revision");
+
+    let records_tbl = txn.open_table(RECORDS)?;
+    let mut revisions_tbl = txn.open_table(REVISIONS)?;
+    for entry in records_tbl.iter()? {
+        let (post_id, record) = entry?;
+        let post_id = post_id.value();
+        if revisions_tbl.get((post_id, 1))?.is_some() {
+            continue;
+        }
+        let record: PostRecord = serde_json::from_str(record.value())?;
+        let revision = Revision {
+            // When it was published, or last edited
+            timestamp: record
+                .edited_at
+                .last()
+                .copied()
+                .or(record.timestamp)
+                .unwrap_or(0),
+            title: record.title,
+            content: record.content,
+        };
+        let revision = serde_json::to_string(&revision)?;
+        revisions_tbl.insert((post_id, 1), revision.as_str())?;
+    }
+    Ok(())
+}
+
+// 4 -> 5: each user's list of posts had a post added to it again every time
+// it was edited, and was in the order they were last edited in. It becomes
+// an index of posts by when they were published.
+fn index_posts_by_time(txn: &WriteTransaction) -> Result<(), Box<dyn Error>> {
+    // What this needs of a posts::PostRecord, as it was at 4
+    #[derive(Deserialize)]
+    struct PostRecord {
+        timestamp: Option<u64>,
+    }
+
+    const POST_IDS: TableDefinition<u64, Vec<u64>> = TableDefinition::new("user_id_to_post_ids");
+    const RECORDS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_record");
+    const USER_POSTS: TableDefinition<(u64, u64, u64), ()> = TableDefinition::new("user_posts");
+
+    {
+        let post_ids_tbl = txn.open_table(POST_IDS)?;
+        let records_tbl = txn.open_table(RECORDS)?;
+        let mut user_posts_tbl = txn.open_table(USER_POSTS)?;
+
+        for entry in post_ids_tbl.iter()? {
+            let (user_id, post_ids) = entry?;
+            let user_id = user_id.value();
+            for post_id in post_ids.value() {
+                // Posts without a record were never published
+                let Some(record) = records_tbl.get(&post_id)? else {
+                    continue;
+                };
+                let record: PostRecord = serde_json::from_str(record.value())?;
+                user_posts_tbl.insert((user_id, record.timestamp.unwrap_or(0), post_id), ())?;
+            }
+        }
+    }
+
+    txn.delete_table(POST_IDS)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::posts::{BuildState, PostRecord, Revision};
+    use std::path::Path;
+
+    // A copy of a fixture database, so that migrating it doesn't change the
+    // fixture.
+    fn fixture_db(version: u64) -> (tempfile::TempDir, Database) {
+        let tmp = tempfile::tempdir().unwrap();
+        let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/db")
+            .join(format!("v{}.redb", version));
+        let path = tmp.path().join("test.db");
+        std::fs::copy(fixture, &path).unwrap();
+        let db = Database::create(path).unwrap();
+        (tmp, db)
+    }
+
+    fn version(db: &Database) -> Option<u64> {
+        let txn = db.begin_read().unwrap();
+        let tbl = txn.open_table(METADATA_TABLE).unwrap();
+        let version = tbl.get(SCHEMA_VERSION_KEY).unwrap().map(|v| v.value());
+        version
+    }
+
+    fn record(db: &Database, post_id: u64) -> Option<PostRecord> {
+        let txn = db.begin_read().unwrap();
+        let tbl = txn.open_table(crate::POST_ID_TO_RECORD_TABLE).unwrap();
+        let record = tbl
+            .get(&post_id)
+            .unwrap()
+            .map(|v| serde_json::from_str(v.value()).unwrap());
+        record
+    }
+
+    fn slugs(db: &Database, post_id: u64) -> Option<Vec<String>> {
+        let txn = db.begin_read().unwrap();
+        let tbl = txn.open_table(crate::POST_ID_TO_SLUGS_TABLE).unwrap();
+        let slugs = tbl.get(&post_id).unwrap().map(|v| v.value());
+        slugs
+    }
+
+    #[test]
+    fn test_new_database_starts_at_current_version() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::create(tmp.path().join("test.db")).unwrap();
+        assert_eq!(migrate(&db).unwrap(), CURRENT_VERSION);
+        assert_eq!(version(&db), Some(CURRENT_VERSION));
+        // Every table exists
+        let txn = db.begin_read().unwrap();
+        txn.open_table(builds::QUEUED_BUILDS_TABLE).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let (_tmp, db) = fixture_db(1);
+        assert_eq!(migrate(&db).unwrap(), 1);
+        assert_eq!(version(&db), Some(CURRENT_VERSION));
+
+        assert_eq!(slugs(&db, 100), Some(vec!["100".to_string()]));
+        assert_eq!(slugs(&db, 101), Some(vec!["101".to_string()]));
+        let txn = db.begin_read().unwrap();
+        let owners = txn.open_table(crate::USER_SLUG_TO_POST_ID_TABLE).unwrap();
+        assert_eq!(owners.get((1, "101")).unwrap().unwrap().value(), 101);
+        let subdomains = txn.open_table(crate::USER_ID_TO_SUBDOMAIN_TABLE).unwrap();
+        assert_eq!(subdomains.get(&2).unwrap().unwrap().value(), "bob");
+        txn.open_table(crate::DOMAIN_TO_USER_ID_TABLE).unwrap();
+
+        // The mention is on the same line, so "# First post" isn't a heading,
+        // and the title falls back to the date, which we never recorded
+        let first = record(&db, 100).unwrap();
+        assert_eq!(first.title, "1969-12-31");
+        assert_eq!(first.slugs, vec!["100"]);
+        assert_eq!(first.timestamp, None);
+    }
+
+    #[test]
+    fn test_migrate_v2() {
+        let (_tmp, db) = fixture_db(2);
+        assert_eq!(migrate(&db).unwrap(), 2);
+        assert_eq!(version(&db), Some(CURRENT_VERSION));
+
+        let about = record(&db, 102).unwrap();
+        assert_eq!(about.user_id, 1);
+        assert_eq!(about.title, "About me");
+        assert_eq!(about.page.as_deref(), Some("about"));
+        assert_eq!(about.slugs, vec!["about"]);
+        assert_eq!(about.timestamp, Some(1_700_000_000));
+        assert_eq!(about.build, BuildState::Built);
+
+        let bobs = record(&db, 200).unwrap();
+        assert_eq!(bobs.user_id, 2);
+        assert_eq!(bobs.tags, vec!["rust", "zulip"]);
+        assert!(bobs.draft);
+        assert_eq!(bobs.assets, vec!["/user_uploads/13/ab/pic.png"]);
+        assert_eq!(record(&db, 101).unwrap().title, "Second post");
+
+        let txn = db.begin_read().unwrap();
+        for old in ["post_id_to_post", "post_id_to_timestamp"] {
+            assert!(txn
+                .open_table(TableDefinition::<u64, &str>::new(old))
+                .is_err());
+        }
+        let domains = txn.open_table(crate::DOMAIN_TO_USER_ID_TABLE).unwrap();
+        assert_eq!(domains.get("bob.example.com").unwrap().unwrap().value(), 2);
+    }
+
+    #[test]
+    fn test_migrate_v3() {
+        let (_tmp, db) = fixture_db(3);
+        assert_eq!(migrate(&db).unwrap(), 3);
+        assert_eq!(version(&db), Some(CURRENT_VERSION));
+
+        let txn = db.begin_read().unwrap();
+        let tbl = txn.open_table(crate::POST_REV_TO_REVISION_TABLE).unwrap();
+        let revisions: Vec<((u64, u64), Revision)> = tbl
+            .iter()
+            .unwrap()
+            .map(|e| {
+                let (k, v) = e.unwrap();
+                (k.value(), serde_json::from_str(v.value()).unwrap())
+            })
+            .collect();
+        let keys: Vec<_> = revisions.iter().map(|(k, _)| *k).collect();
+        assert_eq!(keys, vec![(100, 1), (101, 1), (102, 1), (103, 1), (200, 1)]);
+
+        // The edited post's revision is from when it was last edited
+        let (_, edited) = &revisions[3];
+        assert_eq!(edited.timestamp, 1_700_000_300);
+        assert_eq!(edited.title, "Third post");
+        assert!(edited.content.contains("Edited words"));
+    }
+
+    #[test]
+    fn test_migrate_v4() {
+        let (_tmp, db) = fixture_db(4);
+        assert_eq!(migrate(&db).unwrap(), 4);
+        assert_eq!(version(&db), Some(CURRENT_VERSION));
+
+        // Posts without a timestamp come first, and edited ones only once
+        assert_eq!(
+            crate::user_post_ids(&db, 1).unwrap(),
+            vec![100, 101, 102, 103]
+        );
+        assert_eq!(crate::user_post_ids(&db, 2).unwrap(), vec![201, 200]);
+
+        let txn = db.begin_read().unwrap();
+        assert!(txn
+            .open_table(TableDefinition::<u64, Vec<u64>>::new("user_id_to_post_ids"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let (_tmp, db) = fixture_db(1);
+        migrate(&db).unwrap();
+        assert_eq!(migrate(&db).unwrap(), CURRENT_VERSION);
+        assert_eq!(slugs(&db, 100), Some(vec!["100".to_string()]));
+    }
+
+    #[test]
+    fn test_keeps_slugs_already_recorded() {
+        let (_tmp, db) = fixture_db(1);
+        {
+            let txn = db.begin_write().unwrap();
+            txn.open_table(crate::POST_ID_TO_SLUGS_TABLE)
+                .unwrap()
+                .insert(&100, vec!["first-post".to_string(), "100".to_string()])
+                .unwrap();
+            // Post 101's old URL has been taken by another post
+            txn.open_table(crate::USER_SLUG_TO_POST_ID_TABLE)
+                .unwrap()
+                .insert((1, "101"), &100)
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        migrate(&db).unwrap();
+        assert_eq!(
+            slugs(&db, 100),
+            Some(vec!["first-post".to_string(), "100".to_string()])
+        );
+        assert_eq!(slugs(&db, 101), None);
+    }
+
+    #[test]
+    fn test_failed_migration_changes_nothing() {
+        let (_tmp, db) = fixture_db(1);
+        let fail: Migration = |txn| {
+            txn.open_table(crate::USER_ID_TO_SUBDOMAIN_TABLE)?
+                .remove(&1)?;
+            Err("nope".into())
+        };
+        let err = run_migrations(&db, &[record_old_slugs, fail]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to migrate the database from version 2 to 3: nope"
+        );
+
+        let txn = db.begin_read().unwrap();
+        assert!(txn.open_table(METADATA_TABLE).is_err());
+        assert!(txn.open_table(crate::POST_ID_TO_SLUGS_TABLE).is_err());
+        let subdomains = txn.open_table(crate::USER_ID_TO_SUBDOMAIN_TABLE).unwrap();
+        assert!(subdomains.get(&1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_refuses_version_0() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::create(tmp.path().join("test.db")).unwrap();
+        {
+            let txn = db.begin_write().unwrap();
+            txn.open_table(METADATA_TABLE)
+                .unwrap()
+                .insert(SCHEMA_VERSION_KEY, &0)
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        assert!(migrate(&db).is_err());
+        assert_eq!(version(&db), Some(0));
+    }
+
+    #[test]
+    fn test_refuses_newer_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let db = Database::create(tmp.path().join("test.db")).unwrap();
+        {
+            let txn = db.begin_write().unwrap();
+            txn.open_table(METADATA_TABLE)
+                .unwrap()
+                .insert(SCHEMA_VERSION_KEY, &(CURRENT_VERSION + 1))
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        assert!(migrate(&db).is_err());
+        assert_eq!(version(&db), Some(CURRENT_VERSION + 1));
+    }
+
+    // Writes the fixture for a version, as the bot at that version would
+    // have left it, from the tables spelled out as they were then. Run with
+    // `cargo test make_fixtures -- --ignored` to write them all again.
+    fn write_fixture(version: u64, path: &Path) {
+        const SUBDOMAINS: TableDefinition<u64, &str> = TableDefinition::new("user_id_to_subdomain");
+        const OWNERS: TableDefinition<&str, u64> = TableDefinition::new("subdomain_to_user_id");
+        const SETTINGS: TableDefinition<u64, &str> = TableDefinition::new("user_id_to_settings");
+        const POST_IDS: TableDefinition<u64, Vec<u64>> =
+            TableDefinition::new("user_id_to_post_ids");
+        const POSTS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_post");
+        const TIMESTAMPS: TableDefinition<u64, u64> = TableDefinition::new("post_id_to_timestamp");
+        const RECORDS: TableDefinition<u64, &str> = TableDefinition::new("post_id_to_record");
+        const SLUGS: TableDefinition<u64, Vec<String>> = TableDefinition::new("post_id_to_slugs");
+        const SLUG_OWNERS: TableDefinition<(u64, &str), u64> =
+            TableDefinition::new("user_slug_to_post_id");
+        const REVISIONS: TableDefinition<(u64, u64), &str> =
+            TableDefinition::new("post_rev_to_revision");
+        const DOMAINS: TableDefinition<&str, u64> = TableDefinition::new("domain_to_user_id");
+        const PENDING_DOMAINS: TableDefinition<u64, (&str, &str)> =
+            TableDefinition::new("user_id_to_pending_domain");
+        const QUEUED_BUILDS: TableDefinition<&str, &str> = TableDefinition::new("queued_builds");
+
+        // Alice's first posts are from before we recorded timestamps. Before
+        // 2 the mention was on the same line as the content.
+        let mention = if version == 1 {
+            "@**Blog Bot (HyperTXT)** "
+        } else {
+            "@**Blog Bot (HyperTXT)**\n"
+        };
+        let content = |post_id: u64| -> String {
+            let rest = match post_id {
+                100 => "# First post\n\nHello world",
+                101 => "# Second post\n\nMore words",
+                102 => "PAGE: about\n\n# About me\n\nHi!",
+                103 => "# Third post\n\nEdited words",
+                200 => "TAGS: rust, zulip\nDRAFT: true\n\n# Bob's post\n\nSee [pic](/user_uploads/13/ab/pic.png)",
+                201 => "# Old news\n\nSecond",
+                _ => unreachable!(),
+            };
+            format!("{}{}", mention, rest)
+        };
+        let timestamp = |post_id: u64| match post_id {
+            102 => Some(1_700_000_000),
+            200 => Some(1_700_000_100),
+            103 => Some(1_700_000_200),
+            201 => Some(1_600_000_000),
+            _ => None,
+        };
+        let slug = |post_id: u64| match post_id {
+            102 => "about".to_string(),
+            103 => "third-post".to_string(),
+            200 => "bobs-post".to_string(),
+            201 => "old-news".to_string(),
+            _ => post_id.to_string(),
+        };
+        let title = |post_id: u64| match post_id {
+            100 => "First post",
+            101 => "Second post",
+            102 => "About me",
+            103 => "Third post",
+            200 => "Bob's post",
+            _ => "Old news",
+        };
+        let (alices, bobs): (Vec<u64>, Vec<u64>) = match version {
+            1 => (vec![100, 101, 100], vec![]),
+            2 => (vec![100, 101, 100, 102], vec![200]),
+            3 => (vec![100, 101, 100, 102, 103, 103], vec![200]),
+            _ => (vec![100, 101, 100, 102, 103, 103], vec![200, 201, 201]),
+        };
+        let post_ids: Vec<(u64, u64)> = alices
+            .iter()
+            .map(|id| (1, *id))
+            .chain(bobs.iter().map(|id| (2, *id)))
+            .collect();
+        // A PostRecord as JSON, as it was at 3 and 4
+        let record = |user_id: u64, post_id: u64| -> String {
+            let mut record = serde_json::json!({
+                "user_id": user_id,
+                "author": "",
+                "content": content(post_id),
+                "title": title(post_id),
+                "timestamp": timestamp(post_id),
+                "edited_at": [],
+                "stream_id": null,
+                "topic": "",
+                "slugs": [slug(post_id)],
+                "tags": [],
+                "categories": [],
+                "draft": false,
+                "page": null,
+                "assets": [],
+                "build": {"state": "built"},
+            });
+            match post_id {
+                102 => record["page"] = "about".into(),
+                200 => {
+                    record["tags"] = serde_json::json!(["rust", "zulip"]);
+                    record["draft"] = true.into();
+                    record["assets"] = serde_json::json!(["/user_uploads/13/ab/pic.png"]);
+                }
+                // Published once records existed, and edited since
+                103 | 201 => {
+                    record["author"] = if user_id == 1 { "Alice" } else { "Bob" }.into();
+                    record["edited_at"] = if post_id == 103 {
+                        serde_json::json!([1_700_000_300])
+                    } else {
+                        serde_json::json!([1_700_000_400])
+                    };
+                    record["stream_id"] = 7.into();
+                    record["topic"] = "blogging".into();
+                    record["build"] = serde_json::json!({"state": "pending"});
+                }
+                _ => {}
+            }
+            record.to_string()
+        };
+
+        let _ = std::fs::remove_file(path);
+        let mut db = Database::create(path).unwrap();
+        let txn = db.begin_write().unwrap();
+        {
+            let mut subdomains = txn.open_table(SUBDOMAINS).unwrap();
+            let mut owners = txn.open_table(OWNERS).unwrap();
+            for (user_id, subdomain) in [(1, "alice"), (2, "bob")] {
+                subdomains.insert(&user_id, subdomain).unwrap();
+                owners.insert(subdomain, &user_id).unwrap();
+            }
+            let mut post_ids_tbl = txn.open_table(POST_IDS).unwrap();
+            post_ids_tbl.insert(&1, alices.clone()).unwrap();
+            if !bobs.is_empty() {
+                post_ids_tbl.insert(&2, bobs.clone()).unwrap();
+            }
+
+            if version == 1 || version == 2 {
+                let mut posts = txn.open_table(POSTS).unwrap();
+                for (_, post_id) in &post_ids {
+                    posts.insert(post_id, content(*post_id).as_str()).unwrap();
+                }
+            }
+            if version == 2 {
+                let mut timestamps = txn.open_table(TIMESTAMPS).unwrap();
+                for (_, post_id) in &post_ids {
+                    if let Some(ts) = timestamp(*post_id) {
+                        timestamps.insert(post_id, &ts).unwrap();
+                    }
+                }
+            }
+            if version >= 3 {
+                let mut records = txn.open_table(RECORDS).unwrap();
+                for (user_id, post_id) in &post_ids {
+                    records
+                        .insert(post_id, record(*user_id, *post_id).as_str())
+                        .unwrap();
+                }
+            }
+            if version >= 4 {
+                let mut revisions = txn.open_table(REVISIONS).unwrap();
+                for (_, post_id) in &post_ids {
+                    let mut versions =
+                        vec![(1, timestamp(*post_id).unwrap_or(0), content(*post_id))];
+                    match post_id {
+                        // Only its last version was kept as a record
+                        103 => versions[0].1 = 1_700_000_300,
+                        201 => {
+                            versions[0].2 = format!("{}# Old news\n\nFirst", mention);
+                            versions.push((2, 1_700_000_400, content(201)));
+                        }
+                        _ => {}
+                    }
+                    for (rev, timestamp, content) in versions {
+                        let revision = serde_json::json!({
+                            "timestamp": timestamp,
+                            "title": title(*post_id),
+                            "content": content,
+                        });
+                        revisions
+                            .insert((*post_id, rev), revision.to_string().as_str())
+                            .unwrap();
+                    }
+                }
+            }
+
+            if version >= 2 {
+                let mut slugs = txn.open_table(SLUGS).unwrap();
+                let mut slug_owners = txn.open_table(SLUG_OWNERS).unwrap();
+                for (user_id, post_id) in &post_ids {
+                    slugs.insert(post_id, vec![slug(*post_id)]).unwrap();
+                    slug_owners
+                        .insert((*user_id, slug(*post_id).as_str()), post_id)
+                        .unwrap();
+                }
+                txn.open_table(SETTINGS).unwrap();
+                txn.open_table(DOMAINS)
+                    .unwrap()
+                    .insert("bob.example.com", &2)
+                    .unwrap();
+                txn.open_table(PENDING_DOMAINS).unwrap();
+                txn.open_table(QUEUED_BUILDS).unwrap();
+                txn.open_table(METADATA_TABLE)
+                    .unwrap()
+                    .insert(SCHEMA_VERSION_KEY, &version)
+                    .unwrap();
+            }
+        }
+        txn.commit().unwrap();
+        db.compact().unwrap();
+    }
+
+    #[test]
+    #[ignore = "rewrites the fixtures in fixtures/db"]
+    fn make_fixtures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/db");
+        for version in 1..CURRENT_VERSION {
+            write_fixture(version, &dir.join(format!("v{}.redb", version)));
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::{self, HeaderValue};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::net::TcpListener;
+
+use crate::domains;
+use crate::paths::{self, Root};
+
+// An optional web server for the built blogs, so that small deployments (and
+// local development) don't need a separate one set up. It does the same job
+// as the proxy config in the README: each Host gets its blog's output dir,
+// uploads are shared by every blog, and domain ownership tokens are served on
+// the domain they're for.
+
+// Looks up which blog (by subdomain) a custom domain belongs to
+pub type ResolveDomain = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Server {
+    static_root: Root,
+    uploads: Root,
+    challenges: Root,
+    resolve_domain: ResolveDomain,
+}
+
+impl Server {
+    pub fn from_env(resolve_domain: ResolveDomain) -> Result<Server, String> {
+        Ok(Server {
+            static_root: Root::from_env("STATIC_ROOT")?,
+            uploads: paths::uploads_root()?,
+            challenges: domains::challenges_root()?,
+            resolve_domain,
+        })
+    }
+
+    pub async fn run(self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let listener = TcpListener::bind(addr).await?;
+        println!("Serving blogs on http://{}", addr);
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {:?}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let server = server.clone();
+                    async move { Ok::<_, Infallible>(server.handle(req).await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    eprintln!("error serving connection: {:?}", e);
+                }
+            });
+        }
+    }
+
+    // Everything `respond` does is blocking filesystem work, so it runs on a
+    // blocking thread rather than holding up other connections.
+    async fn handle<B>(self, req: Request<B>) -> Response<Full<Bytes>> {
+        let (parts, _) = req.into_parts();
+        let req = Request::from_parts(parts, ());
+        tokio::task::spawn_blocking(move || self.respond(&req))
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("failed to respond: {:?}", e);
+                plain(StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong")
+            })
+    }
+
+    fn respond<B>(&self, req: &Request<B>) -> Response<Full<Bytes>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return plain(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        let Some(path) = percent_decode(req.uri().path()) else {
+            return plain(StatusCode::BAD_REQUEST, "Bad request");
+        };
+        let if_none_match = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok());
+
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(normalize_host)
+            .unwrap_or_default();
+
+        if let Some(token) = path.strip_prefix(domains::CHALLENGE_PATH) {
+            let file = self
+                .challenges
+                .child(&host)
+                .and_then(Root::new)
+                .and_then(|dir| dir.child(token));
+            return match file {
+                Ok(file) => serve_file(&file, CachePolicy::NoStore, if_none_match)
+                    .unwrap_or_else(|| plain(StatusCode::NOT_FOUND, "Not found")),
+                Err(_) => plain(StatusCode::NOT_FOUND, "Not found"),
+            };
+        }
+
+        let Some(blog) = self.blog_for_host(&host) else {
+            return plain(StatusCode::NOT_FOUND, "There's no blog here");
+        };
+
+        // Zulip upload URLs are unique per upload, so they never change
+        if let Some(rel) = path.strip_prefix("/user_uploads/") {
+            return match self.uploads.join(rel) {
+                Ok(file) => serve_file(&file, CachePolicy::Immutable, if_none_match),
+                Err(_) => None,
+            }
+            .unwrap_or_else(|| not_found(&blog));
+        }
+
+        let rel = path.trim_start_matches('/');
+        let file = if rel.is_empty() {
+            blog.join("index.html")
+        } else {
+            blog.join(rel)
+        };
+        let Ok(mut file) = file else {
+            return not_found(&blog);
+        };
+        if file.is_dir() {
+            // Relative links in the page only work from the URL with a slash
+            if !path.ends_with('/') {
+                return redirect(&format!("{}/", percent_encode(&path)));
+            }
+            file.push("index.html");
+        }
+        let policy = if file.extension().is_some_and(|e| e == "html") {
+            CachePolicy::Revalidate
+        } else {
+            CachePolicy::ShortLived
+        };
+        serve_file(&file, policy, if_none_match).unwrap_or_else(|| not_found(&blog))
+    }
+
+    // {subdomain}.hypertxt.io (or {subdomain}.localhost, for development), or
+    // a custom domain that's been added to a blog.
+    fn blog_for_host(&self, host: &str) -> Option<Root> {
+        let subdomain = [domains::BASE_DOMAIN, "localhost"]
+            .iter()
+            .find_map(|base| host.strip_suffix(&format!(".{}", base)))
+            .map(String::from)
+            .or_else(|| (self.resolve_domain)(host))?;
+        let dir = self.static_root.entry(&subdomain).ok()?;
+        Root::new(dir).ok()
+    }
+}
+
+// e.g. "example.com" from "Example.com.:8080"
+fn normalize_host(host: &str) -> String {
+    let host = host.split(':').next().unwrap_or_default();
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+enum CachePolicy {
+    // Ownership tokens, which come and go
+    NoStore,
+    // Pages change whenever the blog is rebuilt, so browsers have to check
+    Revalidate,
+    // CSS, feeds, etc
+    ShortLived,
+    // Uploads
+    Immutable,
+}
+
+impl CachePolicy {
+    fn header(&self) -> &'static str {
+        match self {
+            CachePolicy::NoStore => "no-store",
+            CachePolicy::Revalidate => "no-cache",
+            CachePolicy::ShortLived => "public, max-age=3600",
+            CachePolicy::Immutable => "public, max-age=31536000, immutable",
+        }
+    }
+}
+
+fn serve_file(
+    path: &Path,
+    policy: CachePolicy,
+    if_none_match: Option<&str>,
+) -> Option<Response<Full<Bytes>>> {
+    let meta = fs::metadata(path).ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let etag = etag(&meta);
+    let builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, policy.header());
+
+    if if_none_match.is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*")) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Full::new(Bytes::new()))
+            .ok();
+    }
+
+    let body = fs::read(path).ok()?;
+    builder
+        .header(header::CONTENT_TYPE, content_type(path))
+        .body(Full::new(Bytes::from(body)))
+        .ok()
+}
+
+// Built from the size and modification time, which is enough to tell when a
+// rebuild changed a file without reading it.
+fn etag(meta: &fs::Metadata) -> String {
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", meta.len(), modified)
+}
+
+fn content_type(path: &Path) -> HeaderValue {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let value = match mime.type_() {
+        mime::TEXT => format!("{}; charset=utf-8", mime.essence_str()),
+        _ => mime.essence_str().to_string(),
+    };
+    HeaderValue::from_str(&value).unwrap_or(HeaderValue::from_static("application/octet-stream"))
+}
+
+// Every Zola site has a 404.html, which is what we want to show
+fn not_found(blog: &Root) -> Response<Full<Bytes>> {
+    let page: Option<PathBuf> = blog.join("404.html").ok();
+    match page.and_then(|p| fs::read(p).ok()) {
+        Some(body) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::CACHE_CONTROL, CachePolicy::Revalidate.header())
+            .body(Full::new(Bytes::from(body)))
+            .unwrap_or_else(|_| plain(StatusCode::NOT_FOUND, "Not found")),
+        None => plain(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+fn redirect(location: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(header::LOCATION, location)
+        .body(Full::new(Bytes::new()))
+        .unwrap_or_else(|_| plain(StatusCode::BAD_REQUEST, "Bad request"))
+}
+
+fn plain(status: StatusCode, msg: &'static str) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from_static(msg.as_bytes())));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    resp
+}
+
+// Turns e.g. /my%20post/ back into /my post/. Anything that doesn't decode to
+// valid UTF-8 (or decodes to a NUL) is rejected.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    let decoded = String::from_utf8(out).ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+    Some(decoded)
+}
+
+// The other way, for putting a decoded path back in a URL: /my post/ becomes
+// /my%20post/. Only unreserved characters and slashes are left as they are.
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~/".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    struct TestServer {
+        _tmp: tempfile::TempDir,
+        server: Server,
+    }
+
+    // STATIC_ROOT with a built blog in it, plus uploads and challenges, laid
+    // out the same way as in production.
+    fn test_server() -> TestServer {
+        let tmp = tempfile::tempdir().unwrap();
+        let static_root = Root::create(tmp.path().join("static")).unwrap();
+        let blog = static_root.subroot("myblog").unwrap();
+        fs::write(blog.join("index.html").unwrap(), "<h1>Home</h1>").unwrap();
+        fs::write(blog.join("404.html").unwrap(), "<h1>Lost</h1>").unwrap();
+        fs::write(blog.join("style.css").unwrap(), "h1 {}").unwrap();
+        fs::create_dir(blog.join("my post").unwrap()).unwrap();
+        fs::write(blog.join("my post/index.html").unwrap(), "<p>Post</p>").unwrap();
+        // Outside of any blog, which nothing should be able to reach
+        fs::write(tmp.path().join("secret.txt"), "secret").unwrap();
+
+        let uploads = Root::create(tmp.path().join("user_uploads")).unwrap();
+        fs::create_dir_all(uploads.join("1/abc").unwrap()).unwrap();
+        fs::write(uploads.join("1/abc/pic.png").unwrap(), "png").unwrap();
+
+        let challenges = Root::create(tmp.path().join("domain_challenges")).unwrap();
+        fs::create_dir(challenges.join("not-added-yet.com").unwrap()).unwrap();
+        fs::write(
+            challenges.join("not-added-yet.com/token123").unwrap(),
+            "token123",
+        )
+        .unwrap();
+
+        let resolve_domain: ResolveDomain = Arc::new(|host| match host {
+            "example.com" => Some("myblog".to_string()),
+            _ => None,
+        });
+        TestServer {
+            _tmp: tmp,
+            server: Server {
+                static_root,
+                uploads,
+                challenges,
+                resolve_domain,
+            },
+        }
+    }
+
+    fn get(server: &Server, host: &str, path: &str) -> Response<Full<Bytes>> {
+        let req = Request::get(path)
+            .header(header::HOST, host)
+            .body(())
+            .unwrap();
+        server.respond(&req)
+    }
+
+    async fn body(resp: Response<Full<Bytes>>) -> String {
+        let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_host() {
+        let t = test_server();
+        for host in [
+            "myblog.hypertxt.io",
+            "MyBlog.hypertxt.io:443",
+            "myblog.localhost:8080",
+            "example.com",
+        ] {
+            let resp = get(&t.server, host, "/");
+            assert_eq!(resp.status(), StatusCode::OK, "{}", host);
+            assert_eq!(body(resp).await, "<h1>Home</h1>");
+        }
+        for host in ["nope.hypertxt.io", "other.com", "hypertxt.io", ""] {
+            assert_eq!(
+                get(&t.server, host, "/").status(),
+                StatusCode::NOT_FOUND,
+                "{}",
+                host
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pages_and_assets() {
+        let t = test_server();
+        let resp = get(&t.server, "myblog.hypertxt.io", "/my%20post/");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(body(resp).await, "<p>Post</p>");
+
+        let resp = get(&t.server, "myblog.hypertxt.io", "/my%20post");
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()[header::LOCATION], "/my%20post/");
+
+        let resp = get(&t.server, "myblog.hypertxt.io", "/style.css");
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/css; charset=utf-8"
+        );
+        assert_eq!(
+            resp.headers()[header::CACHE_CONTROL],
+            "public, max-age=3600"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_uploads_and_challenges() {
+        let t = test_server();
+        let resp = get(&t.server, "example.com", "/user_uploads/1/abc/pic.png");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/png");
+        assert!(resp.headers()[header::CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+
+        // Tokens are served without a blog, since the domain isn't set up
+        // yet, but only on the domain they're for
+        let resp = get(
+            &t.server,
+            "Not-Added-Yet.com:80",
+            "/.well-known/hypertxt-challenge/token123",
+        );
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, "token123");
+        for (host, path) in [
+            (
+                "someone-else.com",
+                "/.well-known/hypertxt-challenge/token123",
+            ),
+            (
+                "myblog.hypertxt.io",
+                "/.well-known/hypertxt-challenge/token123",
+            ),
+            (
+                "",
+                "/.well-known/hypertxt-challenge/not-added-yet.com/token123",
+            ),
+            (
+                "..",
+                "/.well-known/hypertxt-challenge/not-added-yet.com/token123",
+            ),
+        ] {
+            assert_eq!(
+                get(&t.server, host, path).status(),
+                StatusCode::NOT_FOUND,
+                "{} {}",
+                host,
+                path
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_not_found_uses_blog_404() {
+        let t = test_server();
+        for path in [
+            "/missing/",
+            "/user_uploads/1/abc/missing.png",
+            "/../secret.txt",
+            "/%2e%2e/secret.txt",
+            "/..%2fsecret.txt",
+            "/user_uploads/../static/myblog/index.html",
+        ] {
+            let resp = get(&t.server, "myblog.hypertxt.io", path);
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert_eq!(body(resp).await, "<h1>Lost</h1>", "{}", path);
+        }
+        let resp = get(&t.server, "myblog.hypertxt.io", "/%ff");
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_handle() {
+        let t = test_server();
+        let req = Request::get("/my%20post/")
+            .header(header::HOST, "myblog.hypertxt.io")
+            .body(())
+            .unwrap();
+        let resp = t.server.clone().handle(req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(body(resp).await, "<p>Post</p>");
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("/my post/"), "/my%20post/");
+        assert_eq!(percent_encode("/a-b_c.d~e/"), "/a-b_c.d~e/");
+        assert_eq!(
+            percent_encode("/x\r\nSet-Cookie: a"),
+            "/x%0D%0ASet-Cookie%3A%20a"
+        );
+        for path in ["/café/", "/100%/", "/?#&"] {
+            assert_eq!(percent_decode(&percent_encode(path)).as_deref(), Some(path));
+        }
+    }
+
+    #[test]
+    fn test_etags() {
+        let t = test_server();
+        let resp = get(&t.server, "myblog.hypertxt.io", "/style.css");
+        let etag = resp.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        let req = Request::get("/style.css")
+            .header(header::HOST, "myblog.hypertxt.io")
+            .header(header::IF_NONE_MATCH, format!("\"other\", {}", etag))
+            .body(())
+            .unwrap();
+        let resp = t.server.respond(&req);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[header::ETAG], etag.as_str());
+    }
+
+    #[test]
+    fn test_only_get_and_head() {
+        let t = test_server();
+        let req = Request::post("/")
+            .header(header::HOST, "myblog.hypertxt.io")
+            .body(())
+            .unwrap();
+        assert_eq!(
+            t.server.respond(&req).status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use serde::Deserialize;
+use std::fs;
+
+use crate::paths::Root;
+
+pub const DEFAULT_THEME: &str = "terminimal";
+
+// The accent colors terminimal ships, see
+// https://github.com/pawroman/zola-theme-terminimal#accent-colors
+pub const ACCENT_COLORS: [&str; 5] = ["blue", "green", "orange", "pink", "red"];
+pub const DEFAULT_ACCENT_COLOR: &str = "blue";
+
+// Zola's built-in syntax highlighting themes, see
+// https://www.getzola.org/documentation/getting-started/configuration/#syntax-highlighting
+pub const HIGHLIGHT_THEMES: [&str; 43] = [
+    "1337",
+    "agola-dark",
+    "ascetic-white",
+    "axar",
+    "ayu-dark",
+    "ayu-light",
+    "ayu-mirage",
+    "base16-atelierdune-light",
+    "base16-ocean-dark",
+    "base16-ocean-light",
+    "bbedit",
+    "boron",
+    "charcoal",
+    "cheerfully-light",
+    "classic-modern",
+    "demain",
+    "dimmed-fluid",
+    "dracula",
+    "gray-matter-dark",
+    "green",
+    "gruvbox-dark",
+    "gruvbox-light",
+    "idle",
+    "inspired-github",
+    "ir-white",
+    "kronuz",
+    "material-dark",
+    "material-light",
+    "monokai",
+    "nord",
+    "nyx-bold",
+    "one-dark",
+    "OneHalfDark",
+    "OneHalfLight",
+    "railsbase16-green-screen-dark",
+    "solarized-dark",
+    "solarized-light",
+    "subway-madrid",
+    "subway-moscow",
+    "Tomorrow",
+    "two-dark",
+    "visual-studio-dark",
+    "zenburn",
+];
+
+// Custom stylesheets are meant for small tweaks, not whole themes
+pub const MAX_CUSTOM_CSS_LEN: usize = 10_000;
+
+// The file a blog's custom stylesheet is written to, in its static dir
+pub const CUSTOM_CSS_FILENAME: &str = "custom.css";
+
+// A Zola theme installed under THEMES_ROOT, which every blog has symlinked in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    pub name: String,
+    pub description: String,
+    // Somewhere to see what the theme looks like, if it tells us
+    pub preview: Option<String>,
+    // The theme's own [extra] options, and their defaults
+    pub extra: toml::Table,
+}
+
+// The bits of a theme's theme.toml we care about, see
+// https://www.getzola.org/documentation/themes/creating-a-theme/
+#[derive(Debug, Deserialize)]
+struct ThemeToml {
+    #[serde(default)]
+    description: String,
+    demo: Option<String>,
+    homepage: Option<String>,
+    #[serde(default)]
+    extra: toml::Table,
+}
+
+impl Theme {
+    // Whether the theme reads a given [extra] option, e.g. accent_color.
+    pub fn supports_extra(&self, key: &str) -> bool {
+        self.extra.contains_key(key)
+    }
+
+    // Whether the theme ships a given template, e.g. "tags/list.html".
+    pub fn has_template(&self, themes: &Root, template: &str) -> bool {
+        themes
+            .join(format!("{}/templates/{}", self.name, template))
+            .map(|p| p.is_file())
+            .unwrap_or(false)
+    }
+
+    // Whether one of the theme's templates has a given block we can fill in,
+    // e.g. the extra_head block in terminimal's index.html.
+    pub fn has_block(&self, themes: &Root, template: &str, block: &str) -> bool {
+        themes
+            .join(format!("{}/templates/{}", self.name, template))
+            .and_then(|p| fs::read_to_string(p).map_err(|e| e.to_string()))
+            .map(|t| {
+                t.split("{%").any(|tag| {
+                    tag.trim_start_matches('-')
+                        .split_whitespace()
+                        .take(2)
+                        .eq(["block", block])
+                })
+            })
+            .unwrap_or(false)
+    }
+}
+
+pub fn check_accent_color(color: &str) -> Result<(), String> {
+    if ACCENT_COLORS.contains(&color) {
+        return Ok(());
+    }
+    Err(format!(
+        "{:?} isn't an accent color. Try one of: {}",
+        color,
+        ACCENT_COLORS.join(", ")
+    ))
+}
+
+pub fn check_highlight_theme(name: &str) -> Result<(), String> {
+    if HIGHLIGHT_THEMES.contains(&name) {
+        return Ok(());
+    }
+    Err(format!(
+        "{:?} isn't a highlight theme. Try one of: {}",
+        name,
+        HIGHLIGHT_THEMES.join(", ")
+    ))
+}
+
+// Checks a user's stylesheet before it goes anywhere near their blog. It gets
+// served as its own file, so there's no way to break out into HTML, but we
+// still don't want it pulling in anything from elsewhere (which could track
+// readers) or using the old IE script hooks. Escapes are rejected outright,
+// since they could be used to spell any of those in a way we wouldn't spot.
+pub fn sanitize_css(css: &str) -> Result<String, String> {
+    let css = strip_code_fence(css.trim()).trim();
+    if css.len() > MAX_CUSTOM_CSS_LEN {
+        return Err(format!(
+            "Custom CSS can be at most {} bytes, yours is {}",
+            MAX_CUSTOM_CSS_LEN,
+            css.len()
+        ));
+    }
+    let lower = css.to_ascii_lowercase();
+    for banned in [
+        "@import",
+        "url(",
+        "image-set(",
+        "expression(",
+        "javascript:",
+        "-moz-binding",
+        "\\",
+        "<",
+    ] {
+        if lower.contains(banned) {
+            return Err(format!("Custom CSS can't contain {:?}", banned));
+        }
+    }
+    // Only as a property, since scroll-behavior is fine
+    for property in declared_properties(&lower) {
+        if property == "behavior" || property == "-ms-behavior" {
+            return Err(format!("Custom CSS can't use the {} property", property));
+        }
+    }
+    if let Some(c) = css
+        .chars()
+        .find(|c| c.is_control() && *c != '\n' && *c != '\t')
+    {
+        return Err(format!("Custom CSS can't contain {:?}", c));
+    }
+    let (opens, closes) = (css.matches('{').count(), css.matches('}').count());
+    if opens != closes {
+        return Err("Custom CSS has unbalanced braces".to_string());
+    }
+    Ok(css.to_string())
+}
+
+// The names of the properties the stylesheet declares, e.g. "color" for
+// "p { color: red }". Comments are skipped, so they can't hide one.
+fn declared_properties(css: &str) -> Vec<String> {
+    let mut uncommented = String::new();
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        uncommented.push_str(&rest[..start]);
+        uncommented.push(' ');
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    uncommented.push_str(rest);
+
+    uncommented
+        .split(['{', ';', '}'])
+        .filter_map(|declaration| {
+            let (name, _) = declaration.split_once(':')?;
+            let name = name.trim();
+            let is_name = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            is_name.then(|| name.to_string())
+        })
+        .collect()
+}
+
+// People will usually paste CSS into Zulip as a ```css code block
+fn strip_code_fence(css: &str) -> &str {
+    let Some(rest) = css.strip_prefix("```") else {
+        return css;
+    };
+    let Some((_lang, rest)) = rest.split_once('\n') else {
+        return css;
+    };
+    rest.trim_end().strip_suffix("```").unwrap_or(rest)
+}
+
+pub fn themes_root() -> Result<Root, String> {
+    Root::from_env("THEMES_ROOT")
+}
+
+// Every directory under the themes root with a theme.toml in it, sorted by
+// name.
+pub fn installed(themes: &Root) -> Result<Vec<Theme>, String> {
+    let entries = fs::read_dir(themes.path())
+        .map_err(|e| format!("failed to read themes dir {:?}: {:?}", themes.path(), e))?;
+
+    let mut installed = Vec::new();
+    for entry in entries.flatten() {
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if let Ok(theme) = load(themes, &name) {
+            installed.push(theme);
+        }
+    }
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(installed)
+}
+
+// Looks up a single theme by name, failing if it isn't installed.
+pub fn find(themes: &Root, name: &str) -> Result<Theme, String> {
+    load(themes, name).map_err(|_| {
+        let names: Vec<String> = installed(themes)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        format!(
+            "There's no theme called {:?}. Try one of: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+fn load(themes: &Root, name: &str) -> Result<Theme, String> {
+    // Make sure the name is a single directory, not a path
+    themes.child(name)?;
+    let path = themes.join(format!("{}/theme.toml", name))?;
+    let raw =
+        fs::read_to_string(&path).map_err(|e| format!("failed to read {:?}: {:?}", path, e))?;
+    let parsed: ThemeToml =
+        toml::from_str(&raw).map_err(|e| format!("failed to parse {:?}: {:?}", path, e))?;
+    Ok(Theme {
+        name: name.to_string(),
+        description: parsed.description,
+        preview: parsed.demo.or(parsed.homepage),
+        extra: parsed.extra,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Lays out a themes dir with a couple of fake themes in it.
+    pub fn test_themes() -> (tempfile::TempDir, Root) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Root::create(tmp.path().join("themes")).unwrap();
+        for (name, theme_toml) in [
+            (
+                "terminimal",
+                "name = \"terminimal\"\ndescription = \"A simple, minimal retro theme\"\ndemo = \"https://pawroman.github.io/zola-theme-terminimal/\"\n\n[extra]\naccent_color = \"blue\"\nlogo_text = \"Terminimal theme\"\nauthor = \"\"\nmenu_items = []\n",
+            ),
+            (
+                "plain",
+                "name = \"plain\"\ndescription = \"No frills\"\nhomepage = \"https://example.com/plain\"\n",
+            ),
+        ] {
+            fs::create_dir_all(root.path().join(name).join("templates/tags")).unwrap();
+            fs::write(root.path().join(name).join("theme.toml"), theme_toml).unwrap();
+        }
+        fs::write(root.path().join("terminimal/templates/tags/list.html"), "").unwrap();
+        fs::write(
+            root.path().join("terminimal/templates/index.html"),
+            "<head>{%- block extra_head %}{% endblock extra_head -%}</head>",
+        )
+        .unwrap();
+        // Not a theme, since there's no theme.toml
+        fs::create_dir(root.path().join("junk")).unwrap();
+        (tmp, root)
+    }
+
+    #[test]
+    fn test_installed_themes() {
+        let (_tmp, root) = test_themes();
+        let themes = installed(&root).unwrap();
+        let names: Vec<_> = themes.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["plain", "terminimal"]);
+        assert_eq!(
+            themes[0].preview.as_deref(),
+            Some("https://example.com/plain")
+        );
+        assert_eq!(
+            themes[1].preview.as_deref(),
+            Some("https://pawroman.github.io/zola-theme-terminimal/")
+        );
+    }
+
+    #[test]
+    fn test_find_theme() {
+        let (_tmp, root) = test_themes();
+        let theme = find(&root, "terminimal").unwrap();
+        assert!(theme.supports_extra("accent_color"));
+        assert!(theme.supports_extra("menu_items"));
+        assert!(!theme.supports_extra("page_titles"));
+        assert!(theme.has_template(&root, "tags/list.html"));
+
+        let plain = find(&root, "plain").unwrap();
+        assert!(!plain.supports_extra("accent_color"));
+        assert!(!plain.supports_extra("menu_items"));
+        assert!(!plain.has_template(&root, "tags/list.html"));
+
+        let err = find(&root, "nope").unwrap_err();
+        assert!(err.contains("plain, terminimal"));
+        assert!(find(&root, "junk").is_err());
+        assert!(find(&root, "../themes/plain").is_err());
+    }
+
+    #[test]
+    fn test_has_block() {
+        let (_tmp, root) = test_themes();
+        let terminimal = find(&root, "terminimal").unwrap();
+        assert!(terminimal.has_block(&root, "index.html", "extra_head"));
+        assert!(!terminimal.has_block(&root, "index.html", "extra"));
+        assert!(!terminimal.has_block(&root, "page.html", "extra_head"));
+        let plain = find(&root, "plain").unwrap();
+        assert!(!plain.has_block(&root, "index.html", "extra_head"));
+    }
+
+    #[test]
+    fn test_customization_whitelists() {
+        assert!(check_accent_color("red").is_ok());
+        assert!(check_accent_color("chartreuse").is_err());
+        assert!(check_accent_color("red; } body { display: none").is_err());
+        assert!(check_highlight_theme("dracula").is_ok());
+        assert!(check_highlight_theme("../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_sanitize_css() {
+        assert_eq!(
+            sanitize_css("  h1 { color: #c0ffee; }\n").unwrap(),
+            "h1 { color: #c0ffee; }"
+        );
+        assert_eq!(
+            sanitize_css("```css\np {\n\tmargin: 0;\n}\n```").unwrap(),
+            "p {\n\tmargin: 0;\n}"
+        );
+        assert!(sanitize_css("html { scroll-behavior: smooth; }").is_ok());
+        for bad in [
+            "@import 'https://evil.example/x.css';",
+            "body { background: URL(https://tracker.example/px.gif) }",
+            "body { background: image-set('a.png' 1x) }",
+            "p { width: expression(alert(1)) }",
+            "p { behavior: url(x.htc) }",
+            "p { color: red; BEHAVIOR : foo }",
+            "p { /* hi */behavior: foo }",
+            "p { -ms-behavior: foo }",
+            "p { -moz-binding: none }",
+            "p { content: '\\75rl(x)' }",
+            "</style><script>alert(1)</script>",
+            "p { color: red",
+            "p { color: red }\u{0}",
+        ] {
+            assert!(sanitize_css(bad).is_err(), "{:?} should be rejected", bad);
+        }
+        assert!(sanitize_css(&"a".repeat(MAX_CUSTOM_CSS_LEN + 1)).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use toml_edit::{visit_mut::VisitMut, DocumentMut, Value};
+
+use crate::paths::Root;
+use crate::themes::{self, Theme};
+
+// Typed versions of the files Zola reads, so that anything user-provided
+// (titles, blog names, etc) gets properly escaped when we serialize them,
+// instead of being pasted into a template.
+
+// The taxonomies every blog has, which posts set with TAGS: and CATEGORIES:
+pub const TAXONOMIES: [&str; 2] = ["tags", "categories"];
+
+// The site-wide config.toml, see https://www.getzola.org/documentation/getting-started/configuration/
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Config {
+    pub base_url: String,
+    pub title: String,
+    pub theme: String,
+    // Whether to automatically compile all Sass files in the sass directory
+    pub compile_sass: bool,
+    // Whether to build a search index to be used later on by a JavaScript library
+    pub build_search_index: bool,
+    pub generate_feeds: bool,
+    // Every feed format we want, which Zola tells apart by extension
+    pub feed_filenames: Vec<String>,
+    pub taxonomies: Vec<TaxonomyConfig>,
+    pub markdown: MarkdownConfig,
+    // Theme-specific variables, which differ from theme to theme
+    pub extra: toml::Table,
+}
+
+// e.g. tags, which get a page listing every tag and one page per tag
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TaxonomyConfig {
+    pub name: String,
+    // Whether to generate an Atom feed for each term, e.g. /tags/rust/atom.xml
+    pub feed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct MarkdownConfig {
+    pub highlight_code: bool,
+    // One of Zola's built-in themes, or its default if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub highlight_theme: Option<String>,
+}
+
+impl Config {
+    pub fn new(user_domain: &str, blog_name: &str, theme: &Theme) -> Config {
+        Config {
+            base_url: format!("https://{}", user_domain),
+            title: blog_name.to_string(),
+            theme: theme.name.clone(),
+            compile_sass: true,
+            build_search_index: false,
+            generate_feeds: true,
+            feed_filenames: vec!["atom.xml".to_string(), "rss.xml".to_string()],
+            taxonomies: TAXONOMIES
+                .iter()
+                .map(|name| TaxonomyConfig {
+                    name: name.to_string(),
+                    feed: true,
+                })
+                .collect(),
+            markdown: MarkdownConfig {
+                highlight_code: true,
+                highlight_theme: None,
+            },
+            extra: toml::Table::new(),
+        }
+    }
+
+    // Sets an [extra] option, but only if the theme actually reads it, so
+    // that switching themes doesn't leave a pile of meaningless settings.
+    pub fn set_extra<V: Into<toml::Value>>(&mut self, theme: &Theme, key: &str, value: V) {
+        if theme.supports_extra(key) {
+            self.extra.insert(key.to_string(), value.into());
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        to_toml(self)
+    }
+}
+
+// The bits of an existing config.toml that we need to know about. Blogs
+// created before the config was typed don't have every field Config expects,
+// so this only asks for what it uses.
+#[derive(Debug, Deserialize)]
+pub struct SiteInfo {
+    pub base_url: String,
+    #[serde(default = "default_theme")]
+    pub theme: String,
+    #[serde(default)]
+    pub extra: SiteExtra,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SiteExtra {
+    #[serde(default)]
+    pub accent_color: Option<String>,
+    #[serde(default)]
+    pub logo_text: String,
+    #[serde(default)]
+    pub author: String,
+}
+
+fn default_theme() -> String {
+    themes::DEFAULT_THEME.to_string()
+}
+
+pub fn read_site_info(blog: &Root) -> Result<SiteInfo, String> {
+    let path = blog.join("config.toml")?;
+    let raw =
+        fs::read_to_string(&path).map_err(|e| format!("failed to read {:?}: {:?}", path, e))?;
+    toml::from_str(&raw).map_err(|e| format!("failed to parse {:?}: {:?}", path, e))
+}
+
+// Front matter for a section, e.g. content/_index.md
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct SectionFrontMatter {
+    pub render: bool,
+    pub sort_by: String,
+}
+
+impl Default for SectionFrontMatter {
+    fn default() -> Self {
+        SectionFrontMatter {
+            render: true,
+            sort_by: "update_date".to_string(),
+        }
+    }
+}
+
+// Front matter for a single post, e.g. content/123.md
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct PageFrontMatter {
+    pub title: String,
+    // Standalone pages don't have one, which keeps them out of the feeds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    // The page's whole URL path, instead of {section}/{slug}
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    // Old URLs that should redirect here, e.g. after the title changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub draft: bool,
+    // e.g. tags = ["rust", "zulip"]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub taxonomies: BTreeMap<String, Vec<String>>,
+}
+
+// Renders a Zola content file: TOML front matter between `+++` lines,
+// followed by the (already rendered) body.
+pub fn content_file<T: Serialize>(front_matter: &T, body: &str) -> Result<String, String> {
+    Ok(format!("+++\n{}+++\n\n{}", to_toml(front_matter)?, body))
+}
+
+fn to_toml<T: Serialize>(v: &T) -> Result<String, String> {
+    let raw = toml::to_string(v).map_err(|e| format!("failed to serialize TOML: {:?}", e))?;
+    // The serializer picks multi-line strings for anything with a newline in
+    // it, which would let a line containing just `+++` end the front matter
+    // early. Re-emit every string on a single line instead.
+    let mut doc = raw
+        .parse::<DocumentMut>()
+        .map_err(|e| format!("failed to reparse TOML: {:?}", e))?;
+    SingleLineStrings.visit_document_mut(&mut doc);
+    Ok(doc.to_string())
+}
+
+struct SingleLineStrings;
+
+impl VisitMut for SingleLineStrings {
+    fn visit_value_mut(&mut self, node: &mut Value) {
+        if let Value::String(s) = node {
+            if let Ok(mut v) = basic_string(s.value()).parse::<Value>() {
+                *v.decor_mut() = s.decor().clone();
+                *node = v;
+            }
+        }
+        toml_edit::visit_mut::visit_value_mut(self, node);
+    }
+}
+
+// Quotes a string as a TOML basic string, escaping anything that could span
+// lines or otherwise isn't allowed to appear raw.
+fn basic_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04X}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pulls the front matter back out of a content file, the same way Zola
+    // would.
+    fn parse_front_matter<T: for<'de> Deserialize<'de>>(file: &str) -> T {
+        let rest = file.strip_prefix("+++\n").unwrap();
+        let (fm, _) = rest.split_once("+++\n").unwrap();
+        toml::from_str(fm).unwrap()
+    }
+
+    #[test]
+    fn test_page_front_matter_round_trips() {
+        for title in [
+            "A plain title",
+            r#"She said "hello""#,
+            "Back\\slash",
+            "Line one\nline two",
+            "Ends with a quote\"",
+            "Emoji 🦀 and ünïcödé ✨",
+            "\"\"\"triple\"\"\"",
+            "+++",
+            "Before\n+++\nAfter",
+            "title = \"injected\"\n[extra]",
+        ] {
+            let fm = PageFrontMatter {
+                title: title.to_string(),
+                date: Some("2024-11-20T15:00:00Z".to_string()),
+                ..Default::default()
+            };
+            let file = content_file(&fm, "Some body").unwrap();
+            let parsed: PageFrontMatter = parse_front_matter(&file);
+            assert_eq!(parsed, fm);
+            assert!(file.ends_with("\n\nSome body"));
+        }
+    }
+
+    #[test]
+    fn test_front_matter_stays_on_single_lines() {
+        let fm = PageFrontMatter {
+            title: "Before\n+++\nAfter\r\n\u{7f}".to_string(),
+            date: Some("2024-11-20T15:00:00Z".to_string()),
+            ..Default::default()
+        };
+        let file = content_file(&fm, "").unwrap();
+        assert_eq!(
+            file,
+            "+++\ntitle = \"Before\\n+++\\nAfter\\r\\n\\u007F\"\ndate = \"2024-11-20T15:00:00Z\"\n+++\n\n"
+        );
+    }
+
+    #[test]
+    fn test_page_front_matter_optional_fields() {
+        let fm = PageFrontMatter {
+            title: "Tagged".to_string(),
+            date: Some("2024-11-20".to_string()),
+            description: Some("Quotes \" and ünïcödé".to_string()),
+            draft: true,
+            taxonomies: BTreeMap::from([(
+                "tags".to_string(),
+                vec!["rust".to_string(), "a \"quoted\" tag".to_string()],
+            )]),
+            ..Default::default()
+        };
+        let file = content_file(&fm, "").unwrap();
+        assert!(!file.contains("slug"));
+        assert!(!file.contains("path"));
+        assert!(file.contains("[taxonomies]"));
+        let parsed: PageFrontMatter = parse_front_matter(&file);
+        assert_eq!(parsed, fm);
+    }
+
+    #[test]
+    fn test_config_round_trips() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let theme = themes::find(&themes, "terminimal").unwrap();
+        let mut config = Config::new(
+            "myblog.hypertxt.io",
+            "My \"Great\" Blog\ntheme = \"evil\"",
+            &theme,
+        );
+        config.set_extra(
+            &theme,
+            "author",
+            "Ñame 👋\n[markdown]\nhighlight_code = false",
+        );
+        let parsed: Config = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed, config);
+        assert_eq!(parsed.theme, "terminimal");
+        assert!(parsed.markdown.highlight_code);
+        let taxonomies: Vec<_> = parsed.taxonomies.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(taxonomies, TAXONOMIES);
+        assert!(parsed.taxonomies.iter().all(|t| t.feed));
+        assert!(parsed.generate_feeds);
+        assert_eq!(parsed.feed_filenames, vec!["atom.xml", "rss.xml"]);
+    }
+
+    #[test]
+    fn test_config_extra_depends_on_theme() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let terminimal = themes::find(&themes, "terminimal").unwrap();
+        let plain = themes::find(&themes, "plain").unwrap();
+
+        let mut config = Config::new("myblog.hypertxt.io", "Blog", &terminimal);
+        config.set_extra(&terminimal, "accent_color", "red");
+        assert_eq!(config.extra["accent_color"].as_str(), Some("red"));
+
+        let mut config = Config::new("myblog.hypertxt.io", "Blog", &plain);
+        config.set_extra(&plain, "accent_color", "red");
+        assert!(config.extra.is_empty());
+        assert_eq!(config.theme, "plain");
+    }
+
+    #[test]
+    fn test_config_has_no_injected_keys() {
+        let (_tmp, themes) = themes::tests::test_themes();
+        let theme = themes::find(&themes, "terminimal").unwrap();
+        let config = Config::new("myblog.hypertxt.io", "\"\noutput_dir = \"/etc\"\n", &theme);
+        let value: toml::Table = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert!(!value.contains_key("output_dir"));
+    }
+}
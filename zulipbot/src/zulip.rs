@@ -43,6 +43,9 @@ pub struct Message {
     pub timestamp: u64,
     pub subject: String,
     pub sender_full_name: String,
+    // Only set on messages that have been edited
+    #[serde(default)]
+    pub last_edit_timestamp: Option<u64>,
 }
 
 pub enum SendMessageType {
@@ -223,7 +226,7 @@ async fn register_event_queue(
         .ok_or_else(|| "no queue id in response".into())
 }
 
-async fn send_direct_message(msg: &str, user_id: u64) -> Result<(), String> {
+pub async fn send_direct_message(msg: &str, user_id: u64) -> Result<(), String> {
     let client = reqwest::Client::new();
     let mut id = "[".to_string();
     id.push_str(&user_id.to_string());
@@ -242,7 +245,23 @@ async fn send_direct_message(msg: &str, user_id: u64) -> Result<(), String> {
     Ok(())
 }
 
+// A link to a message, wherever it is.
+pub fn message_url(msg_id: u64) -> String {
+    format!("https://recurse.zulipchat.com/#narrow/near/{}", msg_id)
+}
+
 pub async fn get_message(msg_id: u64) -> Result<Message, String> {
+    let mut messages = get_messages(&[msg_id]).await?;
+    if messages.len() != 1 {
+        return Err("wrong number of messages".to_string());
+    }
+    Ok(messages.pop().unwrap())
+}
+
+// Fetches several messages in one request. Any that have been deleted (or
+// that we can't see) are left out.
+pub async fn get_messages(msg_ids: &[u64]) -> Result<Vec<Message>, String> {
+    let ids: Vec<String> = msg_ids.iter().map(|id| id.to_string()).collect();
     let client = reqwest::Client::new();
     let response = client
         .get("https://recurse.zulipchat.com/api/v1/messages")
@@ -251,7 +270,7 @@ pub async fn get_message(msg_id: u64) -> Result<Message, String> {
             Some(env::var("BOT_PASSWORD").unwrap_or_default()),
         )
         .query(&[
-            ("message_ids", format!("[{msg_id}]")),
+            ("message_ids", format!("[{}]", ids.join(","))),
             ("apply_markdown", "false".to_string()),
         ])
         .send()
@@ -261,15 +280,9 @@ pub async fn get_message(msg_id: u64) -> Result<Message, String> {
         .await
         .map_err(|e| format!("failed to JSON format get messages response: {:?}", e))?;
 
-    match response.messages {
-        Some(mut messages) => {
-            if messages.len() != 1 {
-                return Err("wrong number of messages".to_string());
-            }
-            Ok(messages.pop().unwrap())
-        }
-        None => Err("no messages in response".to_string()),
-    }
+    response
+        .messages
+        .ok_or_else(|| "no messages in response".to_string())
 }
 
 async fn send_message(msg: &str, topic: &str, channel_id: u64) -> Result<(), String> {
@@ -306,6 +319,7 @@ pub async fn download_image(path: &str, dst: &str) -> Result<(), String> {
             "Skipping download for {} as we already have it locally",
             path
         );
+        return Ok(());
     }
 
     let client = reqwest::Client::new();